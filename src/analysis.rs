@@ -1,22 +1,22 @@
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
-use tokio::task;
-use futures::future;
-use tokio::sync::Semaphore;
+use serde_json::json;
 
 use crate::prelude::*;
 use crate::data_node::DataNode;
+use crate::fingerprint::cluster_lineage_groups;
+use crate::lineage::LineageSettings;
+use crate::document::{Document, DocumentMetadata, DocumentType};
 use crate::json_node::JsonNode;
-use crate::basis_graph::{BasisGraph, BasisGraphBuilder};
-use crate::document::{Document, DocumentType};
+use crate::post_processing::apply_value_transformations;
 use crate::document_format::DocumentFormat;
-use crate::transformation::{Transformation, HashTransformation};
+use crate::transformation::{DataNodeTree, HashTransformation};
 use crate::provider::Provider;
-use crate::context::Context;
+use crate::context::{Context, ContextID};
 use crate::document_node::DocumentNode;
 use crate::graph_node::{Graph, GraphNode};
 use crate::profile::Profile;
-use crate::basis_network::BasisNetwork;
+use crate::basis_network::{BasisNetwork, NetworkRelationship};
 use crate::basis_node::BasisNode;
 use crate::config::{CONFIG};
 
@@ -24,6 +24,8 @@ pub struct Analysis {
     dataset: Dataset,
     node_analysis: NodeAnalysis,
     network_analysis: NetworkAnalysis,
+    metadata: DocumentMetadata,
+    options: Option<Options>,
 }
 
 impl Analysis {
@@ -31,13 +33,17 @@ impl Analysis {
         provider: Arc<P>,
         input: AnalysisInput
     ) -> Result<Self, Errors> {
-        let dataset = input.to_dataset(Arc::clone(&provider));
+        let metadata = input.metadata.clone();
+        let options = input.options.clone();
+        let profile_id = input.document_profile.id.clone();
+        let dataset = input.to_dataset()?;
 
-        let node_analysis = self.get_basis_nodes(
+        let node_analysis = Analysis::get_basis_nodes(
             Arc::clone(&provider),
-            &dataset
+            &dataset,
+            &profile_id
         ).await?;
-        let network_analysis = self.get_basis_networks(
+        let network_analysis = Analysis::get_basis_networks(
             Arc::clone(&provider),
             &dataset
         ).await?;
@@ -46,174 +52,324 @@ impl Analysis {
             dataset,
             node_analysis,
             network_analysis,
+            metadata,
+            options,
         };
 
         Ok(analysis)
     }
 
-    pub fn to_document(self, document_format: &Option<DocumentFormat>) -> Result<Document, Errors> {
-        unimplemented!()
+    /// The extracted JSON nodes after `Options.value_transformations`,
+    /// together with the values those failed on
+    pub fn to_document(self, _document_format: &Option<DocumentFormat>) -> Result<Document, Errors> {
+        log::trace!("In to_document");
+
+        let mut json_nodes = self.get_json_nodes()?;
+        let errors = apply_value_transformations(&mut json_nodes, &self.options)?;
+
+        let data = serde_json::to_string_pretty(&json!({
+            "nodes": json_nodes,
+            "errors": errors,
+        })).map_err(|err| {
+            log::error!("Could not serialize analysis: {:?}", err);
+            Errors::UnexpectedOutputFormat
+        })?;
+
+        Ok(Document {
+            document_type: DocumentType::JSON,
+            metadata: self.metadata,
+            data,
+        })
     }
 
-    fn get_basis_nodes(provider: Arc<P>, dataset: &Dataset) {
-        let mut lineage_groups: HashMap<Lineage, Vec<DataNode>> = HashMap::new();
+    /// A JSON node for every data node whose basis node produces fields, in
+    /// document order. Each points at the nearest ancestor that has one.
+    fn get_json_nodes(&self) -> Result<Vec<JsonNode>, Errors> {
+        log::trace!("In get_json_nodes");
+
+        let basis_nodes: HashMap<&Lineage, &BasisNode> = self.node_analysis.basis_nodes.iter()
+            .map(|basis_node| (&basis_node.lineage, basis_node))
+            .collect();
 
-        for data_node in self.map.values() {
-            let lineage = data_node.lineage.clone();
+        let mut json_nodes: Vec<JsonNode> = Vec::new();
 
-            lineage_groups
-                .entry(lineage)
-                .or_insert_with(Vec::new)
-                .push(data_node.clone());
+        if let Some(root) = &self.dataset.root {
+            self.collect_json_nodes(root, None, None, &basis_nodes, &mut json_nodes)?;
         }
 
+        Ok(json_nodes)
+    }
 
+    /// `tree` holds the node's fields and those of its descendants as
+    /// rewritten by a recursive transformation of an ancestor. A recursive
+    /// transformation replaces the subtree: its root's fields become the
+    /// node's, its children map onto the node's children in order, and
+    /// children it leaves out are dropped together with their descendants.
+    /// A node whose transformation fails under the `Skip` policy is dropped
+    /// in the same way.
+    fn collect_json_nodes(
+        &self,
+        graph_node: &Graph,
+        tree: Option<DataNodeTree>,
+        parent_id: Option<String>,
+        basis_nodes: &HashMap<&Lineage, &BasisNode>,
+        json_nodes: &mut Vec<JsonNode>,
+    ) -> Result<(), Errors> {
+        let context_id = read_lock!(graph_node).context_id.clone();
+        let mut data_node = read_lock!(self.dataset.data_nodes[&context_id]).clone();
+        let basis_node = basis_nodes.get(&data_node.lineage);
+
+        let tree = match basis_node {
+            Some(basis_node) if basis_node.has_tree_transformations() => {
+                let tree = tree.unwrap_or_else(|| self.get_tree(graph_node));
+
+                match basis_node.transform_tree(tree)? {
+                    Some(tree) => Some(tree),
+                    None => return Ok(()),
+                }
+            },
+            _ => tree,
+        };
 
-        //let max_concurrency = read_lock!(CONFIG).llm.max_concurrency;
-        let max_concurrency = 1;
-        let semaphore = Arc::new(Semaphore::new(max_concurrency));
+        if let Some(tree) = &tree {
+            data_node.fields = tree.fields.clone();
+        }
 
-        let handles: Vec<_> = lineage_groups.into_iter()
-            .map(|(lineage, group)| {
-                let semaphore = semaphore.clone();
-                let cloned_provider = Arc::clone(&provider);
-                let cloned_context = Arc::clone(&context);
+        let mut child_parent_id = parent_id.clone();
+
+        if let Some(basis_node) = basis_node {
+            let json = match basis_node.transform(&data_node)? {
+                Some(json) => json,
+                None => return Ok(()),
+            };
+
+            if !json.is_empty() {
+                child_parent_id = Some(data_node.id.to_string());
+
+                json_nodes.push(JsonNode {
+                    id: data_node.id,
+                    hash: data_node.hash,
+                    lineage: data_node.lineage,
+                    description: data_node.description,
+                    parent_id,
+                    json,
+                });
+            }
+        }
 
-                task::spawn(async move {
-                    let permit = semaphore.acquire_owned().await.unwrap();
-                    get_basis_node(
-                        cloned_provider,
-                        cloned_context,
-                        lineage,
-                        group
-                    ).await
-                })
+        let children = read_lock!(graph_node).children.clone();
+
+        match tree {
+            Some(tree) => {
+                for (child, child_tree) in children.iter().zip(tree.children.into_iter()) {
+                    self.collect_json_nodes(child, Some(child_tree), child_parent_id.clone(), basis_nodes, json_nodes)?;
+                }
+            },
+            None => {
+                for child in children.iter() {
+                    self.collect_json_nodes(child, None, child_parent_id.clone(), basis_nodes, json_nodes)?;
+                }
+            },
+        }
 
-            })
-            .collect();
+        Ok(())
+    }
 
+    fn get_tree(&self, graph_node: &Graph) -> DataNodeTree {
+        let lock = read_lock!(graph_node);
 
+        DataNodeTree {
+            fields: read_lock!(self.dataset.data_nodes[&lock.context_id]).fields.clone(),
+            children: lock.children.iter().map(|child| self.get_tree(child)).collect(),
+        }
+    }
 
+    async fn get_basis_nodes<P: Provider>(
+        provider: Arc<P>,
+        dataset: &Dataset,
+        profile_id: &ID,
+    ) -> Result<NodeAnalysis, Errors> {
+        log::trace!("In get_basis_nodes");
+
+        let mut basis_nodes: Vec<BasisNode> = Vec::new();
+
+        for (lineage, context_ids) in dataset.lineage_groups.iter() {
+            if let Some(basis_node) = Analysis::get_basis_node(
+                Arc::clone(&provider),
+                &dataset.context,
+                lineage,
+                context_ids,
+                profile_id
+            ).await? {
+                basis_nodes.push(basis_node);
+            }
+        }
 
+        Ok(NodeAnalysis {
+            basis_nodes,
+        })
+    }
 
-        let basis_nodes: Vec<BasisNode> = future::join_all(handles)
-            .await
+    /// The provider's basis networks that involve lineages of this document
+    async fn get_basis_networks<P: Provider>(
+        provider: Arc<P>,
+        dataset: &Dataset,
+    ) -> Result<NetworkAnalysis, Errors> {
+        log::trace!("In get_basis_networks");
+
+        let basis_networks = provider.list_basis_networks().await?
             .into_iter()
-            .filter_map(|result| result.ok().and_then(Result::ok))
+            .filter(|basis_network| match &basis_network.relationship {
+                NetworkRelationship::Recursion(recursion) => {
+                    dataset.lineage_groups.contains_key(&recursion.lineage)
+                },
+                NetworkRelationship::Association(association) => {
+                    association.iter().any(|subgraph| dataset.lineage_groups.contains_key(&subgraph.lineage))
+                },
+            })
             .collect();
 
-
-        unimplemented!()
-    }
-
-    fn get_basis_networks(provider: Arc<P>, dataset: &Dataset) {
-        unimplemented!()
+        Ok(NetworkAnalysis {
+            basis_networks,
+        })
     }
 
+    /// The provider's basis node for the lineage. One that belongs to no
+    /// profile yet is claimed for the document's. Inferring a missing one is
+    /// not supported yet, so its nodes are left out of the analysis.
     async fn get_basis_node<P: Provider>(
         provider: Arc<P>,
-        context: Arc<Context>,
-        lineage: Lineage,
-        group: Vec<DataNode>
-    ) -> Result<BasisNode, Errors> {
+        context: &Context,
+        lineage: &Lineage,
+        context_ids: &[ContextID],
+        profile_id: &ID,
+    ) -> Result<Option<BasisNode>, Errors> {
         log::trace!("In get_basis_node");
+        log::debug!("lineage: {:?}", lineage);
 
-        if let Some(basis_node) = provider.get_basis_node_by_lineage(&lineage).await? {
+        if let Some(mut basis_node) = provider.get_basis_node_by_lineage(lineage).await? {
             log::info!("Provider has supplied basis node");
 
-            return Ok(basis_node);
-        }
+            if basis_node.profile_id.is_none() {
+                log::info!("Claiming basis node {} for profile {}", basis_node.id.to_string(), profile_id.to_string());
 
+                basis_node.profile_id = Some(profile_id.clone());
+                provider.save_basis_node(&basis_node).await?;
+            }
 
-        let data_node = group.first().unwrap();
+            return Ok(Some(basis_node));
+        }
 
-        let c = context.get_snippet(&data_node.context_id);
+        if let Some(context_id) = context_ids.first() {
+            let snippet = context.get_snippet(context_id)?;
 
-        log::debug!("snippet: {}", c);
+            log::debug!("snippet: {}", snippet);
+        }
 
+        log::warn!("No basis node for lineage {:?}, leaving its nodes out", lineage);
 
-        unimplemented!()
+        Ok(None)
     }
 }
 
 pub struct AnalysisInput {
-    document_root: Arc<RwLock<DocumentNode>>,
+    document_root: DocumentNode,
     document_profile: Profile,
+    metadata: DocumentMetadata,
+    options: Option<Options>,
 }
 
 impl AnalysisInput {
     pub async fn from_document<P: Provider>(
         provider: Arc<P>,
-        mut document: Document
+        mut document: Document,
+        options: &Option<Options>,
     ) -> Result<Self, Errors> {
-        let profile = document.perform_analysis(provider).await?;
+        let profile = document.perform_analysis(provider, options).await?;
         let document_node = document.get_document_node()?;
 
         Ok(AnalysisInput {
-            document_root: Arc::new(RwLock::new(document_node.clone())),
+            document_root: document_node,
             document_profile: profile,
+            metadata: document.metadata.clone(),
+            options: options.clone(),
         })
     }
 
-    pub fn to_dataset<P: Provider>(
-        self,
-        provider: Arc<P>,
-    ) -> Dataset {
-        let mut document_nodes: HashMap<ContextID, Arc<RwLock<DocumentNode>>> = HashMap::new();
-        let mut document_context: HashMap<DocumentNodeID, ContextID> = HashMap::new();
-
-        let mut data_nodes: HashMap<ContextID, Arc<RwLock<DataNode>>> = HashMap::new();
-        let mut data_context: HashMap<DataNodeID, ContextID> = HashMap::new();
-
-        let mut graph_nodes: HashMap<ContextID, Arc<RwLock<GraphNode>>> = HashMap::new();
-        let mut graph_context: HashMap<GraphNodeID, ContextID> = HashMap::new();
-
-        let mut lineage_groups: HashMap<Lineage, Vec<ContextID>> = HashMap::new();
-
-        let graph = traverse(
-            &mut document_nodes,
-            &mut document_context,
-            &mut data_nodes,
-            &mut data_context,
-            &mut graph_nodes,
-            &mut graph_context,
-            &mut lineage_groups,
-            Arc::clone(self.document_root),
+    fn to_dataset(self) -> Result<Dataset, Errors> {
+        log::trace!("In to_dataset");
+
+        let hash_transformation = self.document_profile.hash_transformation.clone()
+            .ok_or_else(|| Errors::ProfileValidationError("Profile lacks a hash transformation".into()))?;
+
+        let mut dataset = Dataset {
+            context: Context::new(),
+            data_nodes: HashMap::new(),
+            data_context: HashMap::new(),
+            lineage_groups: HashMap::new(),
+            root: None,
+        };
+
+        dataset.root = traverse(
+            &mut dataset,
+            Arc::new(RwLock::new(self.document_root)),
             &Lineage::new(),
             &self.document_profile,
+            &hash_transformation,
             Vec::new(),
-        );
-
-        let root_node_document_id = read_lock!(self.document_root).id.clone();
-        let root_node_context_id = data_context.get(&root_node_document_id).unwrap();
-
-        Dataset {
-            data_nodes,
-            data_context,
-            graph_nodes,
-            graph_context,
-            document_nodes,
-            document_context,
-            root_node_context_id,
+        )?;
+
+        let fingerprint_distance = read_lock!(CONFIG).lineage.fingerprint_distance;
+
+        if let Some(max_distance) = fingerprint_distance {
+            dataset.cluster(max_distance, &self.document_profile.get_lineage_settings());
         }
+
+        Ok(dataset)
     }
 }
 
-type ContextID = ID;
-type GraphID = ID;
-type DocumentNodeID = ID;
 type DataNodeID = ID;
 
 struct Dataset {
+    context: Context,
     data_nodes: HashMap<ContextID, Arc<RwLock<DataNode>>>,
     data_context: HashMap<DataNodeID, ContextID>,
-    graph_nodes: HashMap<ContextID, Arc<RwLock<GraphNode>>,
-    graph_context: HashMap<GraphID, ContextID>,
-    document_nodes: HashMap<ContextID, Arc<RwLock<DocumentNode>>>, 
-    document_context: HashMap<DocumentNodeID, ContextID>,
     lineage_groups: HashMap<Lineage, Vec<ContextID>>,
-    root_node_context_id: ContextID,
-    hash_maps: DatasetHashMaps,
+    /// Nothing if the root node itself was skipped
+    root: Option<Graph>,
+}
+
+impl Dataset {
+    /// Merges near-identical lineage groups before basis nodes are looked up
+    /// for them, moving their data and graph nodes to the merged lineage
+    fn cluster(&mut self, max_distance: u32, settings: &LineageSettings) {
+        let lineage_groups: HashMap<Lineage, Vec<DataNode>> = self.lineage_groups.iter()
+            .map(|(lineage, context_ids)| {
+                let group = context_ids.iter()
+                    .map(|context_id| read_lock!(self.data_nodes[context_id]).clone())
+                    .collect();
+
+                (lineage.clone(), group)
+            })
+            .collect();
+
+        self.lineage_groups = HashMap::new();
+
+        for (lineage, group) in cluster_lineage_groups(lineage_groups, max_distance, settings) {
+            for data_node in group {
+                let context_id = self.data_context[&data_node.id].clone();
+
+                write_lock!(self.data_nodes[&context_id]).lineage = lineage.clone();
+
+                if let Some(graph_node) = self.context.get_graph_node(&context_id) {
+                    write_lock!(graph_node).lineage = lineage.clone();
+                }
+
+                self.lineage_groups.entry(lineage.clone()).or_default().push(context_id);
+            }
+        }
+    }
 }
 
 struct NodeAnalysis {
@@ -224,68 +380,202 @@ struct NetworkAnalysis {
     basis_networks: Vec<BasisNetwork>,
 }
 
+/// Registers the document node and its descendants in the dataset, returning
+/// its graph node, or nothing if the node was skipped together with its
+/// descendants
 fn traverse(
-    document_nodes: &mut HashMap<ContextID, Arc<RwLock<DocumentNode>>>, 
-    document_context: &mut HashMap<DocumentNodeID, ContextID>,
-    data_nodes: &mut HashMap<ContextID, Arc<RwLock<DataNode>>>,
-    data_context: &mut HashMap<DataNodeID, ContextID>,
-    graph_nodes: &mut HashMap<ContextID, Arc<RwLock<GraphNode>>,
-    graph_context: &mut HashMap<GraphID, ContextID>,
-    lineage_groups: &mut HashMap<Lineage, Vec<ContextID>>,
-    document_node: Arc<RwLock<DocumentNode>>>,
+    dataset: &mut Dataset,
+    document_node: Arc<RwLock<DocumentNode>>,
     parent_lineage: &Lineage,
     profile: &Profile,
-    parents: Vec<Arc<RwLock<GraphNode>>>,
-) {
-    let context_id = ID::new();
-
-    document_nodes.insert(context_id.clone(), Arc::clone(document_node));
-    document_context.insert(read_lock!(document_node).id.clone(), context_id.clone());
-
-    let data_node = Arc::new(RwLock::new(DataNode::new(
-        &profile.hash_transformation.clone().unwrap(),
-        document_node.get_fields(),
-        document_node.get_description(),
+    hash_transformation: &HashTransformation,
+    parents: Vec<Graph>,
+) -> Result<Option<Graph>, Errors> {
+    let context_id = ID::derive_or_new(&["context", &read_lock!(document_node).id.to_string()]);
+
+    let data_node = match DataNode::new(
+        hash_transformation,
+        profile.hash_algorithm,
+        &profile.get_lineage_settings(),
+        &read_lock!(document_node),
         parent_lineage,
-    )));
-    data_nodes.insert(context_id.clone(), Arc::clone(data_node));
-    data_context.insert(read_lock!(data_node).id.clone(), context_id.clone());
+    )? {
+        Some(data_node) => data_node,
+        None => return Ok(None),
+    };
 
-    let lineage = read_lock!(data_node).lineage.clone();
-    lineage_groups
-        .entry(lineage)
-        .or_insert_with(Vec::new)
-        .push(context_id.clone());
+    let lineage = data_node.lineage.clone();
+    let data_node_id = data_node.id.clone();
+    let data_node = Arc::new(RwLock::new(data_node));
 
     let graph_node = Arc::new(RwLock::new(GraphNode::from_data_node(
-        Arc::clone(data_node),
-        parents.clone()
+        &context_id,
+        Arc::clone(&data_node),
+        parents
     )));
-    graph_nodes.insert(context_id.clone(), Arc::clone(graph_node));
-    graph_context.insert(read_lock!(graph_node).id.clone(), context_id.clone());
 
+    dataset.context.register(&context_id, Arc::clone(&document_node), Arc::clone(&graph_node));
+    dataset.data_nodes.insert(context_id.clone(), data_node);
+    dataset.data_context.insert(data_node_id, context_id.clone());
+    dataset.lineage_groups
+        .entry(lineage.clone())
+        .or_default()
+        .push(context_id);
+
+    let children = read_lock!(document_node).get_children(profile.xml_element_transformation.clone())?;
+
+    for child in children {
+        let child = traverse(
+            dataset,
+            Arc::new(RwLock::new(child)),
+            &lineage,
+            profile,
+            hash_transformation,
+            vec![Arc::clone(&graph_node)]
+        )?;
+
+        if let Some(child) = child {
+            write_lock!(graph_node).children.push(child);
+        }
+    }
+
+    Ok(Some(graph_node))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use crate::organization::organize;
+    use crate::provider::JsonFileProvider;
+    use crate::runtime::ValueOperation;
+    use crate::testing::{basis_node, merge_text, runtime, temp_path, text_field};
+    use crate::transformation::{
+        DataNodeRecursiveTransform,
+        DataToJsonFieldTransform,
+        JsonValueTransform,
+        Runtime,
+        Transformation,
+    };
+
+    fn to_json_field() -> Transformation {
+        Transformation::DataToJsonFieldTransform(DataToJsonFieldTransform {
+            id: ID::new(),
+            runtime: runtime("analysis-test-text", text_field),
+            code: String::from("text"),
+            examples: Vec::new(),
+        })
+    }
+
+    /// Analyzes the document once so that a profile is bootstrapped, then
+    /// saves a basis node with the given transformations for each lineage
+    async fn analyze<F>(html: &str, get_transformations: F, options: Option<Options>) -> Value
+    where
+        F: Fn(&Lineage) -> Vec<Transformation>,
     {
-        let children: Vec<Arc<RwLock<GraphNode>>> = read_lock!(document_node)
-            .get_children(profile.xml_element_transformation.clone())
-            .into_iter()
-            .map(|child| {
-                traverse(
-                    document_nodes,
-                    document_context,
-                    data_nodes,
-                    data_context,
-                    graph_nodes,
-                    graph_context,
-                    lineage_groups,
-                    Arc::clone(child),
-                    &lineage,
-                    profile,
-                    vec![Arc::clone(&graph_node)]
-                )
-            })
-            .collect();
+        let path = temp_path("analysis");
+        std::fs::write(&path, "{}").unwrap();
+
+        let provider = Arc::new(JsonFileProvider::new(path.clone()));
+
+        let document = Document::from_string(html.to_string(), &None).unwrap();
+        let analysis = organize(Arc::clone(&provider), document, &None).await.unwrap();
+
+        for lineage in analysis.dataset.lineage_groups.keys() {
+            provider.save_basis_node(&BasisNode {
+                transformations: get_transformations(lineage),
+                ..basis_node(lineage.clone())
+            }).await.unwrap();
+        }
+
+        let document = Document::from_string(html.to_string(), &options).unwrap();
+        let analysis = organize(Arc::clone(&provider), document, &options).await.unwrap();
+        let output: Value = serde_json::from_str(&analysis.to_document(&None).unwrap().data).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        output
+    }
+
+    fn get_values(output: &Value) -> Vec<&str> {
+        output["nodes"].as_array().unwrap().iter()
+            .flat_map(|node| node["json"].as_array().unwrap())
+            .map(|json| json["value"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn to_document_applies_value_transformations() {
+        let options = Some(Options {
+            value_transformations: Some(vec![Transformation::JsonValueTransform(JsonValueTransform {
+                id: ID::new(),
+                runtime: Runtime::Native,
+                code: String::new(),
+                operations: vec![ValueOperation::Trim, ValueOperation::Uppercase],
+                lineages: Vec::new(),
+                keys: vec![String::from("text")],
+                examples: Vec::new(),
+            })]),
+            ..Options::default()
+        });
+
+        let output = analyze(
+            "<html><body><p>  Price: 12  </p></body></html>",
+            |_| vec![to_json_field()],
+            options
+        ).await;
+
+        assert_eq!(get_values(&output), vec!["PRICE: 12"]);
+        assert_eq!(output["errors"], Value::Array(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn to_document_applies_recursive_transformations() {
+        let html = "<html><body><p>Hello <b>big</b> world</p></body></html>";
+
+        let output = analyze(html, |_| vec![to_json_field()], None).await;
+        let values: Vec<&str> = get_values(&output).into_iter().map(str::trim).collect();
+        assert_eq!(values, vec!["Hello", "big", "world"]);
+
+        let output = analyze(html, |lineage| {
+            let mut transformations = Vec::new();
+
+            if lineage.last_segment().as_deref() == Some("p") {
+                transformations.push(Transformation::DataNodeRecursiveTransform(DataNodeRecursiveTransform {
+                    id: ID::new(),
+                    runtime: runtime("analysis-test-merge", merge_text),
+                    code: String::from("merge"),
+                    examples: Vec::new(),
+                }));
+            }
+
+            transformations.push(to_json_field());
+            transformations
+        }, None).await;
+
+        assert_eq!(get_values(&output), vec!["Hello big world"]);
+    }
+
+    #[tokio::test]
+    async fn snippets_mark_the_node_they_were_taken_for() {
+        let path = temp_path("analysis");
+        std::fs::write(&path, "{}").unwrap();
+
+        let provider = Arc::new(JsonFileProvider::new(path.clone()));
+
+        let html = "<html><body><script>ignored()</script><p style=\"color: red\">One</p><p>Two</p></body></html>";
+        let document = Document::from_string(html.to_string(), &None).unwrap();
+        let analysis = organize(Arc::clone(&provider), document, &None).await.unwrap();
+
+        assert!(analysis.dataset.data_nodes.len() > 1);
+
+        for context_id in analysis.dataset.data_nodes.keys() {
+            let snippet = analysis.dataset.context.get_snippet(context_id).unwrap();
+
+            assert_eq!(snippet.matches("<!-- Target node: Start -->").count(), 1, "{}", snippet);
+            assert!(!snippet.contains("ignored"));
+        }
 
-        let mut node_write_lock = graph_node.write().unwrap();
-        node_write_lock.children.extend(children);
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -31,3 +31,42 @@ pub enum NetworkRelationship {
     Recursion(Recursion),
     Association(Association),
 }
+
+impl BasisNetwork {
+    pub fn validate(&self) -> Result<(), Errors> {
+        if self.id.to_string().trim().is_empty() {
+            return Err(Errors::BasisNetworkValidationError("Basis network id is empty".into()));
+        }
+
+        let lineages: Vec<&Lineage> = match &self.relationship {
+            NetworkRelationship::Recursion(recursion) => {
+                recursion.transformation.validate()?;
+
+                vec![&recursion.lineage]
+            },
+            NetworkRelationship::Association(association) => {
+                if association.is_empty() {
+                    return Err(Errors::BasisNetworkValidationError(
+                        format!("Basis network {} associates no subgraphs", self.id.to_string())
+                    ));
+                }
+
+                if association.iter().any(|subgraph| subgraph.subgraph.is_unfinalized()) {
+                    return Err(Errors::BasisNetworkValidationError(
+                        format!("Basis network {} has an uncomputed subgraph hash", self.id.to_string())
+                    ));
+                }
+
+                association.iter().map(|subgraph| &subgraph.lineage).collect()
+            },
+        };
+
+        if lineages.iter().any(|lineage| lineage.depth() == 0) {
+            return Err(Errors::BasisNetworkValidationError(
+                format!("Basis network {} has an empty lineage", self.id.to_string())
+            ));
+        }
+
+        Ok(())
+    }
+}
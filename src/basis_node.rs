@@ -1,7 +1,13 @@
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
 
 use crate::prelude::*;
-use crate::transformation::{Transformation};
+use crate::config::LineageMode;
+use crate::data_node::DataNode;
+use crate::hash::HashScheme;
+use crate::json_node::{Json, JsonMetadata};
+use crate::lineage::{legacy_max_cycle_length, LineageSettings};
+use crate::transformation::{DataNodeTree, Transformation};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BasisNode {
@@ -10,4 +16,306 @@ pub struct BasisNode {
     pub lineage: Lineage,
     pub description: String,
     pub transformations: Vec<Transformation>,
+    /// The profile whose documents this basis node was inferred from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_id: Option<ID>,
+    /// Encoding the node and lineage hashes were computed with
+    #[serde(default = "HashScheme::legacy")]
+    pub hash_scheme: HashScheme,
+    /// How its lineage collapses repeated hashes
+    #[serde(default = "LineageMode::legacy")]
+    pub lineage_mode: LineageMode,
+    #[serde(default = "legacy_max_cycle_length")]
+    pub lineage_max_cycle_length: usize,
+}
+
+impl BasisNode {
+    /// What its lineage was derived with
+    pub fn get_lineage_settings(&self) -> LineageSettings {
+        LineageSettings {
+            hash_scheme: self.hash_scheme.clone(),
+            mode: self.lineage_mode.clone(),
+            max_cycle_length: self.lineage_max_cycle_length,
+        }
+    }
+
+    /// Applies the recursive transformations to a subtree rooted at a data
+    /// node of this basis node, in order. Nothing if a failed transformation
+    /// skips the subtree.
+    pub fn transform_tree(&self, tree: DataNodeTree) -> Result<Option<DataNodeTree>, Errors> {
+        log::trace!("In transform_tree");
+
+        let mut tree = tree;
+
+        for transformation in self.transformations.iter() {
+            if let Transformation::DataNodeRecursiveTransform(transform) = transformation {
+                match transform.transform(&tree)? {
+                    Some(transformed) => tree = transformed,
+                    None => return Ok(None),
+                }
+            }
+        }
+
+        Ok(Some(tree))
+    }
+
+    pub fn validate(&self) -> Result<(), Errors> {
+        if self.id.to_string().trim().is_empty() {
+            return Err(Errors::BasisNodeValidationError("Basis node id is empty".into()));
+        }
+
+        if self.hash.is_unfinalized() {
+            return Err(Errors::BasisNodeValidationError(
+                format!("Basis node {} has an uncomputed hash", self.id.to_string())
+            ));
+        }
+
+        if self.lineage.depth() == 0 {
+            return Err(Errors::BasisNodeValidationError(
+                format!("Basis node {} has an empty lineage", self.id.to_string())
+            ));
+        }
+
+        for transformation in self.transformations.iter() {
+            transformation.validate()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn has_tree_transformations(&self) -> bool {
+        self.transformations.iter().any(|transformation| {
+            matches!(transformation, Transformation::DataNodeRecursiveTransform(_))
+        })
+    }
+
+    /// Applies the node-level transformations to a data node of this basis
+    /// node, in order. Field transformations rewrite the fields that later
+    /// ones see, each JSON field transformation may contribute a field, and
+    /// schema transformations reshape the fields produced so far as one
+    /// object. Recursive transformations act on whole subtrees and value
+    /// transformations on the final result, so they are not applied here.
+    /// Nothing if a failed transformation skips the node.
+    pub fn transform(&self, data_node: &DataNode) -> Result<Option<Vec<Json>>, Errors> {
+        log::trace!("In transform");
+
+        let mut data_node = data_node.clone();
+        let mut json: Vec<Json> = Vec::new();
+
+        for transformation in self.transformations.iter() {
+            match transformation {
+                Transformation::DataNodeFieldsTransform(transform) => {
+                    match transform.transform(&data_node.fields)? {
+                        Some(fields) => data_node.fields = fields,
+                        None => return Ok(None),
+                    }
+                },
+                Transformation::DataNodeHashTransform(transform) => {
+                    match transform.transform(&data_node)? {
+                        Some(fields) => data_node.fields = fields,
+                        None => return Ok(None),
+                    }
+                },
+                Transformation::DataToJsonFieldTransform(transform) => {
+                    match transform.transform(&data_node)? {
+                        Some(field) => json.extend(field),
+                        None => return Ok(None),
+                    }
+                },
+                Transformation::JsonSchemaTransform(transform) => {
+                    let result = match transform.transform(&to_object(&json))? {
+                        Some(result) => result,
+                        None => return Ok(None),
+                    };
+
+                    json = from_object(&result, &json).ok_or_else(|| {
+                        Errors::TransformationOutputError(format!(
+                            "Transformation {} must produce an object to replace the fields of basis node {}",
+                            transform.id.to_string(),
+                            self.id.to_string()
+                        ))
+                    })?;
+                },
+                Transformation::DataNodeRecursiveTransform(_) |
+                Transformation::JsonValueTransform(_) => {
+                    log::debug!("Skipping transformation {} that does not apply to a single node", transformation.get_id().to_string());
+                },
+            }
+        }
+
+        Ok(Some(json))
+    }
+}
+
+fn to_object(json: &[Json]) -> Value {
+    Value::Object(
+        json.iter()
+            .map(|field| (field.key.clone(), Value::String(field.value.clone())))
+            .collect()
+    )
+}
+
+/// Fields from the entries of an object, keeping the metadata of fields that
+/// were already there. Nested values are kept as serialized JSON and nulls
+/// are dropped.
+fn from_object(value: &Value, previous: &[Json]) -> Option<Vec<Json>> {
+    let object = value.as_object()?;
+
+    Some(object.iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(key, value)| {
+            let meta = previous.iter()
+                .find(|field| field.key == *key)
+                .map(|field| field.meta.clone())
+                .unwrap_or(JsonMetadata {
+                    is_primary_content: false,
+                });
+
+            Json {
+                key: key.clone(),
+                value: match value {
+                    Value::String(value) => value.clone(),
+                    other => other.to_string(),
+                },
+                meta,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Map};
+    use crate::data_node::DataNodeFields;
+    use crate::testing::{self, lineage, runtime, text_field};
+    use crate::transformation::{
+        DataNodeFieldsTransform,
+        DataNodeRecursiveTransform,
+        DataToJsonFieldTransform,
+        JsonSchemaTransform,
+    };
+
+    fn basis_node(transformations: Vec<Transformation>) -> BasisNode {
+        BasisNode {
+            transformations,
+            ..testing::basis_node(lineage(&["p"]))
+        }
+    }
+
+    fn data_node(text: &str) -> DataNode {
+        DataNode {
+            id: ID::new(),
+            hash: Hash::from_str("p"),
+            lineage: Lineage::new(),
+            fields: DataNodeFields::from([(String::from("text"), text.to_string())]),
+            description: String::from("p"),
+            fingerprint: None,
+        }
+    }
+
+    fn trim_fields(inputs: &Map<String, Value>) -> Map<String, Value> {
+        let fields = inputs["fields"].as_object().unwrap().iter()
+            .map(|(key, value)| (key.clone(), Value::from(value.as_str().unwrap().trim())))
+            .collect();
+
+        Map::from_iter([(String::from("fields"), Value::Object(fields))])
+    }
+
+    fn rename_text(inputs: &Map<String, Value>) -> Map<String, Value> {
+        Map::from_iter([(String::from("json"), json!({ "name": inputs["json"]["text"], "count": 1 }))])
+    }
+
+    fn to_list(_inputs: &Map<String, Value>) -> Map<String, Value> {
+        Map::from_iter([(String::from("json"), json!(["text"]))])
+    }
+
+    fn fields_transform() -> Transformation {
+        Transformation::DataNodeFieldsTransform(DataNodeFieldsTransform {
+            id: ID::new(),
+            runtime: runtime("basis-node-test-trim", trim_fields),
+            code: String::from("trim"),
+            examples: Vec::new(),
+        })
+    }
+
+    fn json_field_transform() -> Transformation {
+        Transformation::DataToJsonFieldTransform(DataToJsonFieldTransform {
+            id: ID::new(),
+            runtime: runtime("basis-node-test-text", text_field),
+            code: String::from("text"),
+            examples: Vec::new(),
+        })
+    }
+
+    fn schema_transform(name: &str, run: fn(&Map<String, Value>) -> Map<String, Value>) -> Transformation {
+        Transformation::JsonSchemaTransform(JsonSchemaTransform {
+            id: ID::new(),
+            runtime: runtime(name, run),
+            code: String::from("reshape"),
+            source: String::from(r#"{ "type": "object" }"#),
+            target: String::from("{}"),
+            examples: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn transform_applies_transformations_in_order() {
+        let node = basis_node(vec![
+            fields_transform(),
+            json_field_transform(),
+            schema_transform("basis-node-test-rename", rename_text),
+        ]);
+
+        let json: Vec<(String, String)> = node.transform(&data_node("  Hello  ")).unwrap().unwrap().into_iter()
+            .map(|field| (field.key, field.value))
+            .collect();
+
+        assert_eq!(json, vec![
+            (String::from("count"), String::from("1")),
+            (String::from("name"), String::from("Hello")),
+        ]);
+    }
+
+    #[test]
+    fn transform_rejects_schema_output_that_is_not_an_object() {
+        let node = basis_node(vec![
+            json_field_transform(),
+            schema_transform("basis-node-test-list", to_list),
+        ]);
+
+        assert!(matches!(node.transform(&data_node("Hello")), Err(Errors::TransformationOutputError(_))));
+    }
+
+    #[test]
+    fn transform_tree_applies_recursive_transformations_only() {
+        fn drop_children(inputs: &Map<String, Value>) -> Map<String, Value> {
+            let mut node = inputs["node"].clone();
+            node["children"] = json!([]);
+
+            Map::from_iter([(String::from("node"), node)])
+        }
+
+        let node = basis_node(vec![
+            fields_transform(),
+            Transformation::DataNodeRecursiveTransform(DataNodeRecursiveTransform {
+                id: ID::new(),
+                runtime: runtime("basis-node-test-drop-children", drop_children),
+                code: String::from("drop"),
+                examples: Vec::new(),
+            }),
+        ]);
+
+        let tree = DataNodeTree {
+            fields: DataNodeFields::from([(String::from("text"), String::from(" a "))]),
+            children: vec![DataNodeTree::default()],
+        };
+
+        assert!(node.has_tree_transformations());
+        assert!(!basis_node(vec![fields_transform()]).has_tree_transformations());
+        assert_eq!(node.transform_tree(tree.clone()).unwrap(), Some(DataNodeTree {
+            fields: tree.fields,
+            children: Vec::new(),
+        }));
+    }
 }
@@ -6,6 +6,8 @@ use std::fs;
 use std::io::Write;
 use std::env;
 
+use crate::hash::{HashAlgorithm, HashScheme};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 enum LlmProvider {
     OpenAI,
@@ -33,10 +35,199 @@ pub struct DevConfig {
     pub debug_dir: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    pub similarity_threshold: f64,
+    pub max_runner_ups: usize,
+    pub index_bands: usize,
+    pub index_rows_per_band: usize,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        ProfileConfig {
+            similarity_threshold: 0.8,
+            max_runner_ups: 3,
+            index_bands: 32,
+            index_rows_per_band: 4,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeatureConfig {
+    /// Elements nested deeper than this do not contribute features
+    pub max_depth: Option<usize>,
+    pub include_class_tokens: bool,
+    pub include_id_tokens: bool,
+}
+
+/// How a lineage's identity is derived from the hashes along its path
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LineageMode {
+    /// Every hash on the path counts, so each depth is its own lineage
+    Exact,
+    /// Consecutive repeats of a hash count once, so directly nested
+    /// replies share a lineage
+    CollapseRepeats,
+    /// A hash seen again within `max_cycle_length` steps returns the path to
+    /// where it was first seen, so repeating patterns such as comment >
+    /// replies > comment share a lineage
+    CollapseCycles,
+}
+
+impl LineageMode {
+    /// The mode of records that predate recording it
+    pub fn legacy() -> Self {
+        LineageMode::Exact
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LineageConfig {
+    /// Recorded on new profiles along with `max_cycle_length`. Existing
+    /// profiles keep deriving lineages the way they were created with.
+    pub mode: LineageMode,
+    pub max_cycle_length: usize,
+    /// Sibling lineage groups whose structural fingerprints differ in at
+    /// most this many of 64 bits are merged before basis nodes are inferred,
+    /// so that an extra class does not split a list. Around 8 tolerates an
+    /// extra class or attribute but not a different tag. Unset disables it.
+    pub fingerprint_distance: Option<u32>,
+}
+
+impl Default for LineageConfig {
+    fn default() -> Self {
+        LineageConfig {
+            mode: LineageMode::Exact,
+            max_cycle_length: 4,
+            fingerprint_distance: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HashConfig {
+    /// Encoding used for every newly computed hash. Profiles and basis nodes
+    /// record the scheme they were created with.
+    pub scheme: HashScheme,
+    /// Digest used by new profiles. Existing profiles keep the algorithm
+    /// they were created with.
+    pub algorithm: HashAlgorithm,
+}
+
+impl Default for HashConfig {
+    fn default() -> Self {
+        HashConfig {
+            scheme: HashScheme::V2,
+            algorithm: HashAlgorithm::Sha256,
+        }
+    }
+}
+
+/// How document, data and graph nodes get their IDs
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum IdMode {
+    /// A random UUIDv4 on every run
+    Random,
+    /// A UUIDv5 of the parent's ID and the position among its siblings, so
+    /// that runs over the same page produce the same IDs
+    Position,
+    /// A UUIDv5 of the parent's ID and the markup of the node's whole
+    /// subtree, so that IDs also survive siblings being inserted or removed.
+    /// Copies of the same subtree under one parent are told apart by their
+    /// order, so only those shift when another copy is inserted.
+    Content,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdConfig {
+    pub mode: IdMode,
+}
+
+impl Default for IdConfig {
+    fn default() -> Self {
+        IdConfig {
+            mode: IdMode::Random,
+        }
+    }
+}
+
+/// What happens to a node whose transformation fails or exceeds its limits
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TransformationPolicy {
+    /// Drop the node and everything beneath it
+    Skip,
+    /// Carry on with the node as it was before the transformation
+    KeepOriginal,
+    /// Abort with the error
+    Fail,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    pub awk_command: String,
+    pub node_command: String,
+    pub python_command: String,
+    /// Executable started as the QuickJS worker. If unset, scripts run on
+    /// threads of this process, which cannot be stopped once they time out.
+    pub quickjs_command: Option<String>,
+    /// How long a transformation may take per call. Subprocess workers that
+    /// exceed it are killed and restarted.
+    pub timeout_ms: u64,
+    /// Memory available to a QuickJS context, a NodeJS heap, a Python or
+    /// AWK process or a WASM module's linear memory
+    pub memory_limit_mb: usize,
+    /// Instructions a WASM transformation may execute per call, counted
+    /// deterministically rather than by wall clock
+    pub wasm_fuel: u64,
+    /// Largest serialized result a transformation may return
+    pub max_output_bytes: usize,
+    /// Idle workers kept per runtime. Calls beyond this many at once start
+    /// extra workers, which are stopped once they finish.
+    pub max_workers: usize,
+    pub on_error: TransformationPolicy,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            awk_command: String::from("awk"),
+            node_command: String::from("node"),
+            python_command: String::from("python3"),
+            quickjs_command: None,
+            timeout_ms: 5000,
+            memory_limit_mb: 256,
+            wasm_fuel: 100_000_000,
+            max_output_bytes: 1024 * 1024,
+            max_workers: 4,
+            on_error: TransformationPolicy::Fail,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub llm: LlmConfig,
     pub dev: DevConfig,
+    #[serde(default)]
+    pub profile: ProfileConfig,
+    #[serde(default)]
+    pub features: FeatureConfig,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub lineage: LineageConfig,
+    #[serde(default)]
+    pub hash: HashConfig,
+    #[serde(default)]
+    pub ids: IdConfig,
 }
 
 fn get_default_debug_dir() -> String {
@@ -62,7 +253,13 @@ impl Config {
             },
             dev: DevConfig {
                 debug_dir: get_default_debug_dir(),
-            }
+            },
+            profile: ProfileConfig::default(),
+            features: FeatureConfig::default(),
+            runtime: RuntimeConfig::default(),
+            lineage: LineageConfig::default(),
+            hash: HashConfig::default(),
+            ids: IdConfig::default(),
         }
     }
 
@@ -81,6 +278,22 @@ impl Config {
             panic!("It makes no sense for target_node_examples_max_count to be less than 1");
         }
 
+        if !(0.0..=1.0).contains(&config.profile.similarity_threshold) {
+            panic!("profile.similarity_threshold must be between 0 and 1");
+        }
+
+        if config.runtime.timeout_ms < 1 || config.runtime.memory_limit_mb < 1 || config.runtime.max_output_bytes < 1 || config.runtime.wasm_fuel < 1 {
+            panic!("runtime.timeout_ms, runtime.memory_limit_mb, runtime.max_output_bytes and runtime.wasm_fuel must be at least 1");
+        }
+
+        if config.lineage.max_cycle_length < 1 {
+            panic!("lineage.max_cycle_length must be at least 1");
+        }
+
+        if config.profile.index_bands < 1 || config.profile.index_rows_per_band < 1 {
+            panic!("profile.index_bands and profile.index_rows_per_band must be at least 1");
+        }
+
         Ok(config)
     }
 
@@ -2,93 +2,109 @@ use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use std::collections::{HashSet, VecDeque};
 
-use crate::id::{ID};
+use crate::prelude::*;
 use crate::document_node::DocumentNode;
-use crate::graph_node::{Graph, GraphNode};
-use crate::macros::*;
+use crate::graph_node::Graph;
 
-type ContextID = ID;
+pub type ContextID = ID;
 
 pub struct Context {
     document_nodes: HashMap<ContextID, Arc<RwLock<DocumentNode>>>,
-    data_nodes: HashMap<ContextID, Arc<RwLock<DataNode>>>,
-    graph_nodes: HashMap<ContextID, Arc<RwLock<GraphNode>>>,
+    graph_nodes: HashMap<ContextID, Graph>,
+    root_context_id: Option<ContextID>,
 }
 
 impl Context {
     pub fn new() -> Self {
         Context {
             document_nodes: HashMap::new(),
-            data_nodes: HashMap::new(),
             graph_nodes: HashMap::new(),
+            root_context_id: None,
         }
     }
 
-    pub fn entry(&mut self, node: &DocumentNode) -> ID {
-        let id = ID::new();
-
-        if self.document_nodes.is_empty() {
-            self.root_context_id = Some(id.clone());
+    /// The first node registered is the root
+    pub fn register(
+        &mut self,
+        context_id: &ContextID,
+        document_node: Arc<RwLock<DocumentNode>>,
+        graph_node: Graph,
+    ) {
+        if self.root_context_id.is_none() {
+            self.root_context_id = Some(context_id.clone());
         }
 
-        self.document_nodes.insert(id.clone(), node.clone());
-
-        id
-    }
-
-    pub fn register_data_node(&mut self, id: ContextID, data_node_id: ID) {
-        self.data_nodes.insert(id, data_node_id.clone());
+        self.document_nodes.insert(context_id.clone(), document_node);
+        self.graph_nodes.insert(context_id.clone(), graph_node);
     }
 
-    pub fn register_graph_node(&mut self, id: ContextID, graph_node: Graph) {
-        self.graph_nodes.insert(id, graph_node.clone());
+    pub fn get_graph_node(&self, context_id: &ContextID) -> Option<Graph> {
+        self.graph_nodes.get(context_id).cloned()
     }
 
-    pub fn get_snippet(&self, context_id: &ContextID) -> String {
+    /// The part of the document around a node and its graph neighbours,
+    /// with the node itself marked
+    pub fn get_snippet(&self, context_id: &ContextID) -> Result<String, Errors> {
         log::trace!("In get_snippet");
 
-
-        let document_node = self.document_nodes.get(context_id).unwrap();
-
-
-        let graph_node = self.graph_nodes.get(context_id).clone().unwrap();
-
+        let document_node = self.get_document_node(context_id)?;
+        let graph_node = self.graph_nodes.get(context_id).ok_or_else(|| {
+            log::error!("No graph node is registered for context {}", context_id.to_string());
+            Errors::UnexpectedError
+        })?;
 
         let mut document_node_ids: HashSet<ID> = HashSet::new();
         self.traverse_neighbours(
             Arc::clone(graph_node),
             &mut document_node_ids
-        );
+        )?;
 
         log::debug!("document_node_ids: {:?}", document_node_ids);
 
-
         let mut snippet = String::new();
 
-        let root_context_id = self.root_context_id.clone().unwrap();
-        let root_document_node = self.document_nodes.get(&root_context_id).unwrap();
+        let root_context_id = self.root_context_id.clone().ok_or_else(|| {
+            log::error!("No document nodes are registered");
+            Errors::UnexpectedError
+        })?;
+        let root_graph_node = self.graph_nodes.get(&root_context_id).ok_or_else(|| {
+            log::error!("No graph node is registered for the root context");
+            Errors::UnexpectedError
+        })?;
+        let target_id = read_lock!(document_node).id.clone();
 
         self.traverse_document(
             &mut snippet,
-            &root_document_node,
+            root_graph_node,
             &document_node_ids,
-            &document_node.id
-        );
+            &target_id
+        )?;
 
-        log::debug!("-----------------------------------------------------------------------------------------------------");
         log::debug!("snippet: {}", snippet);
 
+        Ok(snippet)
+    }
 
-        unimplemented!()
+    fn get_document_node(&self, context_id: &ContextID) -> Result<Arc<RwLock<DocumentNode>>, Errors> {
+        self.document_nodes.get(context_id).cloned().ok_or_else(|| {
+            log::error!("No document node is registered for context {}", context_id.to_string());
+            Errors::UnexpectedError
+        })
     }
 
+    /// Walks the registered nodes rather than the document itself, so that
+    /// the snippet holds the nodes analysis saw, with the same IDs
     fn traverse_document(
         &self,
         snippet: &mut String,
-        document_node: &DocumentNode,
+        graph_node: &Graph,
         document_node_ids: &HashSet<ID>,
         target_id: &ID
-    ) {
+    ) -> Result<(), Errors> {
+        let graph_node = read_lock!(graph_node);
+        let document_node = self.get_document_node(&graph_node.context_id)?;
+        let document_node = read_lock!(document_node);
+
         let (mut a, b) = document_node.to_string_components();
 
         if document_node.id == *target_id {
@@ -102,13 +118,13 @@ impl Context {
                 snippet.push_str(&a);
             }
 
-            for child in document_node.get_children(None) {
+            for child in graph_node.children.iter() {
                 self.traverse_document(
                     snippet,
-                    &child,
+                    child,
                     document_node_ids,
                     target_id
-                );
+                )?;
             }
 
             if should_render {
@@ -117,48 +133,43 @@ impl Context {
         } else if should_render {
             snippet.push_str(&a);
         }
+
+        Ok(())
     }
 
     fn traverse_neighbours(
         &self,
         graph_node: Graph,
         visited: &mut HashSet<ID>,
-    ) {
+    ) -> Result<(), Errors> {
         let mut stack = VecDeque::new();
         stack.push_back(Arc::clone(&graph_node));
 
         while let Some(node) = stack.pop_back() {
             let lock = read_lock!(node);
-            let document_node = self.document_nodes.get(&lock.context_id).unwrap();
+            let document_node_id = read_lock!(self.get_document_node(&lock.context_id)?).id.clone();
 
-            if visited.contains(&document_node.id) {
+            if visited.contains(&document_node_id) {
                 continue;
             }
 
-            visited.insert(document_node.id.clone());
+            visited.insert(document_node_id);
 
             if visited.len() > 20 {
-                return;
+                return Ok(());
             }
 
-            for child in lock.children.iter() {
-                let context_id = &read_lock!(child).context_id;
-                let document_node = self.document_nodes.get(context_id).unwrap();
+            for neighbour in lock.children.iter().chain(lock.parents.first()) {
+                let context_id = read_lock!(neighbour).context_id.clone();
+                let document_node_id = read_lock!(self.get_document_node(&context_id)?).id.clone();
 
-                if !visited.contains(&document_node.id) {
-                    stack.push_back(Arc::clone(child));
-                }
-            }
-
-            if let Some(parent) = lock.parents.first() {
-                let context_id = &read_lock!(parent).context_id;
-                let document_node = self.document_nodes.get(context_id).unwrap();
-
-                if !visited.contains(&document_node.id) {
-                    stack.push_back(Arc::clone(parent));
+                if !visited.contains(&document_node_id) {
+                    stack.push_back(Arc::clone(neighbour));
                 }
             }
         }
+
+        Ok(())
     }
 
     fn mark_text(text: &str) -> String {
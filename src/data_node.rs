@@ -2,6 +2,11 @@ use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 
 use crate::prelude::*;
+use crate::config::{CONFIG};
+use crate::document_node::DocumentNode;
+use crate::fingerprint;
+use crate::hash::HashAlgorithm;
+use crate::lineage::{describe_segment, LineageSettings};
 use crate::transformation::HashTransformation;
 
 pub type DataNodeFields = HashMap<String, String>;
@@ -13,25 +18,40 @@ pub struct DataNode {
     pub lineage: Lineage,
     pub fields: DataNodeFields,
     pub description: String,
+    /// Structural SimHash, computed when lineage groups are clustered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<u64>,
 }
 
 impl DataNode {
+    /// Nothing if the node is skipped because its hash transformation failed
     pub fn new(
         hash_transformation: &HashTransformation,
-        fields: DataNodeFields,
-        description: String,
+        hash_algorithm: HashAlgorithm,
+        lineage_settings: &LineageSettings,
+        document_node: &DocumentNode,
         parent_lineage: &Lineage,
-    ) -> Self {
-        let hash: Hash = hash_transformation.transform(fields.clone());
-        let lineage = parent_lineage.with_hash(hash.clone());
+    ) -> Result<Option<Self>, Errors> {
+        let fields: DataNodeFields = document_node.get_fields();
+        let description = document_node.get_description();
 
-        DataNode {
-            id: ID::new(),
+        let hash: Hash = match hash_transformation.transform(fields.clone(), &lineage_settings.hash_scheme, hash_algorithm)? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        let lineage = parent_lineage.with_hash(hash.clone(), describe_segment(&fields), lineage_settings);
+
+        let fingerprint = read_lock!(CONFIG).lineage.fingerprint_distance
+            .map(|_| fingerprint::fingerprint(&fields));
+
+        Ok(Some(DataNode {
+            id: ID::derive_or_new(&[&lineage.to_string(), &document_node.id.to_string()]),
             hash,
             fields,
             lineage,
             description,
-        }
+            fingerprint,
+        }))
     }
 
     pub fn get_hash(&self) -> Hash {
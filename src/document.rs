@@ -12,7 +12,8 @@ use crate::document_node::{DocumentNode};
 use crate::provider::Provider;
 use crate::profile::Profile;
 use crate::transformation::XMLElementTransformation;
-use crate::hash::{Hash};
+use crate::hash::{Hash, HashAlgorithm, HashScheme};
+use crate::config::{CONFIG, FeatureConfig};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum DocumentType {
@@ -71,7 +72,7 @@ impl Document {
             let mut reader = std::io::Cursor::new(xml);
 
             match Element::parse(reader) {
-                Ok(element) => Ok(DocumentNode::new(xmltree::XMLNode::Element(element))),
+                Ok(element) => Ok(DocumentNode::new_root(xmltree::XMLNode::Element(element))),
                 Err(e) => {
                     log::error!("Could not parse XML: {}", e);
 
@@ -83,48 +84,84 @@ impl Document {
         }
     }
 
+    pub fn get_feature_paths(&self) -> Result<HashSet<String>, Errors> {
+        log::trace!("In document/get_feature_paths");
+
+        let dom = self.to_dom().ok_or(Errors::UnexpectedDocumentType)?;
+
+        let mut features: HashSet<String> = HashSet::new();
+
+        get_xml_features(
+            &dom.document,
+            &mut String::from(""),
+            0,
+            &mut features,
+            &read_lock!(CONFIG).features,
+        );
+
+        Ok(features)
+    }
+
     pub async fn perform_analysis<P: Provider>(
         &mut self,
-        provider: Arc<P>
+        provider: Arc<P>,
+        options: &Option<Options>,
     ) -> Result<Profile, Errors> {
         log::trace!("In document/perform_analysis");
 
-        if let Some(dom) = self.to_dom() {
+        if self.to_dom().is_some() {
             log::info!("It seems to be possible to parse this document as XML");
 
             self.document_type = DocumentType::XML;
 
-            let mut features: HashSet<String> = HashSet::new();
+            let feature_paths = self.get_feature_paths()?;
 
-            get_xml_features(
-                &dom.document,
-                &mut String::from(""),
-                &mut features,
-            );
+            // Profiles keep the algorithm they were created with, so the
+            // document is compared under each algorithm in turn
+            let mut profile_match = None;
 
-            let features: HashSet<Hash> = features.iter().map(|feature| {
-                let mut hash = Hash::new();
-                hash.push(feature).finalize().clear_items();
-                hash.clone()
-            }).collect();
+            for algorithm in HashAlgorithm::all() {
+                let features: HashSet<Hash> = feature_paths.iter()
+                    .map(|path| hash_feature(path, algorithm))
+                    .collect();
 
-            if let Some(profile) = provider.get_profile(&features).await? {
-                log::info!("Found a profile");
+                profile_match = provider.get_profile(&features, options).await?;
 
-                if profile.xml_element_transformation.is_none() {
-                    log::info!("Profile provided but xml transformation missing");
-                    unimplemented!();
+                if profile_match.is_some() {
+                    break;
                 }
+            }
 
-                if profile.hash_transformation.is_none() {
-                    log::info!("Profile provided by hash transformation is missing");
-                    unimplemented!();
+            if let Some(profile_match) = profile_match {
+                log::info!(
+                    "Found a profile with similarity {} (threshold {})",
+                    profile_match.similarity,
+                    profile_match.threshold
+                );
+
+                let mut profile = profile_match.profile;
+
+                if profile.complete_with_defaults() {
+                    provider.save_profile(&profile).await?;
                 }
 
                 Ok(profile)
             } else {
                 log::info!("Profile not provided, we will create a new one");
-                unimplemented!();
+
+                let features: HashSet<Hash> = feature_paths.iter()
+                    .map(|path| hash_feature(path, HashAlgorithm::current()))
+                    .collect();
+
+                let profile = Profile::bootstrap(features, &self.metadata.origin)
+                    .with_feature_paths(&feature_paths);
+
+                provider.save_profile(&profile).await?;
+                provider.reweight_profiles().await?;
+
+                log::info!("Saved generated profile {}", profile.id.to_string());
+
+                Ok(profile)
             }
         } else {
              Err(Errors::UnexpectedDocumentType)
@@ -172,15 +209,26 @@ impl Document {
     }
 }
 
+/// A feature is a single item, which concatenation already encodes
+/// unambiguously, so it keeps the legacy encoding and stored feature hashes
+/// stay valid under every scheme
+pub fn hash_feature(feature: &String, algorithm: HashAlgorithm) -> Hash {
+    let mut hash = Hash::new();
+    hash.push(feature).finalize_with(&HashScheme::legacy(), algorithm).clear_items();
+    hash.clone()
+}
+
 fn get_xml_features(
     node: &Handle,
     path: &mut String,
+    depth: usize,
     features: &mut HashSet<String>,
+    feature_config: &FeatureConfig,
 ) {
     match &node.data {
         NodeData::Document => {
             for child in node.children.borrow().iter() {
-                get_xml_features(child, path, features);
+                get_xml_features(child, path, depth, features, feature_config);
             }
         }
         NodeData::Text { .. } => {
@@ -191,15 +239,28 @@ fn get_xml_features(
             ref attrs,
             ..
         } => {
+            if feature_config.max_depth.map_or(false, |max_depth| depth >= max_depth) {
+                return;
+            }
+
             let mut new_path = format!("{}/{}", path, name.local);
 
             for attr in attrs.borrow().iter() {
                 let attr_name = attr.name.local.trim();
                 features.insert(format!("{}.{}", new_path, attr_name));
+
+                let is_token_feature = (attr_name == "class" && feature_config.include_class_tokens) ||
+                    (attr_name == "id" && feature_config.include_id_tokens);
+
+                if is_token_feature {
+                    for token in attr.value.split_whitespace() {
+                        features.insert(format!("#{}:{}", attr_name, token));
+                    }
+                }
             }
 
             for child in node.children.borrow().iter() {
-                get_xml_features(child, &mut new_path, features);
+                get_xml_features(child, &mut new_path, depth + 1, features, feature_config);
             }
         }
         _ => {}
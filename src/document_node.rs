@@ -3,6 +3,7 @@ use xmltree::{XMLNode, Element};
 use std::collections::HashMap;
 
 use crate::prelude::*;
+use crate::config::{CONFIG, IdMode};
 use crate::transformation::XMLElementTransformation;
 
 #[derive(Clone, Debug)]
@@ -19,6 +20,14 @@ impl DocumentNode {
         }
     }
 
+    /// A root node, whose ID is fixed unless IDs are random
+    pub fn new_root(xml_node: XMLNode) -> Self {
+        DocumentNode {
+            id: ID::derive_or_new(&["root"]),
+            data: xml_node,
+        }
+    }
+
     pub fn to_string_components(&self) -> (String, Option<String>) {
         match &self.data {
             XMLNode::Element(element_node) => {
@@ -37,7 +46,7 @@ impl DocumentNode {
     pub fn from_transformations(
         xml_node: XMLNode,
         xml_element_transformation: XMLElementTransformation,
-    ) -> Option<Self> {
+    ) -> Result<Option<Self>, Errors> {
         match &xml_node {
             XMLNode::Element(element_node) => {
                 let mut element: Option<String> = Some(element_node.name.clone());
@@ -52,7 +61,7 @@ impl DocumentNode {
                 let (transformed_element, transformed_attributes) = xml_element_transformation.transform(
                     element.unwrap().clone(),
                     attributes.clone()
-                );
+                )?;
 
                 attributes = transformed_attributes;
 
@@ -65,7 +74,7 @@ impl DocumentNode {
 
                 log::info!("Done applying XML element transformations.");
 
-                element.map(|some_element| {
+                Ok(element.map(|some_element| {
                     let mut transformed_node = xml_node.clone();
 
                     if let XMLNode::Element(ref mut elem) = transformed_node {
@@ -74,10 +83,10 @@ impl DocumentNode {
                     }
 
                     DocumentNode::new(transformed_node)
-                })
+                }))
             },
             XMLNode::Text(_text_node) => {
-                Some(DocumentNode::new(xml_node))
+                Ok(Some(DocumentNode::new(xml_node)))
             },
             _ => panic!("Unexpected XML node type")
         }
@@ -115,12 +124,12 @@ impl DocumentNode {
     pub fn get_children(
         &self,
         xml_element_transformation: Option<XMLElementTransformation>
-    ) -> Vec<DocumentNode> {
+    ) -> Result<Vec<DocumentNode>, Errors> {
         match &self.data {
             XMLNode::Element(element_node) => {
-                element_node.children
+                let children = element_node.children
                     .iter()
-                    .filter_map(|child| {
+                    .map(|child| {
 
                         if let Some(xml_element_transformation) = &xml_element_transformation {
                             DocumentNode::from_transformations(
@@ -128,16 +137,77 @@ impl DocumentNode {
                                 xml_element_transformation.clone()
                             )
                         } else {
-                            Some(DocumentNode::new(child.clone()))
+                            Ok(Some(DocumentNode::new(child.clone())))
                         }
                     })
-                    .collect()
+                    .collect::<Result<Vec<Option<DocumentNode>>, Errors>>()?;
+
+                let mode = read_lock!(CONFIG).ids.mode.clone();
+                let mut occurrences: HashMap<String, usize> = HashMap::new();
+
+                // Positions count children eliminated by the transformation,
+                // so that IDs do not shift when it changes
+                Ok(children.into_iter()
+                    .enumerate()
+                    .filter_map(|(position, child)| child.map(|child| (position, child)))
+                    .map(|(position, mut child)| {
+                        child.id = self.get_child_id(&mode, position, &child, &mut occurrences);
+                        child
+                    })
+                    .collect())
             },
-            XMLNode::Text(text_node) => Vec::new(),
+            XMLNode::Text(_) => Ok(Vec::new()),
             _ => panic!("Unexpected XML node type")
         }
     }
 
+    fn get_child_id(
+        &self,
+        mode: &IdMode,
+        position: usize,
+        child: &DocumentNode,
+        occurrences: &mut HashMap<String, usize>,
+    ) -> ID {
+        let parent_id = self.id.to_string();
+
+        match mode {
+            IdMode::Random => child.id.clone(),
+            IdMode::Position => ID::derive(&[&parent_id, &position.to_string()]),
+            IdMode::Content => {
+                let mut markup = String::new();
+                DocumentNode::get_markup(&child.data, &mut markup);
+
+                // Derived independently of the configured hash algorithm, so
+                // that changing it leaves IDs alone
+                let content = ID::derive(&[&markup]).to_string();
+
+                // Copies of the same subtree are told apart by their order
+                let occurrence = occurrences.entry(content.clone()).or_insert(0);
+                *occurrence += 1;
+
+                ID::derive(&[&parent_id, &content, &occurrence.to_string()])
+            },
+        }
+    }
+
+    /// The markup of the node and everything beneath it, with attributes in
+    /// a fixed order
+    fn get_markup(xml_node: &XMLNode, markup: &mut String) {
+        match xml_node {
+            XMLNode::Element(element) => {
+                markup.push_str(&DocumentNode::get_opening_tag(element));
+
+                for child in element.children.iter() {
+                    DocumentNode::get_markup(child, markup);
+                }
+
+                markup.push_str(&DocumentNode::get_closing_tag(element));
+            },
+            XMLNode::Text(text) | XMLNode::CData(text) => markup.push_str(text),
+            _ => {},
+        }
+    }
+
     fn get_opening_tag(element: &Element) -> String {
         let mut tag = format!("<{}", element.name);
 
@@ -158,3 +228,54 @@ impl DocumentNode {
         format!("</{}>", element.name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_ids(markup: &str) -> Vec<ID> {
+        let parent = DocumentNode {
+            id: ID::from_str("parent"),
+            data: XMLNode::Element(Element::parse(markup.as_bytes()).unwrap()),
+        };
+
+        let children = match &parent.data {
+            XMLNode::Element(element) => element.children.clone(),
+            _ => unreachable!(),
+        };
+
+        let mut occurrences: HashMap<String, usize> = HashMap::new();
+
+        children.into_iter()
+            .enumerate()
+            .map(|(position, child)| {
+                parent.get_child_id(&IdMode::Content, position, &DocumentNode::new(child), &mut occurrences)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn content_ids_survive_inserted_siblings() {
+        let ids = content_ids(
+            "<table><tr class=\"athing\"><td>One</td></tr><tr class=\"athing\"><td>Two</td></tr></table>"
+        );
+        let inserted = content_ids(
+            "<table><tr class=\"athing\"><td>New</td></tr><tr class=\"athing\"><td>One</td></tr><tr class=\"athing\"><td>Two</td></tr></table>"
+        );
+
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(ids, inserted[1..]);
+        assert!(!ids.contains(&inserted[0]));
+    }
+
+    #[test]
+    fn content_ids_follow_the_whole_subtree() {
+        let ids = content_ids("<ul><li><b>One</b></li><li><b>One</b></li></ul>");
+        let changed = content_ids("<ul><li><b>Uno</b></li><li><b>One</b></li></ul>");
+
+        // Copies are told apart by their order
+        assert_ne!(ids[0], ids[1]);
+        assert_ne!(ids[0], changed[0]);
+        assert_eq!(ids[0], changed[1]);
+    }
+}
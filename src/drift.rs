@@ -0,0 +1,352 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+use crate::data_node::DataNode;
+use crate::document::{Document, hash_feature};
+use crate::document_node::DocumentNode;
+use crate::fingerprint::cluster_lineage_groups;
+use crate::hash::HashAlgorithm;
+use crate::profile::Profile;
+use crate::provider::Provider;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SimilarityTrend {
+    /// Similarity from the most recent earlier report for the same profile
+    pub previous: f64,
+    /// Change in similarity since the previous report
+    pub delta: f64,
+    /// Mean similarity across all earlier reports for the same profile
+    pub mean: f64,
+    pub samples: usize,
+}
+
+/// How a document has moved away from the profile it was matched against
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DriftReport {
+    pub profile_id: ID,
+    pub profile_description: String,
+    pub origin: Option<String>,
+    pub date: Option<String>,
+    pub similarity: f64,
+    pub threshold: f64,
+    /// Feature paths present in the document but unknown to the profile
+    pub added_features: Vec<String>,
+    /// Profile features no longer present in the document. Features of
+    /// profiles saved before paths were recorded are named by their hash.
+    pub removed_features: Vec<String>,
+    /// Lineages found in the document with no stored basis node
+    pub new_lineages: Vec<String>,
+    /// Lineages of the profile's stored basis nodes not found in the document
+    pub missing_lineages: Vec<String>,
+    /// CSS selector of each new or missing lineage, for lineages stored
+    /// with a readable path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub lineage_selectors: BTreeMap<String, String>,
+    #[serde(default)]
+    pub trend: Option<SimilarityTrend>,
+    pub drifted: bool,
+}
+
+impl DriftReport {
+    /// Compares this report against earlier ones for the same profile
+    pub fn with_trend(mut self, history: &[DriftReport]) -> Self {
+        let previous: Vec<f64> = history.iter()
+            .filter(|report| report.profile_id == self.profile_id)
+            .map(|report| report.similarity)
+            .collect();
+
+        if let Some(last) = previous.last() {
+            self.trend = Some(SimilarityTrend {
+                previous: *last,
+                delta: self.similarity - last,
+                mean: previous.iter().sum::<f64>() / previous.len() as f64,
+                samples: previous.len(),
+            });
+        }
+
+        self
+    }
+}
+
+/// Detects structural drift of a document against a stored profile. Unless a
+/// profile is given, the closest profile is used even if it falls below the
+/// similarity threshold, since that is exactly the case worth reporting.
+pub async fn detect_drift<P: Provider>(
+    provider: Arc<P>,
+    document: &Document,
+    profile_id: Option<&ID>,
+    options: &Option<Options>,
+) -> Result<DriftReport, Errors> {
+    log::trace!("In detect_drift");
+
+    let feature_paths = document.get_feature_paths()?;
+    let profiles = provider.list_profiles().await?;
+
+    let get_features = |algorithm: HashAlgorithm| -> HashSet<Hash> {
+        feature_paths.iter()
+            .map(|path| hash_feature(path, algorithm))
+            .collect()
+    };
+
+    let (algorithm, similarity, profile) = match profile_id {
+        Some(profile_id) => {
+            let algorithm = profiles.iter()
+                .find(|profile| &profile.id == profile_id)
+                .map(|profile| profile.hash_algorithm)
+                .ok_or(Errors::ProfileNotFound)?;
+
+            let (similarity, profile) = Profile::rank_profiles(&profiles, &get_features(algorithm), &None)
+                .into_iter()
+                .find(|(_, profile)| &profile.id == profile_id)
+                .ok_or(Errors::ProfileNotFound)?;
+
+            (algorithm, similarity, profile)
+        },
+        None => {
+            // Profiles keep the algorithm they were created with, so the
+            // document is compared under each algorithm in turn
+            HashAlgorithm::all()
+                .into_iter()
+                .filter_map(|algorithm| {
+                    Profile::rank_profiles(&profiles, &get_features(algorithm), &document.metadata.origin)
+                        .into_iter()
+                        .next()
+                        .map(|(similarity, profile)| (algorithm, similarity, profile))
+                })
+                .fold(None, |best: Option<(HashAlgorithm, f64, &Profile)>, candidate| match best {
+                    Some(best) if best.1 >= candidate.1 => Some(best),
+                    _ => Some(candidate),
+                })
+                .ok_or(Errors::ProfileNotFound)?
+        },
+    };
+
+    let features = get_features(algorithm);
+
+    let mut profile = profile.clone();
+    profile.complete_with_defaults();
+
+    let threshold = profile.get_similarity_threshold(options);
+
+    let added_features: Vec<String> = feature_paths.iter()
+        .filter(|path| !profile.features.contains(&hash_feature(path, algorithm)))
+        .cloned()
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+
+    let removed_features: Vec<String> = profile.features.iter()
+        .filter(|feature| !features.contains(feature))
+        .filter_map(|feature| profile.feature_paths.get(feature).cloned().or_else(|| feature.to_string()))
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+
+    let document_lineages: HashSet<Lineage> = get_document_lineages(document, &profile)?;
+
+    let stored_basis_lineages: Vec<Lineage> = provider.list_basis_nodes().await?
+        .into_iter()
+        .filter(|basis_node| basis_node.profile_id.as_ref() == Some(&profile.id))
+        .map(|basis_node| basis_node.lineage)
+        .collect();
+
+    let found_lineages: HashSet<String> = document_lineages.iter()
+        .map(|lineage| lineage.to_string())
+        .collect();
+
+    let stored_lineages: HashSet<String> = stored_basis_lineages.iter()
+        .map(|lineage| lineage.to_string())
+        .collect();
+
+    let new_lineages: Vec<String> = found_lineages
+        .difference(&stored_lineages)
+        .cloned()
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+
+    let missing_lineages: Vec<String> = stored_lineages
+        .difference(&found_lineages)
+        .cloned()
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+
+    let lineage_selectors: BTreeMap<String, String> = document_lineages.iter()
+        .chain(stored_basis_lineages.iter())
+        .map(|lineage| (lineage.to_string(), lineage.to_css_selector()))
+        .filter(|(lineage, selector)| {
+            !selector.is_empty() && (new_lineages.contains(lineage) || missing_lineages.contains(lineage))
+        })
+        .collect();
+
+    // Without stored basis nodes every lineage is new, which says nothing
+    // about drift
+    let drifted = similarity < threshold ||
+        !missing_lineages.is_empty() ||
+        (!stored_lineages.is_empty() && !new_lineages.is_empty());
+
+    log::info!(
+        "Drift against profile {}: similarity {} (threshold {}), {} added features, {} removed features, {} new lineages, {} missing lineages",
+        profile.id.to_string(),
+        similarity,
+        threshold,
+        added_features.len(),
+        removed_features.len(),
+        new_lineages.len(),
+        missing_lineages.len(),
+    );
+
+    Ok(DriftReport {
+        profile_id: profile.id.clone(),
+        profile_description: profile.description.clone(),
+        origin: document.metadata.origin.clone(),
+        date: document.metadata.date.clone(),
+        similarity,
+        threshold,
+        added_features,
+        removed_features,
+        new_lineages,
+        missing_lineages,
+        lineage_selectors,
+        trend: None,
+        drifted,
+    })
+}
+
+/// Lineages of every node in the document, derived with the profile's
+/// transformations the same way analysis derives them
+fn get_document_lineages(
+    document: &Document,
+    profile: &Profile,
+) -> Result<HashSet<Lineage>, Errors> {
+    log::trace!("In get_document_lineages");
+
+    let hash_transformation = profile.hash_transformation.clone()
+        .ok_or_else(|| Errors::ProfileValidationError("Profile lacks a hash transformation".into()))?;
+
+    let document_node = document.get_document_node()?;
+    let lineage_settings = profile.get_lineage_settings();
+
+    let mut lineage_groups: HashMap<Lineage, Vec<DataNode>> = HashMap::new();
+    let mut stack: Vec<(DocumentNode, Lineage)> = vec![(document_node, Lineage::new())];
+
+    while let Some((document_node, parent_lineage)) = stack.pop() {
+        let data_node = match DataNode::new(
+            &hash_transformation,
+            profile.hash_algorithm,
+            &lineage_settings,
+            &document_node,
+            &parent_lineage,
+        )? {
+            Some(data_node) => data_node,
+            None => continue,
+        };
+
+        for child in document_node.get_children(profile.xml_element_transformation.clone())? {
+            stack.push((child, data_node.lineage.clone()));
+        }
+
+        lineage_groups.entry(data_node.lineage.clone()).or_default().push(data_node);
+    }
+
+    let fingerprint_distance = read_lock!(CONFIG).lineage.fingerprint_distance;
+
+    let lineage_groups = match fingerprint_distance {
+        Some(max_distance) => cluster_lineage_groups(lineage_groups, max_distance, &lineage_settings),
+        None => lineage_groups,
+    };
+
+    Ok(lineage_groups.into_keys().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basis_node::BasisNode;
+    use crate::organization::organize;
+    use crate::provider::JsonFileProvider;
+    use crate::testing::{basis_node, temp_path};
+
+    const ORIGINAL: &str = "<html><body><div class=\"item\"><p>One</p></div></body></html>";
+    const CHANGED: &str = "<html><body><div class=\"item\"></div><span>Two</span></body></html>";
+
+    #[tokio::test]
+    async fn detect_drift_compares_against_the_profile_basis_nodes() {
+        let path = temp_path("drift");
+        std::fs::write(&path, "{}").unwrap();
+
+        let provider = Arc::new(JsonFileProvider::new(path.clone()));
+
+        let original = Document::from_string(ORIGINAL.to_string(), &None).unwrap();
+        organize(Arc::clone(&provider), original.clone(), &None).await.unwrap();
+
+        let profile = provider.list_profiles().await.unwrap().remove(0);
+
+        for lineage in get_document_lineages(&original, &profile).unwrap() {
+            provider.save_basis_node(&BasisNode {
+                hash_scheme: profile.hash_scheme.clone(),
+                lineage_mode: profile.lineage_mode.clone(),
+                lineage_max_cycle_length: profile.lineage_max_cycle_length,
+                ..basis_node(lineage)
+            }).await.unwrap();
+        }
+
+        // Analysing a document claims the basis nodes for its profile
+        organize(Arc::clone(&provider), original.clone(), &None).await.unwrap();
+
+        let report = detect_drift(Arc::clone(&provider), &original, Some(&profile.id), &None).await.unwrap();
+
+        assert!(report.new_lineages.is_empty());
+        assert!(report.missing_lineages.is_empty());
+        assert!(report.removed_features.is_empty());
+        assert!(!report.drifted);
+
+        let changed = Document::from_string(CHANGED.to_string(), &None).unwrap();
+        let report = detect_drift(Arc::clone(&provider), &changed, Some(&profile.id), &None).await.unwrap();
+
+        let original_paths = original.get_feature_paths().unwrap();
+
+        assert_eq!(report.new_lineages.len(), 2);
+        assert_eq!(report.missing_lineages.len(), 2);
+        assert!(!report.removed_features.is_empty());
+        assert!(report.removed_features.iter().all(|feature| original_paths.contains(feature)));
+        assert!(report.drifted);
+
+        let selectors: BTreeSet<&str> = report.lineage_selectors.values().map(String::as_str).collect();
+        assert_eq!(report.lineage_selectors.len(), 4);
+        assert_eq!(selectors, BTreeSet::from(["html > body > div.item > p", "html > body > span"]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn detect_drift_finds_profiles_hashed_with_another_algorithm() {
+        let path = temp_path("drift");
+        std::fs::write(&path, "{}").unwrap();
+
+        let provider = Arc::new(JsonFileProvider::new(path.clone()));
+
+        let document = Document::from_string(ORIGINAL.to_string(), &None).unwrap();
+        let algorithm = HashAlgorithm::all().pop().unwrap();
+        assert_ne!(algorithm, HashAlgorithm::current());
+
+        let features: HashSet<Hash> = document.get_feature_paths().unwrap().iter()
+            .map(|path| hash_feature(path, algorithm))
+            .collect();
+        let profile = Profile::bootstrap(features, &None);
+        provider.save_profile(&profile).await.unwrap();
+
+        let report = detect_drift(Arc::clone(&provider), &document, None, &None).await.unwrap();
+
+        assert_eq!(report.profile_id, profile.id);
+        assert_eq!(report.similarity, 1.0);
+        assert!(report.added_features.is_empty());
+        assert!(report.removed_features.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
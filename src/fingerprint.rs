@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, HashMap};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::prelude::*;
+use crate::data_node::{DataNode, DataNodeFields};
+use crate::lineage::LineageSettings;
+
+/// Fields whose values describe structure rather than content. Their words
+/// count as tokens, while other fields only count by name.
+const STRUCTURAL_FIELDS: [&str; 3] = ["tag", "class", "role"];
+
+/// SimHash of a node's structure. Nodes that differ by a class or an
+/// attribute get fingerprints a few bits apart, where their exact hashes
+/// would have nothing in common.
+pub fn fingerprint(fields: &DataNodeFields) -> u64 {
+    let mut weights = [0i64; 64];
+
+    for (token, weight) in get_tokens(fields) {
+        let hash = xxh3_64(token.as_bytes());
+
+        for (bit, total) in weights.iter_mut().enumerate() {
+            if hash >> bit & 1 == 1 {
+                *total += weight;
+            } else {
+                *total -= weight;
+            }
+        }
+    }
+
+    to_bits(&weights)
+}
+
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Merges lineage groups whose fingerprints are at most `max_distance` bits
+/// apart, as long as they share a parent. Levels are merged from the root
+/// down, so the descendants of merged groups end up together as well. Data
+/// nodes take the lineage of the group they were merged into.
+pub fn cluster_lineage_groups(
+    lineage_groups: HashMap<Lineage, Vec<DataNode>>,
+    max_distance: u32,
+    settings: &LineageSettings,
+) -> HashMap<Lineage, Vec<DataNode>> {
+    log::trace!("In cluster_lineage_groups");
+
+    let group_count = lineage_groups.len();
+
+    let mut levels: BTreeMap<usize, Vec<(Lineage, Vec<DataNode>)>> = BTreeMap::new();
+
+    for (lineage, group) in lineage_groups.into_iter() {
+        levels.entry(lineage.depth()).or_default().push((lineage, group));
+    }
+
+    // Where each original lineage was reparented to
+    let mut reparented_to: HashMap<Lineage, Lineage> = HashMap::new();
+    // Union-find of reparented lineages, pointing towards the group they
+    // were merged into
+    let mut merged_into: HashMap<Lineage, Lineage> = HashMap::new();
+    let mut clustered: HashMap<Lineage, Vec<DataNode>> = HashMap::new();
+
+    for (_, level) in levels.into_iter() {
+        let mut reparented: HashMap<Lineage, Vec<DataNode>> = HashMap::new();
+
+        for (lineage, group) in level.into_iter() {
+            let parent = reparented_to.get(&lineage.parent(settings))
+                .map(|parent| find_representative(&mut merged_into, parent));
+
+            let target = match (parent, lineage.last_hash()) {
+                (Some(parent), Some(hash)) => {
+                    parent.with_hash(hash, lineage.last_segment().unwrap_or_default(), settings)
+                },
+                _ => lineage.clone(),
+            };
+
+            reparented_to.insert(lineage, target.clone());
+            reparented.entry(target).or_default().extend(group);
+        }
+
+        // Larger groups go first so that they absorb the stragglers
+        let mut level: Vec<(Lineage, Vec<DataNode>)> = reparented.into_iter().collect();
+        level.sort_by(|a, b| {
+            b.1.len().cmp(&a.1.len()).then_with(|| a.0.to_string().cmp(&b.0.to_string()))
+        });
+
+        let mut representatives: Vec<(Lineage, Lineage, u64)> = Vec::new();
+
+        for (lineage, group) in level.into_iter() {
+            let parent = lineage.parent(settings);
+            let group_fingerprint = get_group_fingerprint(&group);
+
+            let nearest = group_fingerprint.and_then(|group_fingerprint| {
+                representatives.iter()
+                    .filter(|(_, representative_parent, _)| *representative_parent == parent)
+                    .map(|(representative, _, fingerprint)| {
+                        (distance(*fingerprint, group_fingerprint), representative)
+                    })
+                    .filter(|(distance, _)| *distance <= max_distance)
+                    .min_by_key(|(distance, _)| *distance)
+                    .map(|(distance, representative)| (distance, representative.clone()))
+            });
+
+            match nearest {
+                Some((distance, representative)) => {
+                    log::debug!(
+                        "Merging lineage {:?} into {:?} at distance {}",
+                        lineage,
+                        representative,
+                        distance
+                    );
+
+                    merged_into.insert(lineage, representative.clone());
+
+                    clustered.get_mut(&representative)
+                        .expect("Representative was clustered")
+                        .extend(group);
+                },
+                None => {
+                    if let Some(group_fingerprint) = group_fingerprint {
+                        representatives.push((lineage.clone(), parent, group_fingerprint));
+                    }
+
+                    clustered.insert(lineage, group);
+                },
+            }
+        }
+    }
+
+    log::info!("Clustered {} lineage groups into {}", group_count, clustered.len());
+
+    for (lineage, group) in clustered.iter_mut() {
+        for data_node in group.iter_mut() {
+            data_node.lineage = lineage.clone();
+        }
+    }
+
+    clustered
+}
+
+/// The group a reparented lineage was merged into, or the lineage itself.
+/// Every lineage on the way is pointed straight at it.
+fn find_representative(merged_into: &mut HashMap<Lineage, Lineage>, lineage: &Lineage) -> Lineage {
+    let mut representative = lineage.clone();
+
+    while let Some(next) = merged_into.get(&representative) {
+        representative = next.clone();
+    }
+
+    let mut current = lineage.clone();
+
+    while current != representative {
+        current = merged_into.insert(current, representative.clone())
+            .expect("Lineage on the way was merged");
+    }
+
+    representative
+}
+
+/// The tag weighs double, so that nodes of different tags stay far apart
+/// while an extra class or attribute moves the fingerprint a few bits
+fn get_tokens(fields: &DataNodeFields) -> Vec<(String, i64)> {
+    let mut tokens: Vec<(String, i64)> = Vec::new();
+
+    for (key, value) in fields.iter() {
+        tokens.push((key.clone(), 1));
+
+        if !STRUCTURAL_FIELDS.contains(&key.as_str()) {
+            continue;
+        }
+
+        let weight = if key == "tag" { 2 } else { 1 };
+
+        for word in value.split_whitespace() {
+            tokens.push((format!("{}={}", key, word), weight));
+        }
+    }
+
+    tokens
+}
+
+/// Bitwise majority of the group's fingerprints, or nothing if any node
+/// lacks one
+fn get_group_fingerprint(group: &[DataNode]) -> Option<u64> {
+    let mut weights = [0i64; 64];
+
+    for data_node in group.iter() {
+        let fingerprint = data_node.fingerprint?;
+
+        for (bit, total) in weights.iter_mut().enumerate() {
+            if fingerprint >> bit & 1 == 1 {
+                *total += 1;
+            } else {
+                *total -= 1;
+            }
+        }
+    }
+
+    if group.is_empty() {
+        return None;
+    }
+
+    Some(to_bits(&weights))
+}
+
+fn to_bits(weights: &[i64; 64]) -> u64 {
+    weights.iter()
+        .enumerate()
+        .filter(|(_, total)| **total > 0)
+        .fold(0, |bits, (bit, _)| bits | 1 << bit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(tag: &str, class: &str) -> DataNodeFields {
+        DataNodeFields::from([
+            (String::from("tag"), String::from(tag)),
+            (String::from("class"), String::from(class)),
+        ])
+    }
+
+    fn data_node(lineage: &Lineage, fields: DataNodeFields) -> DataNode {
+        DataNode {
+            id: ID::new(),
+            hash: lineage.last_hash().unwrap(),
+            lineage: lineage.clone(),
+            fingerprint: Some(fingerprint(&fields)),
+            fields,
+            description: String::new(),
+        }
+    }
+
+    #[test]
+    fn distance_counts_differing_bits() {
+        assert_eq!(distance(0, 0), 0);
+        assert_eq!(distance(0b1010, 0b0110), 2);
+        assert_eq!(distance(0, u64::MAX), 64);
+    }
+
+    #[test]
+    fn fingerprint_keeps_similar_structure_close() {
+        let row = fingerprint(&fields("tr", "athing"));
+
+        assert_eq!(row, fingerprint(&fields("tr", "athing")));
+        assert!(distance(row, fingerprint(&fields("tr", "athing odd"))) < distance(row, fingerprint(&fields("div", "athing"))));
+    }
+
+    #[test]
+    fn cluster_merges_siblings_and_their_descendants() {
+        let settings = LineageSettings::current();
+        let segment = |lineage: &Lineage, hash: &str| {
+            lineage.with_hash(Hash::from_str(hash), String::from(hash), &settings)
+        };
+
+        let body = segment(&Lineage::new(), "body");
+        let row = segment(&body, "tr.athing");
+        let odd_row = segment(&body, "tr.athing.odd");
+        let other = segment(&body, "div.athing");
+        let cell = segment(&row, "td");
+        let odd_cell = segment(&odd_row, "td");
+        let odd_span = segment(&odd_cell, "span");
+
+        let max_distance = distance(
+            fingerprint(&fields("tr", "athing")),
+            fingerprint(&fields("tr", "athing odd")),
+        );
+
+        let lineage_groups = HashMap::from([
+            (body.clone(), vec![data_node(&body, fields("body", ""))]),
+            (row.clone(), vec![data_node(&row, fields("tr", "athing")), data_node(&row, fields("tr", "athing"))]),
+            (odd_row.clone(), vec![data_node(&odd_row, fields("tr", "athing odd"))]),
+            (other.clone(), vec![data_node(&other, fields("div", "athing"))]),
+            (cell.clone(), vec![data_node(&cell, fields("td", ""))]),
+            (odd_cell.clone(), vec![data_node(&odd_cell, fields("td", ""))]),
+            (odd_span.clone(), vec![data_node(&odd_span, fields("span", ""))]),
+        ]);
+
+        let clustered = cluster_lineage_groups(lineage_groups, max_distance, &settings);
+
+        let sizes: HashMap<Lineage, usize> = clustered.iter()
+            .map(|(lineage, group)| (lineage.clone(), group.len()))
+            .collect();
+
+        assert_eq!(sizes, HashMap::from([
+            (body, 1),
+            (row.clone(), 3),
+            (other, 1),
+            (cell.clone(), 2),
+            (segment(&cell, "span"), 1),
+        ]));
+        assert!(clustered.iter().all(|(lineage, group)| group.iter().all(|data_node| data_node.lineage == *lineage)));
+    }
+}
@@ -9,6 +9,7 @@ pub type Graph = Arc<RwLock<GraphNode>>;
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GraphNode {
     pub id: ID,
+    pub context_id: ID,
     pub parents: Vec<Graph>,
     pub description: String,
     pub hash: Hash,
@@ -17,11 +18,12 @@ pub struct GraphNode {
 }
 
 impl GraphNode {
-    pub fn from_data_node(data_node: Arc<RwLock<DataNode>>, parents: Vec<Graph>) -> Self {
+    pub fn from_data_node(context_id: &ID, data_node: Arc<RwLock<DataNode>>, parents: Vec<Graph>) -> Self {
         let lock = read_lock!(data_node);
 
         GraphNode {
-            id: ID::new(),
+            id: ID::derive_or_new(&["graph", &lock.id.to_string()]),
+            context_id: context_id.clone(),
             parents,
             description: lock.description.clone(),
             hash: lock.hash.clone(),
@@ -2,25 +2,131 @@ use serde::{Serialize, Deserialize, Serializer, Deserializer};
 use serde::de::{self, Visitor};
 use sha2::{Sha256, Digest};
 use std::fmt;
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::config::{CONFIG};
+use crate::macros::*;
+
+/// How a hash's items are encoded before they are digested. Profiles and
+/// basis nodes record the scheme their hashes were computed with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HashScheme {
+    /// Items concatenated as they are, so `["ab", "c"]` and `["a", "bc"]`
+    /// collide
+    V1,
+    /// Items each prefixed with their length, so that no two lists of
+    /// items encode alike
+    V2,
+}
+
+impl HashScheme {
+    /// The configured scheme, used for every newly computed hash
+    pub fn current() -> Self {
+        read_lock!(CONFIG).hash.scheme.clone()
+    }
+
+    /// The scheme of records that predate versioning
+    pub fn legacy() -> Self {
+        HashScheme::V1
+    }
+
+    fn encode(&self, items: &[String]) -> Vec<u8> {
+        match self {
+            HashScheme::V1 => items.join("").into_bytes(),
+            HashScheme::V2 => items.iter()
+                .map(|item| format!("{}:{}", item.len(), item))
+                .collect::<String>()
+                .into_bytes(),
+        }
+    }
+}
+
+/// The digest behind a hash. Profiles record the algorithm their features
+/// were hashed with, and every hash computed under a profile uses it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, std::hash::Hash, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+    /// Not cryptographic, but much faster on large documents
+    Xxh3_128,
+}
+
+impl HashAlgorithm {
+    /// The configured algorithm, used for new profiles
+    pub fn current() -> Self {
+        read_lock!(CONFIG).hash.algorithm
+    }
+
+    /// The algorithm of records that predate the choice
+    pub fn legacy() -> Self {
+        HashAlgorithm::Sha256
+    }
+
+    /// Every algorithm, the configured one first
+    pub fn all() -> Vec<Self> {
+        let current = Self::current();
+        let mut algorithms = vec![current];
+
+        for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3, HashAlgorithm::Xxh3_128] {
+            if algorithm != current {
+                algorithms.push(algorithm);
+            }
+        }
+
+        algorithms
+    }
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            HashAlgorithm::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+            HashAlgorithm::Xxh3_128 => xxh3_128(data).to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Serialized SHA-256 hashes are bare hex, as they always were, while
+    /// the others carry a prefix naming the algorithm
+    fn prefix(&self) -> Option<&'static str> {
+        match self {
+            HashAlgorithm::Sha256 => None,
+            HashAlgorithm::Blake3 => Some("blake3"),
+            HashAlgorithm::Xxh3_128 => Some("xxh3_128"),
+        }
+    }
+
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix {
+            "blake3" => Some(HashAlgorithm::Blake3),
+            "xxh3_128" => Some(HashAlgorithm::Xxh3_128),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Hash {
     items: Option<Vec<String>>,
-    value: Option<String>,
+    algorithm: HashAlgorithm,
+    /// Raw digest, only turned into hex at serialization boundaries
+    value: Option<Box<[u8]>>,
 }
 
 impl Hash {
     pub fn new() -> Self {
         Hash {
             items: Some(Vec::new()),
+            algorithm: HashAlgorithm::legacy(),
             value: None,
         }
     }
 
     pub fn from_str(s: &str) -> Self {
+        let algorithm = HashAlgorithm::current();
+
         Hash {
             items: Some(Vec::new()),
-            value: Some(Self::hash(s.as_bytes())),
+            algorithm,
+            value: Some(algorithm.digest(s.as_bytes()).into_boxed_slice()),
         }
     }
 
@@ -28,6 +134,7 @@ impl Hash {
         let string_items = items.into_iter().map(|item| item.to_string()).collect();
         Hash {
             items: Some(string_items),
+            algorithm: HashAlgorithm::legacy(),
             value: None,
         }
     }
@@ -51,9 +158,14 @@ impl Hash {
     }
 
     pub fn finalize(&mut self) -> &mut Self {
+        self.finalize_with(&HashScheme::current(), HashAlgorithm::current())
+    }
+
+    pub fn finalize_with(&mut self, scheme: &HashScheme, algorithm: HashAlgorithm) -> &mut Self {
         if let Some(ref items) = self.items {
-            let concatenated = items.join("").into_bytes();
-            self.value = Some(Self::hash(&concatenated));
+            let encoded = scheme.encode(items);
+            self.algorithm = algorithm;
+            self.value = Some(algorithm.digest(&encoded).into_boxed_slice());
         }
         self
     }
@@ -62,8 +174,17 @@ impl Hash {
         self.value.is_none()
     }
 
+    pub fn get_algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
     pub fn to_string(&self) -> Option<String> {
-        self.value.clone()
+        self.value.as_ref().map(|value| {
+            match self.algorithm.prefix() {
+                Some(prefix) => format!("{}:{}", prefix, to_hex(value)),
+                None => to_hex(value),
+            }
+        })
     }
 
     pub fn clear_items(&mut self) -> &mut Self {
@@ -71,16 +192,38 @@ impl Hash {
         self
     }
 
-    fn hash(data: &[u8]) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        format!("{:x}", hasher.finalize())
+    fn parse(s: &str) -> Option<Self> {
+        let (algorithm, hex) = match s.split_once(':') {
+            Some((prefix, hex)) => (HashAlgorithm::from_prefix(prefix)?, hex),
+            None => (HashAlgorithm::Sha256, s),
+        };
+
+        Some(Hash {
+            items: None,
+            algorithm,
+            value: Some(from_hex(hex)?.into_boxed_slice()),
+        })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
     }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
 }
 
 impl PartialEq for Hash {
     fn eq(&self, other: &Self) -> bool {
-        self.value == other.value
+        self.algorithm == other.algorithm && self.value == other.value
     }
 }
 
@@ -88,7 +231,7 @@ impl Eq for Hash {}
 
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.value {
+        match self.to_string() {
             Some(value) => write!(f, "{}", value),
             None => write!(f, "<uncomputed hash>"),
         }
@@ -108,8 +251,8 @@ impl Serialize for Hash {
     where
         S: Serializer,
     {
-        match &self.value {
-            Some(value) => serializer.serialize_str(value),
+        match self.to_string() {
+            Some(value) => serializer.serialize_str(&value),
             None => Err(serde::ser::Error::custom("Hash value is missing")),
         }
     }
@@ -126,20 +269,36 @@ impl<'de> Deserialize<'de> for Hash {
             type Value = Hash;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("a valid hash string")
+                formatter.write_str("a hex hash, optionally prefixed with its algorithm")
             }
 
             fn visit_str<E>(self, value: &str) -> Result<Hash, E>
             where
                 E: de::Error,
             {
-                Ok(Hash {
-                    items: None,
-                    value: Some(value.to_string()),
-                })
+                Hash::parse(value).ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
             }
         }
 
         deserializer.deserialize_str(HashVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn v2_encodes_distinct_item_lists_apart() {
+        let scheme = HashScheme::V2;
+
+        assert_ne!(scheme.encode(&items(&[])), scheme.encode(&items(&[""])));
+        assert_ne!(scheme.encode(&items(&["ab", "c"])), scheme.encode(&items(&["a", "bc"])));
+        assert_ne!(scheme.encode(&items(&["a|b"])), scheme.encode(&items(&["a", "b"])));
+        assert_eq!(HashScheme::V1.encode(&items(&["ab", "c"])), HashScheme::V1.encode(&items(&["a", "bc"])));
+    }
+}
@@ -1,11 +1,16 @@
 use uuid::Uuid;
-use serde::{Serialize};
+use serde::{Serialize, Serializer};
 use serde::de::{self, Deserialize, Deserializer, Visitor, Error as SerdeError};
 use std::fmt;
 use std::str::FromStr;
 
+use crate::config::{CONFIG, IdMode};
+use crate::macros::*;
 
-#[derive(Clone, Debug, Serialize, Hash)]
+/// Namespace of the UUIDv5 IDs derived by `ID::derive`
+const NAMESPACE: Uuid = Uuid::from_u128(0x3a663e5b_8af9_49c6_9177_efa28f542f53);
+
+#[derive(Clone, Debug, Hash)]
 pub struct ID {
     value: String
 }
@@ -16,6 +21,27 @@ impl ID {
             value: Uuid::new_v4().to_string()
         }
     }
+
+    /// A UUIDv5 of the names, the same on every run
+    pub fn derive(names: &[&str]) -> Self {
+        // Names are length-prefixed so that their boundaries are unambiguous
+        let name: String = names.iter()
+            .map(|name| format!("{}:{}", name.len(), name))
+            .collect();
+
+        ID {
+            value: Uuid::new_v5(&NAMESPACE, name.as_bytes()).to_string()
+        }
+    }
+
+    /// Derived from the names when IDs are configured to be deterministic,
+    /// random otherwise
+    pub fn derive_or_new(names: &[&str]) -> Self {
+        match read_lock!(CONFIG).ids.mode {
+            IdMode::Random => ID::new(),
+            IdMode::Position | IdMode::Content => ID::derive(names),
+        }
+    }
     
     pub fn from_str(value: &str) -> Self {
         ID {
@@ -36,6 +62,15 @@ impl PartialEq for ID {
 
 impl Eq for ID {}
 
+impl Serialize for ID {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.value)
+    }
+}
+
 impl<'de> Deserialize<'de> for ID {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
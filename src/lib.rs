@@ -10,17 +10,23 @@ pub mod data_node;
 pub mod document;
 pub mod document_format;
 pub mod document_node;
+pub mod drift;
 pub mod environment;
+pub mod fingerprint;
 pub mod graph_node;
 pub mod hash;
 pub mod id;
 pub mod lineage;
 pub mod macros;
+pub mod migration;
 pub mod model;
 pub mod normalization;
 pub mod organization;
+pub mod post_processing;
 pub mod profile;
+pub mod profile_index;
 pub mod provider;
+pub mod runtime;
 pub mod transformation;
 pub mod translation;
 pub mod types;
@@ -28,3 +34,6 @@ pub mod prelude;
 pub mod utility;
 pub mod json_node;
 
+#[cfg(test)]
+mod testing;
+
@@ -1,11 +1,49 @@
 use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
 
-use crate::hash::{Hash};
+use crate::prelude::*;
+use crate::config::{CONFIG, LineageConfig, LineageMode};
+use crate::hash::{HashAlgorithm, HashScheme};
 
-#[derive(Clone, Debug, Serialize, Deserialize, Hash)]
+/// What lineage identities are derived with. Documents analysed under a
+/// profile use the profile's settings rather than the configured ones, so
+/// that the lineages stored for it keep matching when the configuration
+/// changes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineageSettings {
+    pub hash_scheme: HashScheme,
+    pub mode: LineageMode,
+    pub max_cycle_length: usize,
+}
+
+impl LineageSettings {
+    /// The configured settings, recorded on newly created profiles
+    pub fn current() -> Self {
+        let lock = read_lock!(CONFIG);
+
+        LineageSettings {
+            hash_scheme: lock.hash.scheme.clone(),
+            mode: lock.lineage.mode.clone(),
+            max_cycle_length: lock.lineage.max_cycle_length,
+        }
+    }
+}
+
+/// Cycle length of records that predate recording it. Their mode is exact,
+/// which ignores it.
+pub fn legacy_max_cycle_length() -> usize {
+    LineageConfig::default().max_cycle_length
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Lineage {
     source_hashes: Vec<Hash>,
     identity_hash: Hash,
+    /// A readable description of each node along the path, such as
+    /// `tr.athing`. Only for people, it takes no part in identity.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    segments: Vec<String>,
 }
 
 impl Lineage {
@@ -13,30 +51,73 @@ impl Lineage {
         Lineage {
             source_hashes: Vec::new(),
             identity_hash: Hash::new(),
+            segments: Vec::new(),
         }
     }
 
-    pub fn from_hashes(source_hashes: Vec<Hash>) -> Self {
-        let identity_hash = derive_identity(source_hashes.clone());
+    pub fn from_hashes(source_hashes: Vec<Hash>, settings: &LineageSettings) -> Self {
+        let identity_hash = derive_identity(source_hashes.clone(), settings);
 
         Lineage {
             source_hashes,
             identity_hash,
+            segments: Vec::new(),
         }
     }
 
-    pub fn with_hash(&self, hash: Hash) -> Self {
+    pub fn with_hash(&self, hash: Hash, segment: String, settings: &LineageSettings) -> Self {
         let mut source_hashes: Vec<Hash> = self.source_hashes.clone();
         source_hashes.push(hash);
 
-        let identity_hash = derive_identity(source_hashes.clone());
+        let mut segments: Vec<String> = self.segments.clone();
+        segments.push(segment);
+
+        let identity_hash = derive_identity(source_hashes.clone(), settings);
 
         Lineage {
             source_hashes,
             identity_hash,
+            segments,
+        }
+    }
+
+    /// The lineage without its last hash
+    pub fn parent(&self, settings: &LineageSettings) -> Self {
+        let mut source_hashes = self.source_hashes.clone();
+        source_hashes.pop();
+
+        let mut segments = self.segments.clone();
+        segments.truncate(source_hashes.len());
+
+        Lineage {
+            segments,
+            ..Lineage::from_hashes(source_hashes, settings)
         }
     }
 
+    pub fn last_hash(&self) -> Option<Hash> {
+        self.source_hashes.last().cloned()
+    }
+
+    pub fn last_segment(&self) -> Option<String> {
+        self.segments.last().cloned()
+    }
+
+    /// The readable path, such as `html > body > table.itemlist > tr.athing`.
+    /// Empty for lineages stored before paths were recorded.
+    pub fn get_path(&self) -> String {
+        self.segments.join(" > ")
+    }
+
+    /// A CSS selector for the elements of this lineage
+    pub fn to_css_selector(&self) -> String {
+        path_to_css_selector(&self.get_path())
+    }
+
+    pub fn depth(&self) -> usize {
+        self.source_hashes.len()
+    }
+
     pub fn to_string(&self) -> String {
         self.identity_hash.to_string().clone().unwrap()
     }
@@ -50,44 +131,172 @@ impl PartialEq for Lineage {
 
 impl Eq for Lineage {}
 
-fn derive_identity(source_hashes: Vec<Hash>) -> Hash {
+impl std::hash::Hash for Lineage {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.identity_hash.hash(state);
+    }
+}
+
+impl fmt::Debug for Lineage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Lineage({} @ {})", self.get_path(), self.identity_hash)
+    }
+}
+
+/// Describes a node by its tag and classes, as in `td.title`, or as `#text`
+/// for a text node. Classes are escaped so that the segment is also a valid
+/// CSS compound selector.
+pub fn describe_segment(fields: &HashMap<String, String>) -> String {
+    match fields.get("tag") {
+        Some(tag) => {
+            let classes: String = fields.get("class")
+                .map(|class| {
+                    class.split_whitespace()
+                        .map(|name| format!(".{}", escape_css_identifier(name)))
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            format!("{}{}", tag, classes)
+        },
+        None => String::from("#text"),
+    }
+}
+
+/// Turns a readable path back into a CSS selector. Text nodes cannot be
+/// selected, so their segments are dropped.
+pub fn path_to_css_selector(path: &str) -> String {
+    path.split(" > ")
+        .map(|segment| segment.trim())
+        .filter(|segment| !segment.is_empty() && *segment != "#text")
+        .collect::<Vec<&str>>()
+        .join(" > ")
+}
+
+fn escape_css_identifier(identifier: &str) -> String {
+    identifier.chars()
+        .enumerate()
+        .map(|(index, c)| {
+            if index == 0 && c.is_ascii_digit() {
+                // A leading digit has to be written as a code point
+                format!("\\{:x} ", c as u32)
+            } else if c.is_ascii_alphanumeric() || c == '-' || c == '_' || !c.is_ascii() {
+                c.to_string()
+            } else {
+                format!("\\{}", c)
+            }
+        })
+        .collect()
+}
+
+fn derive_identity(source_hashes: Vec<Hash>, settings: &LineageSettings) -> Hash {
 
     let mut hashes = source_hashes.clone();
 
     // We must ensure hashes are finalized
     for hash in hashes.iter_mut() {
         if hash.is_unfinalized() {
-            hash.finalize();
+            hash.finalize_with(&settings.hash_scheme, HashAlgorithm::current());
         }
     }
 
-    let unique_hashes = remove_duplicate_sequences(hashes.clone());
+    let hashes = match settings.mode {
+        LineageMode::Exact => hashes,
+        LineageMode::CollapseRepeats => collapse_cycles(hashes, 1),
+        LineageMode::CollapseCycles => collapse_cycles(hashes, settings.max_cycle_length),
+    };
+
+    // A lineage takes the algorithm of the node hashes it is made of
+    let algorithm = hashes.last()
+        .map(Hash::get_algorithm)
+        .unwrap_or_else(HashAlgorithm::current);
 
     let mut identity_hash = Hash::from_items(hashes);
-    identity_hash.finalize();
+    identity_hash.finalize_with(&settings.hash_scheme, algorithm);
 
     identity_hash
 }
 
-fn remove_duplicate_sequences(vec: Vec<Hash>) -> Vec<Hash> {
-    if vec.is_empty() {
-        return vec;
-    }
-
-    let mut result = Vec::new();
-    let mut iter = vec.into_iter().peekable();
+/// Walks the path keeping a stack of hashes. A hash that matches one of the
+/// last `max_cycle_length` entries unwinds the stack back to that entry
+/// instead of being pushed, so with a length of one only consecutive repeats
+/// collapse.
+fn collapse_cycles(hashes: Vec<Hash>, max_cycle_length: usize) -> Vec<Hash> {
+    let mut result: Vec<Hash> = Vec::new();
 
-    while let Some(current) = iter.next() {
-        result.push(current.clone());
+    for hash in hashes.into_iter() {
+        let window_start = result.len().saturating_sub(max_cycle_length);
+        let previous = result[window_start..].iter().rposition(|seen| seen == &hash);
 
-        while let Some(next) = iter.peek() {
-            if next == &current {
-                iter.next();
-            } else {
-                break;
-            }
+        match previous {
+            Some(index) => result.truncate(window_start + index + 1),
+            None => result.push(hash),
         }
     }
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(tag: &str, class: &str) -> HashMap<String, String> {
+        HashMap::from([
+            (String::from("tag"), String::from(tag)),
+            (String::from("class"), String::from(class)),
+        ])
+    }
+
+    #[test]
+    fn to_css_selector_drops_text_and_escapes_classes() {
+        let settings = LineageSettings::current();
+
+        let lineage = [fields("html", ""), fields("td", "title 2col"), fields("a", "story:link")]
+            .iter()
+            .fold(Lineage::new(), |lineage, fields| {
+                lineage.with_hash(Hash::from_str(&fields["tag"]), describe_segment(fields), &settings)
+            })
+            .with_hash(Hash::from_str("text"), describe_segment(&HashMap::new()), &settings);
+
+        assert_eq!(lineage.get_path(), "html > td.title.\\32 col > a.story\\:link > #text");
+        assert_eq!(lineage.to_css_selector(), "html > td.title.\\32 col > a.story\\:link");
+        assert_eq!(Lineage::new().to_css_selector(), "");
+    }
+
+    fn hashes(items: &[&str]) -> Vec<Hash> {
+        items.iter().map(|item| Hash::from_str(item)).collect()
+    }
+
+    #[test]
+    fn collapse_cycles_unwinds_repeats_within_the_window() {
+        assert_eq!(collapse_cycles(hashes(&["a", "b", "b", "b", "c"]), 1), hashes(&["a", "b", "c"]));
+        assert_eq!(collapse_cycles(hashes(&["a", "b", "a", "c"]), 1), hashes(&["a", "b", "a", "c"]));
+        assert_eq!(collapse_cycles(hashes(&["a", "b", "a", "c"]), 2), hashes(&["a", "c"]));
+        assert_eq!(
+            collapse_cycles(hashes(&["body", "comment", "replies", "comment", "replies", "comment"]), 4),
+            hashes(&["body", "comment"])
+        );
+        assert_eq!(collapse_cycles(hashes(&["a", "b", "c", "d", "a"]), 3), hashes(&["a", "b", "c", "d", "a"]));
+        assert_eq!(collapse_cycles(Vec::new(), 4), Vec::<Hash>::new());
+    }
+
+    #[test]
+    fn identity_follows_the_given_mode_rather_than_the_configured_one() {
+        let exact = LineageSettings {
+            hash_scheme: HashScheme::current(),
+            mode: LineageMode::Exact,
+            max_cycle_length: 4,
+        };
+        let collapsed = LineageSettings {
+            mode: LineageMode::CollapseRepeats,
+            ..exact.clone()
+        };
+
+        let nested = hashes(&["div", "ul", "ul"]);
+        let flat = hashes(&["div", "ul"]);
+
+        assert_ne!(Lineage::from_hashes(nested.clone(), &exact), Lineage::from_hashes(flat.clone(), &exact));
+        assert_eq!(Lineage::from_hashes(nested, &collapsed), Lineage::from_hashes(flat, &collapsed));
+    }
+}
@@ -3,7 +3,7 @@ use std::collections::{HashMap, HashSet};
 use lazy_static::lazy_static;
 use atty::Stream;
 use std::sync::Arc;
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches};
 use log::LevelFilter;
 use std::fs::File;
 use std::io::stdout;
@@ -21,17 +21,23 @@ mod data_node;
 mod document;
 mod document_format;
 mod document_node;
+mod drift;
 mod environment;
+mod fingerprint;
 mod graph_node;
 mod hash;
 mod id;
 mod lineage;
 mod macros;
+mod migration;
 mod model;
 mod normalization;
 mod organization;
+mod post_processing;
 mod profile;
+mod profile_index;
 mod provider;
+mod runtime;
 mod transformation;
 mod translation;
 mod types;
@@ -39,9 +45,18 @@ mod prelude;
 mod utility;
 mod json_node;
 
+#[cfg(test)]
+mod testing;
+
 use crate::prelude::*;
 use crate::config::{CONFIG};
-use crate::provider::{YamlFileProvider};
+use crate::provider::{YamlFileProvider, ProviderData};
+use crate::migration::{ProviderSpec, MigrationReport};
+use crate::document::Document;
+use crate::drift::DriftReport;
+use crate::profile::Profile;
+use crate::transformation::{ExampleFailure, Transformation};
+use crate::provider::Provider;
 
 fn load_stdin() -> io::Result<String> {
     log::trace!("In load_stdin");
@@ -73,8 +88,317 @@ fn setup() {
     init_logging();
 }
 
+fn parse_provider_spec(matches: &ArgMatches, name: &str) -> ProviderSpec {
+    let value = matches.value_of(name).expect("Provider argument is required");
+
+    match ProviderSpec::from_str(value) {
+        Ok(spec) => spec,
+        Err(err) => {
+            eprintln!("Invalid provider {}: {:?}", value, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn print_migration_report(report: &MigrationReport) {
+    println!("{}", serde_json::to_string_pretty(report).expect("Could not serialize migration report"));
+
+    if !report.is_clean() {
+        std::process::exit(2);
+    }
+}
+
+async fn run_provider_command(matches: &ArgMatches) {
+    if let Some(matches) = matches.subcommand_matches("export") {
+        let from = parse_provider_spec(matches, "from");
+        let output = matches.value_of("output").expect("Output is required");
+
+        let data = migration::export_from_spec(&from).await.unwrap_or_else(|err| {
+            eprintln!("Failed to export provider: {:?}", err);
+            std::process::exit(1);
+        });
+
+        let serialized = serde_json::to_string_pretty(&data).expect("Could not serialize provider data");
+
+        if let Err(err) = write_text_to_file(output, &serialized) {
+            eprintln!("Failed to write export: {:?}", err);
+            std::process::exit(1);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("import") {
+        let to = parse_provider_spec(matches, "to");
+        let path = matches.value_of("file").expect("File is required");
+
+        let data: ProviderData = get_file_as_text(path)
+            .and_then(|text| serde_json::from_str(&text).map_err(|_| Errors::JsonParseError))
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to read export file: {:?}", err);
+                std::process::exit(1);
+            });
+
+        match migration::import_into_spec(&to, data).await {
+            Ok(report) => print_migration_report(&report),
+            Err(err) => {
+                eprintln!("Failed to import provider: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("migrate") {
+        let from = parse_provider_spec(matches, "from");
+        let to = parse_provider_spec(matches, "to");
+
+        let result = match migration::export_from_spec(&from).await {
+            Ok(data) => migration::import_into_spec(&to, data).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(report) => print_migration_report(&report),
+            Err(err) => {
+                eprintln!("Failed to migrate provider: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("index") {
+        let provider = parse_provider_spec(matches, "provider");
+
+        if let Err(err) = migration::build_profile_index_for_spec(&provider).await {
+            eprintln!("Failed to build profile index: {:?}", err);
+            std::process::exit(1);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("reweight") {
+        let provider = parse_provider_spec(matches, "provider");
+
+        if let Err(err) = migration::reweight_profiles_for_spec(&provider).await {
+            eprintln!("Failed to reweight profile features: {:?}", err);
+            std::process::exit(1);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("upgrade-hashes") {
+        let provider = parse_provider_spec(matches, "provider");
+
+        match migration::upgrade_hash_scheme_for_spec(&provider).await {
+            Ok(report) => println!("{}", serde_json::to_string_pretty(&report).expect("Could not serialize hash scheme report")),
+            Err(err) => {
+                eprintln!("Failed to upgrade hash scheme: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        eprintln!("Expected one of: export, import, migrate, index, reweight, upgrade-hashes");
+        std::process::exit(1);
+    }
+
+    std::process::exit(0);
+}
+
+fn load_value_transformations(path: &str) -> Vec<Transformation> {
+    let transformations: Vec<Transformation> = get_file_as_text(path)
+        .and_then(|text| serde_json::from_str(&text).or_else(|_| serde_yaml::from_str(&text)).map_err(|err| {
+            log::error!("Failed to parse value transformations: {}", err);
+            Errors::YamlParseError
+        }))
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to load value transformations from {}: {:?}", path, err);
+            std::process::exit(1);
+        });
+
+    for transformation in transformations.iter() {
+        if let Err(err) = transformation.validate() {
+            eprintln!("Invalid value transformation: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+
+    transformations
+}
+
+async fn load_document(matches: &ArgMatches) -> Document {
+    let (text, origin) = if let Some(path) = matches.value_of("file") {
+        (get_file_as_text(path), None)
+    } else if let Some(url) = matches.value_of("url") {
+        (fetch_url_as_text(url).await, Some(url.to_string()))
+    } else {
+        eprintln!("Expected a file or URL");
+        std::process::exit(1);
+    };
+
+    let options = Some(Options {
+        origin,
+        ..Options::default()
+    });
+
+    match text.and_then(|text| Document::from_string(text, &options)) {
+        Ok(document) => document,
+        Err(err) => {
+            eprintln!("Failed to load document: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run_profile_command(matches: &ArgMatches) {
+    let provider = Arc::new(YamlFileProvider::new(String::from("provider.yaml")));
+
+    if let Some(matches) = matches.subcommand_matches("drift") {
+        let document = load_document(matches).await;
+        let profile_id = matches.value_of("profile").map(ID::from_str);
+
+        let report = drift::detect_drift(
+            provider,
+            &document,
+            profile_id.as_ref(),
+            &None,
+        ).await.unwrap_or_else(|err| {
+            eprintln!("Failed to detect drift: {:?}", err);
+            std::process::exit(1);
+        });
+
+        let report = if let Some(path) = matches.value_of("history") {
+            let mut history: Vec<DriftReport> = get_file_as_text(path)
+                .ok()
+                .and_then(|text| serde_json::from_str(&text).ok())
+                .unwrap_or_default();
+
+            let report = report.with_trend(&history);
+            history.push(report.clone());
+
+            let serialized = serde_json::to_string_pretty(&history).expect("Could not serialize drift history");
+
+            if let Err(err) = write_text_to_file(path, &serialized) {
+                eprintln!("Failed to write drift history: {:?}", err);
+                std::process::exit(1);
+            }
+
+            report
+        } else {
+            report
+        };
+
+        println!("{}", serde_json::to_string_pretty(&report).expect("Could not serialize drift report"));
+
+        if report.drifted {
+            std::process::exit(2);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("learn") {
+        let origin = matches.value_of("origin").map(String::from);
+        let options = Some(Options {
+            origin: origin.clone(),
+            ..Options::default()
+        });
+
+        let samples: Vec<Document> = matches.values_of("file")
+            .expect("Sample files are required")
+            .map(|path| get_file_as_text(path).and_then(|text| Document::from_string(text, &options)))
+            .collect::<Result<Vec<Document>, Errors>>()
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to load sample documents: {:?}", err);
+                std::process::exit(1);
+            });
+
+        let min_support = match matches.value_of("min-support") {
+            Some(value) => value.parse::<usize>().unwrap_or_else(|_| {
+                eprintln!("Invalid minimum support: {}", value);
+                std::process::exit(1);
+            }),
+            None => samples.len() / 2 + 1,
+        };
+
+        let profiles = provider.list_profiles().await.unwrap_or_else(|err| {
+            eprintln!("Failed to list profiles: {:?}", err);
+            std::process::exit(1);
+        });
+
+        let mut profile = Profile::learn(&samples, min_support, &origin, &profiles).unwrap_or_else(|err| {
+            eprintln!("Failed to learn profile: {:?}", err);
+            std::process::exit(1);
+        });
+
+        if let Some(description) = matches.value_of("description") {
+            profile.description = description.to_string();
+        }
+
+        if matches.is_present("save") {
+            if let Err(err) = provider.save_profile(&profile).await {
+                eprintln!("Failed to save profile: {:?}", err);
+                std::process::exit(1);
+            }
+
+            if let Err(err) = provider.reweight_profiles().await {
+                eprintln!("Failed to reweight profile features: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+
+        println!("{}", serde_json::to_string_pretty(&profile).expect("Could not serialize profile"));
+    } else if let Some(matches) = matches.subcommand_matches("nativize") {
+        let profiles = provider.list_profiles().await.unwrap_or_else(|err| {
+            eprintln!("Failed to list profiles: {:?}", err);
+            std::process::exit(1);
+        });
+
+        for mut profile in profiles.into_iter() {
+            if !profile.nativize() {
+                println!("{}: kept as is", profile.id.to_string());
+                continue;
+            }
+
+            println!("{}: converted to native transformations", profile.id.to_string());
+
+            if !matches.is_present("dry-run") {
+                if let Err(err) = provider.save_profile(&profile).await {
+                    eprintln!("Failed to save profile: {:?}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("test") {
+        let profile_id = matches.value_of("profile").map(ID::from_str);
+
+        let profiles = provider.list_profiles().await.unwrap_or_else(|err| {
+            eprintln!("Failed to list profiles: {:?}", err);
+            std::process::exit(1);
+        });
+
+        let basis_nodes = provider.list_basis_nodes().await.unwrap_or_else(|err| {
+            eprintln!("Failed to list basis nodes: {:?}", err);
+            std::process::exit(1);
+        });
+
+        let mut failures: Vec<ExampleFailure> = Vec::new();
+
+        for profile in profiles.iter().filter(|profile| profile_id.as_ref().map_or(true, |id| &profile.id == id)) {
+            let profile_failures = profile.test();
+            println!("profile {}: {} failing examples", profile.id.to_string(), profile_failures.len());
+            failures.extend(profile_failures);
+        }
+
+        for basis_node in basis_nodes.iter().filter(|basis_node| {
+            profile_id.as_ref().map_or(true, |id| basis_node.profile_id.as_ref() == Some(id))
+        }) {
+            let basis_node_failures: Vec<ExampleFailure> = basis_node.transformations.iter()
+                .flat_map(|transformation| transformation.test())
+                .collect();
+            println!("basis node {}: {} failing examples", basis_node.id.to_string(), basis_node_failures.len());
+            failures.extend(basis_node_failures);
+        }
+
+        if !failures.is_empty() {
+            println!("{}", serde_json::to_string_pretty(&failures).expect("Could not serialize example failures"));
+            std::process::exit(1);
+        }
+    } else {
+        eprintln!("Expected one of: drift, learn, nativize, test");
+        std::process::exit(1);
+    }
+
+    std::process::exit(0);
+}
+
 #[tokio::main]
 async fn main() {
+    if runtime::serve_quickjs_worker_if_requested() {
+        return;
+    }
+
     setup();
 
     let matches = App::new("parversion")
@@ -88,8 +412,138 @@ async fn main() {
             .long("url")
             .value_name("URL")
             .help("Provide url as document for processing"))
+        .arg(Arg::with_name("value-transformations")
+            .long("value-transformations")
+            .value_name("FILE")
+            .help("YAML or JSON list of transformations applied to every extracted value, in order"))
+        .subcommand(App::new("provider")
+            .about("Move data between providers, e.g. yaml:provider.yaml or sqlite:provider.db")
+            .subcommand(App::new("export")
+                .about("Export everything from a provider to a JSON file")
+                .arg(Arg::with_name("from")
+                    .long("from")
+                    .value_name("PROVIDER")
+                    .required(true)
+                    .help("Provider to export from"))
+                .arg(Arg::with_name("output")
+                    .short('o')
+                    .long("output")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("File to write the export to")))
+            .subcommand(App::new("import")
+                .about("Import a JSON export into a provider")
+                .arg(Arg::with_name("to")
+                    .long("to")
+                    .value_name("PROVIDER")
+                    .required(true)
+                    .help("Provider to import into"))
+                .arg(Arg::with_name("file")
+                    .short('f')
+                    .long("file")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Export file to import")))
+            .subcommand(App::new("migrate")
+                .about("Copy everything from one provider to another")
+                .arg(Arg::with_name("from")
+                    .long("from")
+                    .value_name("PROVIDER")
+                    .required(true)
+                    .help("Provider to export from"))
+                .arg(Arg::with_name("to")
+                    .long("to")
+                    .value_name("PROVIDER")
+                    .required(true)
+                    .help("Provider to import into")))
+            .subcommand(App::new("index")
+                .about("Build and persist the similar-profile lookup index")
+                .arg(Arg::with_name("provider")
+                    .long("provider")
+                    .value_name("PROVIDER")
+                    .required(true)
+                    .help("Provider to index")))
+            .subcommand(App::new("reweight")
+                .about("Recompute TF-IDF feature weights across all profiles")
+                .arg(Arg::with_name("provider")
+                    .long("provider")
+                    .value_name("PROVIDER")
+                    .required(true)
+                    .help("Provider to reweight")))
+            .subcommand(App::new("upgrade-hashes")
+                .about("Record profiles under the current hash scheme and list stale basis nodes")
+                .arg(Arg::with_name("provider")
+                    .long("provider")
+                    .value_name("PROVIDER")
+                    .required(true)
+                    .help("Provider to upgrade"))))
+        .subcommand(App::new("profile")
+            .about("Inspect profiles stored in provider.yaml")
+            .subcommand(App::new("drift")
+                .about("Report how a document has drifted from its closest profile")
+                .arg(Arg::with_name("file")
+                    .short('f')
+                    .long("file")
+                    .value_name("FILE")
+                    .help("Document to compare"))
+                .arg(Arg::with_name("url")
+                    .short('u')
+                    .long("url")
+                    .value_name("URL")
+                    .help("URL of the document to compare"))
+                .arg(Arg::with_name("profile")
+                    .long("profile")
+                    .value_name("ID")
+                    .help("Compare against this profile instead of the closest one"))
+                .arg(Arg::with_name("history")
+                    .long("history")
+                    .value_name("FILE")
+                    .help("JSON file of earlier reports, used for the similarity trend and appended to")))
+            .subcommand(App::new("learn")
+                .about("Learn a profile from several sample documents of the same site")
+                .arg(Arg::with_name("file")
+                    .short('f')
+                    .long("file")
+                    .value_name("FILE")
+                    .multiple_occurrences(true)
+                    .required(true)
+                    .help("Sample document, may be given several times"))
+                .arg(Arg::with_name("min-support")
+                    .long("min-support")
+                    .value_name("COUNT")
+                    .help("Keep features present in at least this many samples, a majority by default"))
+                .arg(Arg::with_name("origin")
+                    .long("origin")
+                    .value_name("URL")
+                    .help("URL of the site the samples come from, used for the profile's URL patterns"))
+                .arg(Arg::with_name("description")
+                    .long("description")
+                    .value_name("TEXT")
+                    .help("Description of the learned profile"))
+                .arg(Arg::with_name("save")
+                    .long("save")
+                    .help("Save the learned profile to provider.yaml")))
+            .subcommand(App::new("nativize")
+                .about("Convert script transformations to native ones where possible")
+                .arg(Arg::with_name("dry-run")
+                    .long("dry-run")
+                    .help("Report what would be converted without saving")))
+            .subcommand(App::new("test")
+                .about("Run the examples embedded in transformations and report failures")
+                .arg(Arg::with_name("profile")
+                    .long("profile")
+                    .value_name("ID")
+                    .help("Only test this profile and the basis nodes inferred from it"))))
         .get_matches();
 
+    if let Some(provider_matches) = matches.subcommand_matches("provider") {
+        run_provider_command(provider_matches).await;
+    }
+
+    if let Some(profile_matches) = matches.subcommand_matches("profile") {
+        run_profile_command(profile_matches).await;
+    }
+
     let document_format = document_format::DocumentFormat::default();
 
     let provider = Arc::new(YamlFileProvider::new(String::from("provider.yaml")));
@@ -97,6 +551,8 @@ async fn main() {
     log::info!("Using yaml file provider");
 
     let options = Options {
+        origin: matches.value_of("url").map(String::from),
+        value_transformations: matches.value_of("value-transformations").map(load_value_transformations),
         ..Options::default()
     };
 
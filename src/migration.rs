@@ -0,0 +1,558 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::prelude::*;
+use crate::hash::HashScheme;
+use crate::lineage::LineageSettings;
+use crate::profile::Profile;
+use crate::basis_node::BasisNode;
+use crate::basis_network::BasisNetwork;
+use crate::provider::{
+    Provider,
+    ProviderData,
+    YamlFileProvider,
+    JsonFileProvider,
+    SledProvider,
+    SqliteProvider,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProviderKind {
+    Yaml,
+    Json,
+    Sled,
+    Sqlite,
+}
+
+/// A provider location such as `yaml:provider.yaml` or `sqlite:provider.db`.
+/// Without a prefix the kind is inferred from the file extension.
+#[derive(Clone, Debug)]
+pub struct ProviderSpec {
+    pub kind: ProviderKind,
+    pub path: String,
+}
+
+impl ProviderSpec {
+    pub fn from_str(spec: &str) -> Result<Self, Errors> {
+        if let Some((kind, path)) = spec.split_once(':') {
+            let kind = match kind {
+                "yaml" => ProviderKind::Yaml,
+                "json" => ProviderKind::Json,
+                "sled" => ProviderKind::Sled,
+                "sqlite" => ProviderKind::Sqlite,
+                _ => return Err(Errors::ProviderSpecError(
+                    format!("Unknown provider kind: {}", kind)
+                )),
+            };
+
+            return Ok(ProviderSpec { kind, path: path.to_string() });
+        }
+
+        let kind = match std::path::Path::new(spec).extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => ProviderKind::Yaml,
+            Some("json") => ProviderKind::Json,
+            Some("db") | Some("sqlite") | Some("sqlite3") => ProviderKind::Sqlite,
+            Some("sled") => ProviderKind::Sled,
+            _ => return Err(Errors::ProviderSpecError(
+                format!("Could not infer provider kind from: {}", spec)
+            )),
+        };
+
+        Ok(ProviderSpec { kind, path: spec.to_string() })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MigrationIssue {
+    pub entity: String,
+    pub id: ID,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MigrationReport {
+    pub imported_profiles: usize,
+    pub imported_basis_nodes: usize,
+    pub imported_basis_networks: usize,
+    /// Entries skipped because an identical copy already exists
+    pub duplicates: Vec<MigrationIssue>,
+    /// Entries skipped because an entry with the same ID but different content exists
+    pub conflicts: Vec<MigrationIssue>,
+    /// Entries skipped because they failed validation
+    pub rejected: Vec<MigrationIssue>,
+}
+
+impl MigrationReport {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty() && self.rejected.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct HashSchemeReport {
+    /// Profiles now recorded under the current scheme
+    pub upgraded_profiles: Vec<ID>,
+    /// Profiles left on their scheme because basis nodes of theirs were
+    /// hashed with it
+    pub kept_profiles: Vec<ID>,
+    /// Basis nodes whose lineage was derived otherwise than their profile
+    /// now derives lineages, or than configured for nodes of no profile.
+    /// Their hashes cannot be recomputed without the items they were built
+    /// from, so they no longer match and their lineages are inferred again.
+    pub stale_basis_nodes: Vec<ID>,
+}
+
+pub async fn export_provider<P: Provider>(
+    provider: Arc<P>,
+) -> Result<ProviderData, Errors> {
+    log::trace!("In export_provider");
+
+    Ok(ProviderData {
+        profiles: provider.list_profiles().await?,
+        basis_nodes: provider.list_basis_nodes().await?,
+        basis_networks: provider.list_basis_networks().await?,
+        profiles_generation: 0,
+        profile_index: None,
+    })
+}
+
+pub async fn import_provider<P: Provider>(
+    provider: Arc<P>,
+    data: ProviderData,
+) -> Result<MigrationReport, Errors> {
+    log::trace!("In import_provider");
+
+    let mut report = MigrationReport::default();
+    let mut batch = ProviderData::default();
+
+    let mut existing_profiles: HashMap<ID, Profile> = provider.list_profiles().await?
+        .into_iter()
+        .map(|profile| (profile.id.clone(), profile))
+        .collect();
+
+    for profile in data.profiles.into_iter() {
+        if let Err(err) = profile.validate() {
+            record_rejected(&mut report, "profile", &profile.id, err);
+            continue;
+        }
+
+        if let Some(existing) = existing_profiles.get(&profile.id) {
+            record_existing(&mut report, "profile", &profile.id, same_profile(existing, &profile));
+            continue;
+        }
+
+        batch.profiles.push(profile.clone());
+        existing_profiles.insert(profile.id.clone(), profile);
+    }
+
+    let mut existing_basis_nodes: HashMap<ID, BasisNode> = provider.list_basis_nodes().await?
+        .into_iter()
+        .map(|basis_node| (basis_node.id.clone(), basis_node))
+        .collect();
+
+    for basis_node in data.basis_nodes.into_iter() {
+        if let Err(err) = basis_node.validate() {
+            record_rejected(&mut report, "basis_node", &basis_node.id, err);
+            continue;
+        }
+
+        if let Some(existing) = existing_basis_nodes.get(&basis_node.id) {
+            record_existing(&mut report, "basis_node", &basis_node.id, same_value(existing, &basis_node));
+            continue;
+        }
+
+        batch.basis_nodes.push(basis_node.clone());
+        existing_basis_nodes.insert(basis_node.id.clone(), basis_node);
+    }
+
+    let mut existing_basis_networks: HashMap<ID, BasisNetwork> = provider.list_basis_networks().await?
+        .into_iter()
+        .map(|basis_network| (basis_network.id.clone(), basis_network))
+        .collect();
+
+    for basis_network in data.basis_networks.into_iter() {
+        if let Err(err) = basis_network.validate() {
+            record_rejected(&mut report, "basis_network", &basis_network.id, err);
+            continue;
+        }
+
+        if let Some(existing) = existing_basis_networks.get(&basis_network.id) {
+            record_existing(&mut report, "basis_network", &basis_network.id, same_value(existing, &basis_network));
+            continue;
+        }
+
+        batch.basis_networks.push(basis_network.clone());
+        existing_basis_networks.insert(basis_network.id.clone(), basis_network);
+    }
+
+    provider.save_batch(&batch).await?;
+
+    report.imported_profiles = batch.profiles.len();
+    report.imported_basis_nodes = batch.basis_nodes.len();
+    report.imported_basis_networks = batch.basis_networks.len();
+
+    if report.imported_profiles > 0 {
+        provider.reweight_profiles().await?;
+    }
+
+    log::info!(
+        "Imported {} profiles, {} basis nodes, {} basis networks ({} duplicates, {} conflicts, {} rejected)",
+        report.imported_profiles,
+        report.imported_basis_nodes,
+        report.imported_basis_networks,
+        report.duplicates.len(),
+        report.conflicts.len(),
+        report.rejected.len(),
+    );
+
+    Ok(report)
+}
+
+pub async fn migrate_provider<S: Provider, T: Provider>(
+    source: Arc<S>,
+    target: Arc<T>,
+) -> Result<MigrationReport, Errors> {
+    log::trace!("In migrate_provider");
+
+    let data = export_provider(source).await?;
+
+    import_provider(target, data).await
+}
+
+/// Brings profiles from older hash schemes up to the current one. Features
+/// are always hashed with the legacy encoding, so they keep matching, while
+/// node and lineage hashes are computed with the profile's scheme. A profile
+/// whose basis nodes were hashed with its old scheme therefore keeps it, so
+/// that their lineages keep matching until they are inferred again.
+pub async fn upgrade_hash_scheme<P: Provider>(
+    provider: Arc<P>,
+) -> Result<HashSchemeReport, Errors> {
+    log::trace!("In upgrade_hash_scheme");
+
+    let scheme = HashScheme::current();
+    let mut report = HashSchemeReport::default();
+
+    let basis_nodes = provider.list_basis_nodes().await?;
+    let mut batch = ProviderData::default();
+    let mut profile_settings: HashMap<ID, LineageSettings> = HashMap::new();
+
+    for mut profile in provider.list_profiles().await? {
+        if profile.hash_scheme == scheme {
+            profile_settings.insert(profile.id.clone(), profile.get_lineage_settings());
+            continue;
+        }
+
+        let lineage_settings = profile.get_lineage_settings();
+        let is_in_use = basis_nodes.iter().any(|basis_node| {
+            basis_node.profile_id.as_ref() == Some(&profile.id) && basis_node.get_lineage_settings() == lineage_settings
+        });
+
+        if is_in_use {
+            log::info!("Keeping profile {} on {:?} for its basis nodes", profile.id.to_string(), profile.hash_scheme);
+            profile_settings.insert(profile.id.clone(), lineage_settings);
+            report.kept_profiles.push(profile.id);
+            continue;
+        }
+
+        log::info!("Upgrading profile {} from {:?} to {:?}", profile.id.to_string(), profile.hash_scheme, scheme);
+
+        profile.hash_scheme = scheme.clone();
+        profile_settings.insert(profile.id.clone(), profile.get_lineage_settings());
+        report.upgraded_profiles.push(profile.id.clone());
+        batch.profiles.push(profile);
+    }
+
+    provider.save_batch(&batch).await?;
+
+    let current_settings = LineageSettings::current();
+
+    for basis_node in basis_nodes.into_iter() {
+        let expected = basis_node.profile_id.as_ref()
+            .and_then(|profile_id| profile_settings.get(profile_id))
+            .unwrap_or(&current_settings);
+
+        if basis_node.get_lineage_settings() != *expected {
+            log::warn!("Basis node {} was derived with {:?}", basis_node.id.to_string(), basis_node.get_lineage_settings());
+            report.stale_basis_nodes.push(basis_node.id);
+        }
+    }
+
+    Ok(report)
+}
+
+pub async fn export_from_spec(spec: &ProviderSpec) -> Result<ProviderData, Errors> {
+    log::trace!("In export_from_spec");
+    log::debug!("spec: {:?}", spec);
+
+    match spec.kind {
+        ProviderKind::Yaml => export_provider(Arc::new(YamlFileProvider::new(spec.path.clone()))).await,
+        ProviderKind::Json => export_provider(Arc::new(JsonFileProvider::new(spec.path.clone()))).await,
+        ProviderKind::Sled => export_provider(Arc::new(SledProvider::new(spec.path.clone())?)).await,
+        ProviderKind::Sqlite => export_provider(Arc::new(SqliteProvider::new(spec.path.clone())?)).await,
+    }
+}
+
+pub async fn import_into_spec(
+    spec: &ProviderSpec,
+    data: ProviderData,
+) -> Result<MigrationReport, Errors> {
+    log::trace!("In import_into_spec");
+    log::debug!("spec: {:?}", spec);
+
+    match spec.kind {
+        ProviderKind::Yaml => import_provider(Arc::new(YamlFileProvider::new(spec.path.clone())), data).await,
+        ProviderKind::Json => import_provider(Arc::new(JsonFileProvider::new(spec.path.clone())), data).await,
+        ProviderKind::Sled => import_provider(Arc::new(SledProvider::new(spec.path.clone())?), data).await,
+        ProviderKind::Sqlite => import_provider(Arc::new(SqliteProvider::new(spec.path.clone())?), data).await,
+    }
+}
+
+pub async fn build_profile_index_for_spec(spec: &ProviderSpec) -> Result<(), Errors> {
+    log::trace!("In build_profile_index_for_spec");
+    log::debug!("spec: {:?}", spec);
+
+    match spec.kind {
+        ProviderKind::Yaml => YamlFileProvider::new(spec.path.clone()).build_profile_index().await,
+        ProviderKind::Json => JsonFileProvider::new(spec.path.clone()).build_profile_index().await,
+        ProviderKind::Sled => SledProvider::new(spec.path.clone())?.build_profile_index().await,
+        ProviderKind::Sqlite => SqliteProvider::new(spec.path.clone())?.build_profile_index().await,
+    }
+}
+
+pub async fn reweight_profiles_for_spec(spec: &ProviderSpec) -> Result<(), Errors> {
+    log::trace!("In reweight_profiles_for_spec");
+    log::debug!("spec: {:?}", spec);
+
+    match spec.kind {
+        ProviderKind::Yaml => YamlFileProvider::new(spec.path.clone()).reweight_profiles().await,
+        ProviderKind::Json => JsonFileProvider::new(spec.path.clone()).reweight_profiles().await,
+        ProviderKind::Sled => SledProvider::new(spec.path.clone())?.reweight_profiles().await,
+        ProviderKind::Sqlite => SqliteProvider::new(spec.path.clone())?.reweight_profiles().await,
+    }
+}
+
+pub async fn upgrade_hash_scheme_for_spec(spec: &ProviderSpec) -> Result<HashSchemeReport, Errors> {
+    log::trace!("In upgrade_hash_scheme_for_spec");
+    log::debug!("spec: {:?}", spec);
+
+    match spec.kind {
+        ProviderKind::Yaml => upgrade_hash_scheme(Arc::new(YamlFileProvider::new(spec.path.clone()))).await,
+        ProviderKind::Json => upgrade_hash_scheme(Arc::new(JsonFileProvider::new(spec.path.clone()))).await,
+        ProviderKind::Sled => upgrade_hash_scheme(Arc::new(SledProvider::new(spec.path.clone())?)).await,
+        ProviderKind::Sqlite => upgrade_hash_scheme(Arc::new(SqliteProvider::new(spec.path.clone())?)).await,
+    }
+}
+
+fn record_rejected(report: &mut MigrationReport, entity: &str, id: &ID, err: Errors) {
+    log::warn!("Rejecting {} {}: {:?}", entity, id.to_string(), err);
+    report.rejected.push(MigrationIssue {
+        entity: entity.to_string(),
+        id: id.clone(),
+        reason: format!("{:?}", err),
+    });
+}
+
+fn record_existing(report: &mut MigrationReport, entity: &str, id: &ID, is_same: bool) {
+    let issue = MigrationIssue {
+        entity: entity.to_string(),
+        id: id.clone(),
+        reason: String::new(),
+    };
+
+    if is_same {
+        log::debug!("Skipping duplicate {} {}", entity, id.to_string());
+        report.duplicates.push(MigrationIssue {
+            reason: "identical entry already present".into(),
+            ..issue
+        });
+    } else {
+        log::warn!("Conflicting {} {}", entity, id.to_string());
+        report.conflicts.push(MigrationIssue {
+            reason: "an entry with this ID but different content already exists".into(),
+            ..issue
+        });
+    }
+}
+
+fn same_profile(a: &Profile, b: &Profile) -> bool {
+    // Features are a set, so they are compared directly rather than through
+    // their (unordered) serialized form
+    if a.features != b.features {
+        return false;
+    }
+
+    let mut a = a.clone();
+    let mut b = b.clone();
+    a.features.clear();
+    b.features.clear();
+
+    same_value(&a, &b)
+}
+
+fn same_value<T: Serialize>(a: &T, b: &T) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use crate::basis_network::{LineageSubgraph, NetworkRelationship};
+    use crate::document::hash_feature;
+    use crate::hash::HashAlgorithm;
+    use crate::testing::{basis_node, lineage, temp_path};
+
+    fn get_data() -> ProviderData {
+        let features: HashSet<Hash> = (0..10)
+            .map(|i| hash_feature(&format!("html/body/{}", i), HashAlgorithm::current()))
+            .collect();
+
+        let lineage = lineage(&["html"]);
+
+        ProviderData {
+            profiles: vec![Profile::bootstrap(features, &None)],
+            basis_nodes: vec![BasisNode {
+                description: String::from("Document root"),
+                ..basis_node(lineage.clone())
+            }],
+            basis_networks: vec![BasisNetwork {
+                id: ID::new(),
+                description: String::from("Root association"),
+                relationship: NetworkRelationship::Association(vec![LineageSubgraph {
+                    lineage,
+                    subgraph: Hash::from_str("subgraph"),
+                }]),
+            }],
+            ..ProviderData::default()
+        }
+    }
+
+    fn assert_same(a: &ProviderData, b: &ProviderData) {
+        assert_eq!(a.profiles.len(), b.profiles.len());
+        assert!(a.profiles.iter().zip(b.profiles.iter()).all(|(a, b)| same_profile(a, b)));
+        assert!(same_value(&a.basis_nodes, &b.basis_nodes));
+        assert!(same_value(&a.basis_networks, &b.basis_networks));
+    }
+
+    #[tokio::test]
+    async fn export_then_import_round_trips_between_providers() {
+        let json_path = temp_path("json");
+        let sqlite_path = temp_path("sqlite");
+        let source = Arc::new(JsonFileProvider::new(json_path.clone()));
+        let target = Arc::new(SqliteProvider::new(sqlite_path.clone()).unwrap());
+
+        let data = get_data();
+
+        let report = import_provider(Arc::clone(&source), data.clone()).await.unwrap();
+        assert_eq!(
+            (report.imported_profiles, report.imported_basis_nodes, report.imported_basis_networks),
+            (1, 1, 1)
+        );
+        assert!(report.is_clean());
+
+        let report = migrate_provider(Arc::clone(&source), Arc::clone(&target)).await.unwrap();
+        assert_eq!(
+            (report.imported_profiles, report.imported_basis_nodes, report.imported_basis_networks),
+            (1, 1, 1)
+        );
+
+        let exported = export_provider(Arc::clone(&target)).await.unwrap();
+        assert_same(&exported, &export_provider(Arc::clone(&source)).await.unwrap());
+
+        // Importing reweights, which is the only change to the original
+        let mut original = data;
+        Profile::compute_feature_weights(&mut original.profiles);
+        assert_same(&exported, &original);
+
+        std::fs::remove_file(&json_path).unwrap();
+        std::fs::remove_file(&sqlite_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn import_reports_duplicates_conflicts_and_rejections() {
+        let path = temp_path("json");
+        let provider = Arc::new(JsonFileProvider::new(path.clone()));
+
+        let data = get_data();
+        import_provider(Arc::clone(&provider), data.clone()).await.unwrap();
+
+        let report = import_provider(Arc::clone(&provider), export_provider(Arc::clone(&provider)).await.unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(report.duplicates.len(), 3);
+        assert!(report.is_clean());
+
+        let mut changed = data.clone();
+        changed.profiles[0].description = String::from("Changed");
+        changed.basis_nodes.clear();
+        changed.basis_networks.clear();
+
+        let mut invalid_node = data.basis_nodes[0].clone();
+        invalid_node.id = ID::new();
+        invalid_node.hash = Hash::new();
+        changed.basis_nodes.push(invalid_node.clone());
+
+        let mut invalid_network = data.basis_networks[0].clone();
+        invalid_network.id = ID::new();
+        invalid_network.relationship = NetworkRelationship::Association(Vec::new());
+        changed.basis_networks.push(invalid_network.clone());
+
+        let report = import_provider(Arc::clone(&provider), changed).await.unwrap();
+
+        let conflicts: Vec<&str> = report.conflicts.iter().map(|issue| issue.entity.as_str()).collect();
+        let rejected: Vec<(&str, &ID)> = report.rejected.iter()
+            .map(|issue| (issue.entity.as_str(), &issue.id))
+            .collect();
+
+        assert_eq!(conflicts, vec!["profile"]);
+        assert_eq!(rejected, vec![("basis_node", &invalid_node.id), ("basis_network", &invalid_network.id)]);
+        assert_eq!(report.imported_basis_nodes + report.imported_basis_networks, 0);
+
+        assert_eq!(provider.list_profiles().await.unwrap()[0].description, data.profiles[0].description);
+        assert_eq!(provider.list_basis_nodes().await.unwrap().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upgrade_keeps_profiles_whose_basis_nodes_use_their_scheme() {
+        let path = temp_path("json");
+        let provider = Arc::new(JsonFileProvider::new(path.clone()));
+
+        let mut data = get_data();
+        let mut kept = data.profiles[0].clone();
+        kept.hash_scheme = HashScheme::V1;
+        let mut upgraded = kept.clone();
+        upgraded.id = ID::new();
+        upgraded.features.insert(hash_feature(&String::from("html/head"), HashAlgorithm::current()));
+
+        let mut orphaned = data.basis_nodes[0].clone();
+        orphaned.hash_scheme = HashScheme::V1;
+        let mut owned = orphaned.clone();
+        owned.id = ID::new();
+        owned.profile_id = Some(kept.id.clone());
+
+        data.profiles = vec![kept.clone(), upgraded.clone()];
+        data.basis_nodes = vec![orphaned.clone(), owned];
+        data.basis_networks.clear();
+        provider.save_batch(&data).await.unwrap();
+
+        let report = upgrade_hash_scheme(Arc::clone(&provider)).await.unwrap();
+
+        assert_eq!(report.kept_profiles, vec![kept.id.clone()]);
+        assert_eq!(report.upgraded_profiles, vec![upgraded.id.clone()]);
+        assert_eq!(report.stale_basis_nodes, vec![orphaned.id]);
+
+        for profile in provider.list_profiles().await.unwrap() {
+            let expected = if profile.id == kept.id { HashScheme::V1 } else { HashScheme::current() };
+            assert_eq!(profile.hash_scheme, expected);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -13,7 +13,7 @@ pub async fn organize<P: Provider>(
 ) -> Result<Analysis, Errors> {
     log::trace!("In organize");
 
-    let input = AnalysisInput::from_document(Arc::clone(&provider), document).await?;
+    let input = AnalysisInput::from_document(Arc::clone(&provider), document, options).await?;
     let analysis = Analysis::new(Arc::clone(&provider), input).await?;
 
     Ok(analysis)
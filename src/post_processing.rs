@@ -0,0 +1,99 @@
+use serde::{Serialize, Deserialize};
+
+use crate::prelude::*;
+use crate::json_node::JsonNode;
+use crate::transformation::{JsonValueTransform, Transformation};
+
+/// A value that a step of the pipeline could not transform
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ValueTransformationError {
+    pub transformation_id: ID,
+    pub json_node_id: ID,
+    pub lineage: String,
+    pub key: String,
+    pub value: String,
+    pub message: String,
+}
+
+/// Runs `Options.value_transformations` over every extracted value, in
+/// order. A value that fails a step keeps its value from before that step,
+/// skips the remaining steps and is reported, while other values carry on.
+/// Only an invalid pipeline fails as a whole.
+pub fn apply_value_transformations(
+    json_nodes: &mut [JsonNode],
+    options: &Option<Options>,
+) -> Result<Vec<ValueTransformationError>, Errors> {
+    log::trace!("In apply_value_transformations");
+
+    let transformations = get_value_transformations(options)?;
+
+    if transformations.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut errors: Vec<ValueTransformationError> = Vec::new();
+
+    for json_node in json_nodes.iter_mut() {
+        let lineage = json_node.lineage.to_string();
+        let mut fields = Vec::new();
+
+        for mut field in json_node.json.drain(..) {
+            let mut is_removed = false;
+
+            for transformation in transformations.iter() {
+                if !transformation.applies_to(&json_node.lineage, &field.key) {
+                    continue;
+                }
+
+                match transformation.transform(&lineage, &field.key, &field.value) {
+                    Ok(Some(value)) => field.value = value,
+                    Ok(None) => {
+                        is_removed = true;
+                        break;
+                    },
+                    Err(err) => {
+                        log::warn!("Value transformation {} failed on {}: {:?}", transformation.id.to_string(), field.key, err);
+
+                        errors.push(ValueTransformationError {
+                            transformation_id: transformation.id.clone(),
+                            json_node_id: json_node.id.clone(),
+                            lineage: lineage.clone(),
+                            key: field.key.clone(),
+                            value: field.value.clone(),
+                            message: format!("{:?}", err),
+                        });
+                        break;
+                    },
+                }
+            }
+
+            if !is_removed {
+                fields.push(field);
+            }
+        }
+
+        json_node.json = fields;
+    }
+
+    Ok(errors)
+}
+
+fn get_value_transformations(options: &Option<Options>) -> Result<Vec<JsonValueTransform>, Errors> {
+    let transformations = options.as_ref()
+        .and_then(|options| options.value_transformations.clone())
+        .unwrap_or_default();
+
+    transformations.into_iter()
+        .map(|transformation| {
+            transformation.validate()?;
+
+            match transformation {
+                Transformation::JsonValueTransform(transform) => Ok(transform),
+                other => Err(Errors::TransformationValidationError(format!(
+                    "Transformation {} does not apply to values",
+                    other.get_id().to_string()
+                ))),
+            }
+        })
+        .collect()
+}
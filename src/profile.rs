@@ -1,10 +1,18 @@
 use serde::{Serialize, Deserialize};
-use std::collections::{HashSet};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
 use crate::prelude::*;
+use crate::config::{CONFIG, LineageMode};
+use crate::document::{Document, hash_feature};
+use crate::hash::{HashAlgorithm, HashScheme};
+use crate::lineage::{legacy_max_cycle_length, LineageSettings};
 use crate::transformation::{
     XMLElementTransformation,
-    HashTransformation
+    HashTransformation,
+    Runtime,
+    ExampleFailure,
 };
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -13,23 +21,502 @@ pub struct Profile {
     pub description: String,
     pub features: HashSet<Hash>,
     pub xml_element_transformation: Option<XMLElementTransformation>,
-    pub hash_transformation: Option<HashTransformation>
+    pub hash_transformation: Option<HashTransformation>,
+    /// Inverse document frequency of each feature across the known profiles,
+    /// so that boilerplate shared by every site counts for little
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feature_weights: Option<HashMap<Hash, f64>>,
+    /// Path each feature was hashed from, so that features can be named
+    /// once they are gone. Profiles saved before paths were recorded lack it.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub feature_paths: HashMap<Hash, String>,
+    /// Glob patterns such as `news.ycombinator.com/item*` or `*.example.com`,
+    /// matched against the host and path of a document's origin. Patterns
+    /// without a path only match the host.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub url_patterns: Vec<String>,
+    /// Match threshold estimated from the samples the profile was learned
+    /// from. Falls back to the configured threshold when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similarity_threshold: Option<f64>,
+    /// Encoding of the node and lineage hashes computed under the profile.
+    /// Features always use the legacy encoding. Profiles saved before
+    /// schemes were recorded used the legacy one.
+    #[serde(default = "HashScheme::legacy")]
+    pub hash_scheme: HashScheme,
+    /// Digest of the features and of every hash computed under the profile
+    #[serde(default = "HashAlgorithm::legacy")]
+    pub hash_algorithm: HashAlgorithm,
+    /// How lineages under the profile collapse repeated hashes, so that
+    /// changing the configured mode leaves its stored lineages matching.
+    /// Profiles saved before modes were recorded used exact lineages.
+    #[serde(default = "LineageMode::legacy")]
+    pub lineage_mode: LineageMode,
+    #[serde(default = "legacy_max_cycle_length")]
+    pub lineage_max_cycle_length: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ProfileCandidate {
+    pub id: ID,
+    pub description: String,
+    pub similarity: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProfileMatch {
+    pub profile: Profile,
+    pub similarity: f64,
+    pub threshold: f64,
+    /// The next best candidates, kept for diagnostics
+    pub runner_ups: Vec<ProfileCandidate>,
 }
 
 impl Profile {
-    pub fn get_similar_profile(
-        profiles: &Vec<Profile>,
-        features: &HashSet<Hash>
-    ) -> Option<Profile> {
-        profiles.iter()
-            .find(|profile| {
-                let similarity = jaccard_similarity(features, &profile.features);
+    /// Creates a profile for a document that matched none of the known
+    /// profiles, using the default transformations
+    pub fn bootstrap(
+        features: HashSet<Hash>,
+        origin: &Option<String>,
+    ) -> Self {
+        log::trace!("In bootstrap");
+
+        let description = match origin {
+            Some(origin) => format!("Generated profile for {}", origin),
+            None => String::from("Generated profile"),
+        };
+
+        let url_patterns = origin.as_ref()
+            .and_then(|origin| parse_origin(origin))
+            .map(|(host, _)| vec![format!("{}/*", host)])
+            .unwrap_or_default();
+
+        // Features were hashed before the profile existed, so it adopts
+        // their algorithm
+        let hash_algorithm = features.iter()
+            .next()
+            .map(Hash::get_algorithm)
+            .unwrap_or_else(HashAlgorithm::current);
+
+        let lineage_settings = LineageSettings::current();
+
+        Profile {
+            id: ID::new(),
+            description,
+            features,
+            xml_element_transformation: Some(XMLElementTransformation::default()),
+            hash_transformation: Some(HashTransformation::default()),
+            feature_weights: None,
+            feature_paths: HashMap::new(),
+            url_patterns,
+            similarity_threshold: None,
+            hash_scheme: lineage_settings.hash_scheme,
+            hash_algorithm,
+            lineage_mode: lineage_settings.mode,
+            lineage_max_cycle_length: lineage_settings.max_cycle_length,
+        }
+    }
+
+    /// Records the paths of the features among the given ones
+    pub fn with_feature_paths(mut self, feature_paths: &HashSet<String>) -> Self {
+        let algorithm = self.hash_algorithm;
+
+        self.feature_paths = feature_paths.iter()
+            .map(|path| (hash_feature(path, algorithm), path.clone()))
+            .filter(|(feature, _)| self.features.contains(feature))
+            .collect();
+
+        self
+    }
+
+    /// Learns a profile from several sample documents of the same site. Only
+    /// features present in at least `min_support` samples are kept, so that
+    /// incidental features of a single page do not end up in the profile.
+    /// The feature weights it would get next to the known profiles are
+    /// computed first, so that the threshold is estimated on the same scale
+    /// as the similarities it gates.
+    pub fn learn(
+        samples: &[Document],
+        min_support: usize,
+        origin: &Option<String>,
+        profiles: &[Profile],
+    ) -> Result<Self, Errors> {
+        log::trace!("In learn");
+
+        if samples.is_empty() {
+            return Err(Errors::DocumentNotProvided);
+        }
 
-                log::debug!("similarity: {}", similarity);
+        if min_support == 0 || min_support > samples.len() {
+            return Err(Errors::ProfileValidationError(format!(
+                "Minimum support must be between 1 and the number of samples ({}), got {}",
+                samples.len(),
+                min_support
+            )));
+        }
+
+        let sample_paths: Vec<HashSet<String>> = samples.iter()
+            .map(|sample| sample.get_feature_paths())
+            .collect::<Result<Vec<HashSet<String>>, Errors>>()?;
+
+        let sample_features: Vec<HashSet<Hash>> = sample_paths.iter()
+            .map(|paths| paths.iter().map(|path| hash_feature(path, HashAlgorithm::current())).collect())
+            .collect();
+
+        let mut support: HashMap<Hash, usize> = HashMap::new();
+
+        for features in sample_features.iter() {
+            for feature in features.iter() {
+                *support.entry(feature.clone()).or_insert(0) += 1;
+            }
+        }
+
+        let core_features: HashSet<Hash> = support.into_iter()
+            .filter(|(_, count)| *count >= min_support)
+            .map(|(feature, _)| feature)
+            .collect();
+
+        if core_features.is_empty() {
+            return Err(Errors::ProfileValidationError(format!(
+                "No feature is present in at least {} samples",
+                min_support
+            )));
+        }
+
+        log::info!(
+            "Learned {} core features from {} samples with minimum support {}",
+            core_features.len(),
+            samples.len(),
+            min_support
+        );
+
+        let feature_paths: HashSet<String> = sample_paths.into_iter().flatten().collect();
+        let mut profile = Profile::bootstrap(core_features, origin).with_feature_paths(&feature_paths);
+        profile.description = match origin {
+            Some(origin) => format!("Learned profile for {}", origin),
+            None => String::from("Learned profile"),
+        };
+
+        let mut weighted: Vec<Profile> = profiles.iter()
+            .filter(|other| other.id != profile.id)
+            .cloned()
+            .collect();
+        weighted.push(profile.clone());
+        Profile::compute_feature_weights(&mut weighted);
+
+        profile.feature_weights = weighted.pop().and_then(|weighted| weighted.feature_weights);
+        profile.similarity_threshold = estimate_threshold(&profile, &sample_features);
+
+        Ok(profile)
+    }
+
+    /// What documents analysed under the profile derive lineages with
+    pub fn get_lineage_settings(&self) -> LineageSettings {
+        LineageSettings {
+            hash_scheme: self.hash_scheme.clone(),
+            mode: self.lineage_mode.clone(),
+            max_cycle_length: self.lineage_max_cycle_length,
+        }
+    }
+
+    /// Fills in default transformations where the profile lacks them.
+    /// Returns whether anything was added.
+    pub fn complete_with_defaults(&mut self) -> bool {
+        let mut is_modified = false;
+
+        if self.xml_element_transformation.is_none() {
+            log::info!("Profile {} lacks an xml element transformation, using the default", self.id.to_string());
+            self.xml_element_transformation = Some(XMLElementTransformation::default());
+            is_modified = true;
+        }
+
+        if self.hash_transformation.is_none() {
+            log::info!("Profile {} lacks a hash transformation, using the default", self.id.to_string());
+            self.hash_transformation = Some(HashTransformation::default());
+            is_modified = true;
+        }
+
+        is_modified
+    }
 
-                similarity > 0.8
+    /// Replaces script transformations with native ones where the script
+    /// only uses patterns that can be expressed declaratively. Returns
+    /// whether anything was replaced.
+    pub fn nativize(&mut self) -> bool {
+        let mut is_modified = false;
+
+        if let Some(transformation) = &self.xml_element_transformation {
+            if !matches!(transformation.runtime, Runtime::Native) {
+                if let Some(native) = transformation.to_native() {
+                    self.xml_element_transformation = Some(native);
+                    is_modified = true;
+                }
+            }
+        }
+
+        if let Some(transformation) = &self.hash_transformation {
+            if !matches!(transformation.runtime, Runtime::Native) {
+                if let Some(native) = transformation.to_native() {
+                    self.hash_transformation = Some(native);
+                    is_modified = true;
+                }
+            }
+        }
+
+        is_modified
+    }
+
+    /// Recomputes the TF-IDF weights of every profile's features. Features
+    /// are a set, so the term frequency is always one and the weight is the
+    /// smoothed inverse document frequency.
+    pub fn compute_feature_weights(profiles: &mut [Profile]) {
+        log::trace!("In compute_feature_weights");
+
+        let total = profiles.len() as f64;
+        let mut document_frequencies: HashMap<Hash, usize> = HashMap::new();
+
+        for profile in profiles.iter() {
+            for feature in profile.features.iter() {
+                *document_frequencies.entry(feature.clone()).or_insert(0) += 1;
+            }
+        }
+
+        for profile in profiles.iter_mut() {
+            let weights: HashMap<Hash, f64> = profile.features.iter()
+                .map(|feature| {
+                    let document_frequency = document_frequencies.get(feature).cloned().unwrap_or(0) as f64;
+                    let weight = ((1.0 + total) / (1.0 + document_frequency)).ln() + 1.0;
+
+                    (feature.clone(), weight)
+                })
+                .collect();
+
+            profile.feature_weights = Some(weights);
+        }
+    }
+
+    /// The threshold from the options if given, then the profile's own
+    /// estimate, then the configured default
+    pub fn get_similarity_threshold(&self, options: &Option<Options>) -> f64 {
+        options.as_ref()
+            .and_then(|opts| opts.profile_similarity_threshold)
+            .or(self.similarity_threshold)
+            .unwrap_or_else(|| read_lock!(CONFIG).profile.similarity_threshold)
+    }
+
+    /// How specifically the profile's URL patterns match the origin: `None`
+    /// if the profile has patterns and none match, otherwise the number of
+    /// literal characters in the best matching pattern (zero without
+    /// patterns or without an origin).
+    pub fn match_origin(&self, origin: &Option<String>) -> Option<usize> {
+        let (host, path) = match origin.as_ref().and_then(|origin| parse_origin(origin)) {
+            Some(parsed) => parsed,
+            None => return Some(0),
+        };
+
+        if self.url_patterns.is_empty() {
+            return Some(0);
+        }
+
+        self.url_patterns.iter()
+            .filter(|pattern| {
+                if pattern.contains('/') {
+                    glob_matches(pattern, &format!("{}{}", host, path))
+                } else {
+                    glob_matches(pattern, &host)
+                }
             })
-            .map(|profile| profile.clone())
+            .map(|pattern| pattern.chars().filter(|c| *c != '*').count())
+            .max()
+    }
+
+    /// Ranks profiles by feature similarity, skipping profiles whose URL
+    /// patterns exclude the origin. Equal similarities are broken by the more
+    /// specific URL match.
+    pub fn rank_profiles<'a>(
+        profiles: &'a [Profile],
+        features: &HashSet<Hash>,
+        origin: &Option<String>,
+    ) -> Vec<(f64, &'a Profile)> {
+        // Features hashed with another algorithm can never be equal, so
+        // such profiles are not candidates at all
+        let algorithm = features.iter().next().map(Hash::get_algorithm);
+        let comparable: Vec<&'a Profile> = profiles.iter()
+            .filter(|profile| algorithm.is_none_or(|algorithm| profile.hash_algorithm == algorithm))
+            .collect();
+
+        if comparable.len() < profiles.len() {
+            log::debug!(
+                "{} of {} profiles use another hash algorithm",
+                profiles.len() - comparable.len(),
+                profiles.len()
+            );
+        }
+
+        let mut ranked: Vec<(f64, usize, &'a Profile)> = comparable.iter()
+            .copied()
+            .filter_map(|profile| {
+                profile.match_origin(origin).map(|specificity| {
+                    (weighted_jaccard_similarity(features, profile), specificity, profile)
+                })
+            })
+            .collect();
+
+        if ranked.len() < comparable.len() {
+            log::debug!(
+                "URL patterns excluded {} of {} profiles",
+                comparable.len() - ranked.len(),
+                comparable.len()
+            );
+        }
+
+        ranked.sort_by(|a, b| {
+            b.0.partial_cmp(&a.0)
+                .unwrap_or(Ordering::Equal)
+                .then(b.1.cmp(&a.1))
+        });
+
+        ranked.into_iter()
+            .map(|(similarity, _, profile)| (similarity, profile))
+            .collect()
+    }
+
+    pub fn get_similar_profile(
+        profiles: &[Profile],
+        features: &HashSet<Hash>,
+        options: &Option<Options>,
+    ) -> Option<ProfileMatch> {
+        let origin = options.as_ref().and_then(|opts| opts.origin.clone());
+        let ranked = Profile::rank_profiles(profiles, features, &origin);
+
+        let max_runner_ups = read_lock!(CONFIG).profile.max_runner_ups;
+        let runner_ups: Vec<ProfileCandidate> = ranked.iter()
+            .skip(1)
+            .take(max_runner_ups)
+            .map(|(similarity, profile)| ProfileCandidate {
+                id: profile.id.clone(),
+                description: profile.description.clone(),
+                similarity: *similarity,
+            })
+            .collect();
+
+        for candidate in runner_ups.iter() {
+            log::debug!(
+                "Runner-up profile {} ({}) with similarity {}",
+                candidate.id.to_string(),
+                candidate.description,
+                candidate.similarity
+            );
+        }
+
+        match ranked.first() {
+            Some((similarity, profile)) if *similarity >= profile.get_similarity_threshold(options) => {
+                let threshold = profile.get_similarity_threshold(options);
+
+                log::info!(
+                    "Matched profile {} ({}) with similarity {} (threshold {})",
+                    profile.id.to_string(),
+                    profile.description,
+                    similarity,
+                    threshold
+                );
+
+                Some(ProfileMatch {
+                    profile: (*profile).clone(),
+                    similarity: *similarity,
+                    threshold,
+                    runner_ups,
+                })
+            },
+            Some((similarity, profile)) => {
+                let threshold = profile.get_similarity_threshold(options);
+
+                log::info!(
+                    "No profile matched: best candidate {} ({}) has similarity {}, below threshold {}",
+                    profile.id.to_string(),
+                    profile.description,
+                    similarity,
+                    threshold
+                );
+
+                None
+            },
+            None => {
+                log::info!("No profile matched: there are no profiles to compare against");
+
+                None
+            }
+        }
+    }
+
+    /// Runs the examples embedded in the profile's transformations,
+    /// returning those that fail
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        let mut failures = Vec::new();
+
+        if let Some(transformation) = &self.xml_element_transformation {
+            failures.extend(transformation.test());
+        }
+
+        if let Some(transformation) = &self.hash_transformation {
+            failures.extend(transformation.test());
+        }
+
+        failures
+    }
+
+    pub fn validate(&self) -> Result<(), Errors> {
+        if self.id.to_string().trim().is_empty() {
+            return Err(Errors::ProfileValidationError("Profile id is empty".into()));
+        }
+
+        if self.features.is_empty() {
+            return Err(Errors::ProfileValidationError(
+                format!("Profile {} has no features", self.id.to_string())
+            ));
+        }
+
+        if self.features.iter().any(|feature| feature.is_unfinalized()) {
+            return Err(Errors::ProfileValidationError(
+                format!("Profile {} contains an uncomputed feature hash", self.id.to_string())
+            ));
+        }
+
+        if let Some(threshold) = self.similarity_threshold {
+            if !(0.0..=1.0).contains(&threshold) {
+                return Err(Errors::ProfileValidationError(
+                    format!("Profile {} has a similarity threshold outside 0 to 1", self.id.to_string())
+                ));
+            }
+        }
+
+        if let Some(transformation) = &self.xml_element_transformation {
+            let is_empty = match transformation.runtime {
+                Runtime::Native => transformation.native.is_none(),
+                _ => transformation.infix.trim().is_empty(),
+            };
+
+            if is_empty {
+                return Err(Errors::ProfileValidationError(
+                    format!("Profile {} has an empty xml element transformation", self.id.to_string())
+                ));
+            }
+        }
+
+        if let Some(transformation) = &self.hash_transformation {
+            let is_empty = match transformation.runtime {
+                Runtime::Native => transformation.native.is_none(),
+                _ => transformation.infix.trim().is_empty(),
+            };
+
+            if is_empty {
+                return Err(Errors::ProfileValidationError(
+                    format!("Profile {} has an empty hash transformation", self.id.to_string())
+                ));
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -43,3 +530,224 @@ fn jaccard_similarity(set_a: &HashSet<Hash>, set_b: &HashSet<Hash>) -> f64 {
 
     intersection.len() as f64 / union.len() as f64
 }
+
+/// Jaccard similarity where each feature counts by its weight in the
+/// profile. Features the profile has never seen get its highest weight, since
+/// they are by definition rare.
+fn weighted_jaccard_similarity(features: &HashSet<Hash>, profile: &Profile) -> f64 {
+    let weights = match &profile.feature_weights {
+        Some(weights) if !weights.is_empty() => weights,
+        _ => return jaccard_similarity(features, &profile.features),
+    };
+
+    let unknown_weight = weights.values().cloned().fold(1.0, f64::max);
+    let weight = |feature: &Hash| weights.get(feature).cloned().unwrap_or(unknown_weight);
+
+    let intersection: f64 = features.intersection(&profile.features).map(weight).sum();
+    let union: f64 = features.union(&profile.features).map(weight).sum();
+
+    if union == 0.0 {
+        return 1.0;
+    }
+
+    intersection / union
+}
+
+/// Estimates a match threshold as one standard deviation below the least
+/// similar sample, so that every sample would have matched. A single sample
+/// says nothing about variation, so no estimate is made.
+fn estimate_threshold(
+    profile: &Profile,
+    sample_features: &[HashSet<Hash>],
+) -> Option<f64> {
+    if sample_features.len() < 2 {
+        return None;
+    }
+
+    let similarities: Vec<f64> = sample_features.iter()
+        .map(|features| weighted_jaccard_similarity(features, profile))
+        .collect();
+
+    let count = similarities.len() as f64;
+    let mean = similarities.iter().sum::<f64>() / count;
+    let variance = similarities.iter().map(|similarity| (similarity - mean).powi(2)).sum::<f64>() / count;
+    let minimum = similarities.iter().cloned().fold(1.0, f64::min);
+
+    let threshold = (minimum - variance.sqrt()).clamp(0.0, 1.0);
+
+    log::info!(
+        "Sample similarities to the learned profile range from {} with mean {}, estimated threshold {}",
+        minimum,
+        mean,
+        threshold
+    );
+
+    Some(threshold)
+}
+
+/// Splits an origin such as `https://example.com/a/b?c` into its host and
+/// path. Origins without a scheme are accepted too.
+fn parse_origin(origin: &str) -> Option<(String, String)> {
+    let parsed = url::Url::parse(origin)
+        .or_else(|_| url::Url::parse(&format!("http://{}", origin)))
+        .ok()?;
+
+    let host = parsed.host_str()?.to_lowercase();
+
+    Some((host, parsed.path().to_string()))
+}
+
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let expression = pattern
+        .to_lowercase()
+        .split('*')
+        .map(regex::escape)
+        .collect::<Vec<String>>()
+        .join(".*");
+
+    match Regex::new(&format!("^{}$", expression)) {
+        Ok(regex) => regex.is_match(&value.to_lowercase()),
+        Err(err) => {
+            log::warn!("Invalid URL pattern {}: {}", pattern, err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(extra: &str) -> Document {
+        let html = format!(
+            "<html><body><div class=\"nav\"><a>Home</a></div><table><tr class=\"athing\"><td>1</td></tr></table>{}</body></html>",
+            extra
+        );
+
+        Document::from_string(html, &None).unwrap()
+    }
+
+    #[test]
+    fn learned_threshold_admits_every_sample_under_weighted_matching() {
+        let boilerplate = Document::from_string(
+            String::from("<html><body><div class=\"nav\"><a>Home</a></div><form><input></form></body></html>"),
+            &None
+        ).unwrap();
+        let existing = Profile::bootstrap(
+            boilerplate.get_feature_paths().unwrap().iter().map(|path| hash_feature(path, HashAlgorithm::current())).collect(),
+            &None
+        );
+
+        let samples = vec![
+            document("<p>One</p>"),
+            document("<span>Two</span><ul><li>a</li></ul>"),
+            document("<p>Three</p><span>Three</span><ol><li>b</li></ol>"),
+        ];
+
+        let profile = Profile::learn(&samples, 2, &None, &vec![existing]).unwrap();
+        let threshold = profile.similarity_threshold.unwrap();
+
+        assert!(profile.feature_weights.is_some());
+
+        for sample in samples.iter() {
+            let features: HashSet<Hash> = sample.get_feature_paths().unwrap().iter()
+                .map(|path| hash_feature(path, HashAlgorithm::current()))
+                .collect();
+
+            let profiles = vec![profile.clone()];
+            let (similarity, _) = Profile::rank_profiles(&profiles, &features, &None)[0];
+
+            assert!(similarity >= threshold, "{} is below {}", similarity, threshold);
+        }
+    }
+
+    fn profile(paths: &[&str], url_patterns: &[&str]) -> Profile {
+        let mut profile = Profile::bootstrap(features(paths), &None);
+        profile.url_patterns = url_patterns.iter().map(|pattern| pattern.to_string()).collect();
+        profile
+    }
+
+    fn features(paths: &[&str]) -> HashSet<Hash> {
+        paths.iter().map(|path| hash_feature(&path.to_string(), HashAlgorithm::current())).collect()
+    }
+
+    #[test]
+    fn rank_profiles_orders_by_similarity_then_url_specificity() {
+        let profiles = vec![
+            profile(&["html", "html > body"], &[]),
+            profile(&["html", "html > body", "html > body > table"], &["*.example.com"]),
+            profile(&["html", "html > body", "html > body > table"], &["news.example.com/item*"]),
+            profile(&["html", "html > body", "html > body > table"], &["other.org"]),
+        ];
+        let observed = features(&["html", "html > body", "html > body > table"]);
+
+        let ranked = Profile::rank_profiles(&profiles, &observed, &Some(String::from("https://news.example.com/item?id=1")));
+
+        let ids: Vec<ID> = ranked.iter().map(|(_, profile)| profile.id.clone()).collect();
+        assert_eq!(ids, vec![profiles[2].id.clone(), profiles[1].id.clone(), profiles[0].id.clone()]);
+        assert_eq!(ranked[0].0, 1.0);
+        assert!(ranked[2].0 < ranked[1].0);
+    }
+
+    #[test]
+    fn rank_profiles_skips_profiles_hashed_with_another_algorithm() {
+        let mut other = profile(&["html"], &[]);
+        other.hash_algorithm = HashAlgorithm::all().into_iter()
+            .find(|algorithm| *algorithm != HashAlgorithm::current())
+            .unwrap();
+        let profiles = vec![profile(&["html"], &[]), other];
+
+        let ranked = Profile::rank_profiles(&profiles, &features(&["html"]), &None);
+
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].1.id, profiles[0].id);
+    }
+
+    #[test]
+    fn similarity_threshold_prefers_options_then_profile_then_config() {
+        let mut profile = profile(&["html"], &[]);
+        let default = read_lock!(CONFIG).profile.similarity_threshold;
+        let options = Some(Options {
+            profile_similarity_threshold: Some(0.25),
+            ..Options::default()
+        });
+
+        assert_eq!(profile.get_similarity_threshold(&None), default);
+
+        profile.similarity_threshold = Some(0.5);
+        assert_eq!(profile.get_similarity_threshold(&None), 0.5);
+        assert_eq!(profile.get_similarity_threshold(&Some(Options::default())), 0.5);
+        assert_eq!(profile.get_similarity_threshold(&options), 0.25);
+    }
+
+    #[test]
+    fn match_origin_supports_host_and_path_wildcards() {
+        let subdomains = profile(&["html"], &["*.example.com"]);
+        assert_eq!(subdomains.match_origin(&Some(String::from("https://news.example.com/item"))), Some(12));
+        assert_eq!(subdomains.match_origin(&Some(String::from("https://example.org/"))), None);
+
+        let items = profile(&["html"], &["news.example.com/item*", "news.example.com/*"]);
+        assert_eq!(items.match_origin(&Some(String::from("https://NEWS.example.com/item?id=1"))), Some(21));
+        assert_eq!(items.match_origin(&Some(String::from("news.example.com/newest"))), Some(17));
+        assert_eq!(items.match_origin(&Some(String::from("https://example.com/item"))), None);
+    }
+
+    #[test]
+    fn match_origin_ignores_the_scheme_and_accepts_unparseable_origins() {
+        let profile = profile(&["html"], &["example.com/*"]);
+
+        assert_eq!(profile.match_origin(&Some(String::from("ftp://example.com/file"))), Some(12));
+        assert_eq!(profile.match_origin(&Some(String::from("not a url"))), Some(0));
+        assert_eq!(profile.match_origin(&None), Some(0));
+        assert_eq!(parse_origin("mailto:someone@example.com"), None);
+        assert_eq!(parse_origin("example.com/a/b?c"), Some((String::from("example.com"), String::from("/a/b"))));
+    }
+
+    #[test]
+    fn glob_matches_whole_values_only() {
+        assert!(glob_matches("*.example.com", "a.b.example.com"));
+        assert!(!glob_matches("*.example.com", "example.com"));
+        assert!(!glob_matches("example.com", "example.com.evil.org"));
+        assert!(glob_matches("example.com/a+b", "example.com/a+b"));
+    }
+}
@@ -0,0 +1,183 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+use crate::profile::Profile;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// How feature sets are cut into LSH bands. This is all a store needs to
+/// find the buckets a feature set falls into.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Banding {
+    pub bands: usize,
+    pub rows_per_band: usize,
+}
+
+impl Banding {
+    pub fn from_config() -> Self {
+        let lock = read_lock!(CONFIG);
+
+        Banding {
+            bands: lock.profile.index_bands,
+            rows_per_band: lock.profile.index_rows_per_band,
+        }
+    }
+
+    /// Key of the bucket the features fall into for each band
+    pub fn band_keys(&self, features: &HashSet<Hash>) -> Vec<u64> {
+        let signature = minhash_signature(features, self.bands * self.rows_per_band);
+
+        signature
+            .chunks(self.rows_per_band)
+            .map(|rows| {
+                rows.iter().fold(FNV_OFFSET_BASIS, |acc, row| {
+                    fnv1a(acc, &row.to_le_bytes())
+                })
+            })
+            .collect()
+    }
+}
+
+/// What a database provider records about the buckets it stores, which it
+/// keeps per band so that a lookup only reads the buckets it hits. The index
+/// is current while its generation equals the store's profile generation,
+/// which every profile write increments.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProfileIndexState {
+    #[serde(flatten)]
+    pub banding: Banding,
+    pub generation: u64,
+}
+
+/// MinHash signatures of profile features, bucketed with LSH banding so that
+/// similar-profile lookups only need to verify a handful of candidates. File
+/// providers keep it whole next to the profiles, since they load everything
+/// on each lookup anyway.
+///
+/// Every hash used here is fixed (FNV-1a and splitmix64) so that an index
+/// persisted by a provider stays valid across builds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProfileIndex {
+    #[serde(flatten)]
+    pub banding: Banding,
+    /// Profile generation of the store when the index was last updated
+    #[serde(default)]
+    pub generation: u64,
+    /// Band keys per profile, needed to remove a profile before reinserting it
+    profile_bands: HashMap<ID, Vec<u64>>,
+    /// One bucket map per band, from band key to the profiles sharing it
+    buckets: Vec<HashMap<u64, Vec<ID>>>,
+}
+
+impl ProfileIndex {
+    pub fn new(banding: Banding, generation: u64) -> Self {
+        ProfileIndex {
+            banding,
+            generation,
+            profile_bands: HashMap::new(),
+            buckets: vec![HashMap::new(); banding.bands],
+        }
+    }
+
+    pub fn build(profiles: &[Profile], generation: u64) -> Self {
+        log::trace!("In build");
+
+        let mut index = ProfileIndex::new(Banding::from_config(), generation);
+
+        for profile in profiles.iter() {
+            index.insert(profile);
+        }
+
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.profile_bands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.profile_bands.is_empty()
+    }
+
+    pub fn insert(&mut self, profile: &Profile) {
+        self.remove(&profile.id);
+
+        let band_keys = self.banding.band_keys(&profile.features);
+
+        for (band, key) in band_keys.iter().enumerate() {
+            self.buckets[band]
+                .entry(*key)
+                .or_default()
+                .push(profile.id.clone());
+        }
+
+        self.profile_bands.insert(profile.id.clone(), band_keys);
+    }
+
+    pub fn remove(&mut self, id: &ID) {
+        if let Some(band_keys) = self.profile_bands.remove(id) {
+            for (band, key) in band_keys.iter().enumerate() {
+                if let Some(bucket) = self.buckets[band].get_mut(key) {
+                    bucket.retain(|other| other != id);
+
+                    if bucket.is_empty() {
+                        self.buckets[band].remove(key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// IDs of profiles that share at least one band with the given features.
+    /// Candidates still need exact similarity verification.
+    pub fn query(&self, features: &HashSet<Hash>) -> HashSet<ID> {
+        let mut candidates: HashSet<ID> = HashSet::new();
+
+        for (band, key) in self.banding.band_keys(features).iter().enumerate() {
+            if let Some(bucket) = self.buckets[band].get(key) {
+                candidates.extend(bucket.iter().cloned());
+            }
+        }
+
+        log::debug!("Profile index yielded {} candidates out of {}", candidates.len(), self.len());
+
+        candidates
+    }
+}
+
+fn minhash_signature(features: &HashSet<Hash>, num_hashes: usize) -> Vec<u64> {
+    let base_hashes: Vec<u64> = features.iter()
+        .filter_map(|feature| feature.to_string())
+        .map(|value| fnv1a(FNV_OFFSET_BASIS, value.as_bytes()))
+        .collect();
+
+    (0..num_hashes)
+        .map(|i| {
+            let seed = splitmix64(i as u64);
+
+            base_hashes.iter()
+                .map(|base| splitmix64(base ^ seed))
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect()
+}
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
@@ -1,23 +1,159 @@
 use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use serde_json::Value;
+use std::path::Path;
 use serde_yaml;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::de::DeserializeOwned;
+use sled::Transactional;
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionalTree};
 
 use crate::prelude::*;
-use crate::profile::Profile;
+use crate::profile::{Profile, ProfileMatch};
 use crate::basis_node::BasisNode;
+use crate::basis_network::BasisNetwork;
+use crate::profile_index::{Banding, ProfileIndex, ProfileIndexState};
 
 #[async_trait]
 pub trait Provider: Send + Sync + Sized + 'static {
     async fn get_profile(
         &self,
-        features: &HashSet<Hash>
-    ) -> Result<Option<Profile>, Errors>;
+        features: &HashSet<Hash>,
+        options: &Option<Options>,
+    ) -> Result<Option<ProfileMatch>, Errors>;
     async fn get_basis_node_by_lineage(
         &self,
         lineage: &Lineage
     ) -> Result<Option<BasisNode>, Errors>;
+    async fn list_profiles(&self) -> Result<Vec<Profile>, Errors>;
+    async fn list_basis_nodes(&self) -> Result<Vec<BasisNode>, Errors>;
+    async fn list_basis_networks(&self) -> Result<Vec<BasisNetwork>, Errors>;
+    /// Leaves the feature weights of every profile as they are. Callers that
+    /// add profiles reweight once they are done with `reweight_profiles`.
+    async fn save_profile(&self, profile: &Profile) -> Result<(), Errors>;
+    async fn save_basis_node(&self, basis_node: &BasisNode) -> Result<(), Errors>;
+    async fn save_basis_network(&self, basis_network: &BasisNetwork) -> Result<(), Errors>;
+    /// Saves every entity of the batch, in a single write where the store
+    /// allows it. Like `save_profile`, it leaves feature weights as they are.
+    async fn save_batch(&self, batch: &ProviderData) -> Result<(), Errors>;
+    async fn build_profile_index(&self) -> Result<(), Errors>;
+    async fn reweight_profiles(&self) -> Result<(), Errors>;
+}
+
+/// Everything a provider stores. This is also the on-disk layout of the
+/// YAML and JSON file providers.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProviderData {
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+    #[serde(default)]
+    pub basis_nodes: Vec<BasisNode>,
+    #[serde(default)]
+    pub basis_networks: Vec<BasisNetwork>,
+    /// Incremented by every profile write, so that an index left behind by
+    /// a write that did not maintain it is recognised as stale
+    #[serde(default)]
+    pub profiles_generation: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_index: Option<ProfileIndex>,
+}
+
+impl ProviderData {
+    /// Profiles worth comparing against the given features. Uses the profile
+    /// index when it is up to date, otherwise every profile is a candidate.
+    pub fn get_candidate_profiles(&self, features: &HashSet<Hash>) -> Vec<Profile> {
+        match &self.profile_index {
+            Some(index) if index.generation == self.profiles_generation => {
+                let candidate_ids = index.query(features);
+
+                self.profiles.iter()
+                    .filter(|profile| candidate_ids.contains(&profile.id))
+                    .cloned()
+                    .collect()
+            },
+            Some(_) => {
+                log::warn!("Profile index is stale, comparing against every profile");
+                self.profiles.clone()
+            },
+            None => self.profiles.clone(),
+        }
+    }
+
+    fn upsert_profile(&mut self, profile: &Profile) {
+        if let Some(existing) = self.profiles.iter_mut().find(|p| p.id == profile.id) {
+            *existing = profile.clone();
+        } else {
+            self.profiles.push(profile.clone());
+        }
+
+        self.profiles_generation += 1;
+
+        match self.profile_index.as_mut() {
+            Some(index) if index.generation + 1 == self.profiles_generation => {
+                index.insert(profile);
+                index.generation = self.profiles_generation;
+            },
+            _ => self.profile_index = Some(ProfileIndex::build(&self.profiles, self.profiles_generation)),
+        }
+    }
+
+    /// Upserts every entity of the batch, in time linear in the size of both
+    fn merge(&mut self, batch: &ProviderData) {
+        upsert_all(&mut self.profiles, &batch.profiles, |profile| &profile.id);
+        upsert_all(&mut self.basis_nodes, &batch.basis_nodes, |basis_node| &basis_node.id);
+        upsert_all(&mut self.basis_networks, &batch.basis_networks, |basis_network| &basis_network.id);
+
+        if batch.profiles.is_empty() {
+            return;
+        }
+
+        self.profiles_generation += 1;
+
+        match self.profile_index.as_mut() {
+            Some(index) if index.generation + 1 == self.profiles_generation => {
+                for profile in batch.profiles.iter() {
+                    index.insert(profile);
+                }
+
+                index.generation = self.profiles_generation;
+            },
+            _ => self.profile_index = Some(ProfileIndex::build(&self.profiles, self.profiles_generation)),
+        }
+    }
+
+    fn upsert_basis_node(&mut self, basis_node: &BasisNode) {
+        if let Some(existing) = self.basis_nodes.iter_mut().find(|n| n.id == basis_node.id) {
+            *existing = basis_node.clone();
+        } else {
+            self.basis_nodes.push(basis_node.clone());
+        }
+    }
+
+    fn upsert_basis_network(&mut self, basis_network: &BasisNetwork) {
+        if let Some(existing) = self.basis_networks.iter_mut().find(|n| n.id == basis_network.id) {
+            *existing = basis_network.clone();
+        } else {
+            self.basis_networks.push(basis_network.clone());
+        }
+    }
+}
+
+fn upsert_all<T: Clone>(items: &mut Vec<T>, batch: &[T], get_id: fn(&T) -> &ID) {
+    let mut positions: HashMap<ID, usize> = items.iter()
+        .enumerate()
+        .map(|(position, item)| (get_id(item).clone(), position))
+        .collect();
+
+    for item in batch.iter() {
+        match positions.get(get_id(item)) {
+            Some(position) => items[*position] = item.clone(),
+            None => {
+                positions.insert(get_id(item).clone(), items.len());
+                items.push(item.clone());
+            },
+        }
+    }
 }
 
 pub struct VoidProvider;
@@ -26,17 +162,54 @@ pub struct VoidProvider;
 impl Provider for VoidProvider {
     async fn get_profile(
         &self,
-        _features: &HashSet<Hash>
-    ) -> Result<Option<Profile>, Errors> {
+        _features: &HashSet<Hash>,
+        _options: &Option<Options>,
+    ) -> Result<Option<ProfileMatch>, Errors> {
         Ok(None)
     }
 
     async fn get_basis_node_by_lineage(
         &self,
-        lineage: &Lineage
+        _lineage: &Lineage
     ) -> Result<Option<BasisNode>, Errors> {
         Ok(None)
     }
+
+    async fn list_profiles(&self) -> Result<Vec<Profile>, Errors> {
+        Ok(Vec::new())
+    }
+
+    async fn list_basis_nodes(&self) -> Result<Vec<BasisNode>, Errors> {
+        Ok(Vec::new())
+    }
+
+    async fn list_basis_networks(&self) -> Result<Vec<BasisNetwork>, Errors> {
+        Ok(Vec::new())
+    }
+
+    async fn save_profile(&self, _profile: &Profile) -> Result<(), Errors> {
+        Ok(())
+    }
+
+    async fn save_basis_node(&self, _basis_node: &BasisNode) -> Result<(), Errors> {
+        Ok(())
+    }
+
+    async fn save_basis_network(&self, _basis_network: &BasisNetwork) -> Result<(), Errors> {
+        Ok(())
+    }
+
+    async fn save_batch(&self, _batch: &ProviderData) -> Result<(), Errors> {
+        Ok(())
+    }
+
+    async fn build_profile_index(&self) -> Result<(), Errors> {
+        Ok(())
+    }
+
+    async fn reweight_profiles(&self) -> Result<(), Errors> {
+        Ok(())
+    }
 }
 
 pub struct YamlFileProvider {
@@ -47,53 +220,111 @@ impl YamlFileProvider {
     pub fn new(file_path: String) -> Self {
         YamlFileProvider { file_path }
     }
+
+    fn load(&self) -> Result<ProviderData, Errors> {
+        let data = fs::read_to_string(&self.file_path)
+            .map_err(|_| Errors::FileReadError)?;
+
+        serde_yaml::from_str(&data).map_err(|err| {
+            log::error!("Failed to parse YAML: {:?}", err);
+            Errors::YamlParseError
+        })
+    }
+
+    fn load_or_default(&self) -> Result<ProviderData, Errors> {
+        if Path::new(&self.file_path).exists() {
+            self.load()
+        } else {
+            Ok(ProviderData::default())
+        }
+    }
+
+    fn store(&self, data: &ProviderData) -> Result<(), Errors> {
+        let serialized = serde_yaml::to_string(data).map_err(|err| {
+            log::error!("Failed to serialize YAML: {:?}", err);
+            Errors::YamlParseError
+        })?;
+
+        write_text_to_file(&self.file_path, &serialized).map_err(|err| {
+            log::error!("Failed to write provider file: {:?}", err);
+            Errors::FileOutputError
+        })
+    }
 }
 
 #[async_trait]
 impl Provider for YamlFileProvider {
     async fn get_profile(
         &self,
-        features: &HashSet<Hash>
-    ) -> Result<Option<Profile>, Errors> {
-        let data = fs::read_to_string(&self.file_path)
-            .map_err(|_| Errors::FileReadError)?;
-
+        features: &HashSet<Hash>,
+        options: &Option<Options>,
+    ) -> Result<Option<ProfileMatch>, Errors> {
         let serialized_features = serde_yaml::to_string(features).expect("Could not serialize to yaml");
 
         log::debug!("serialized_features: {}", serialized_features);
 
-        let yaml_result: Result<serde_yaml::Value, _> = serde_yaml::from_str(&data);
-        if let Err(e) = yaml_result {
-            log::error!("Failed to parse YAML: {:?}", e);
-            return Err(Errors::YamlParseError);
-        }
-        let yaml = yaml_result.unwrap();
-
-        let profiles: Vec<Profile> = yaml.get("profiles")
-            .and_then(|dp| {
-                let deserialized: Result<Vec<Profile>, _> = serde_yaml::from_value(dp.clone());
-                if let Err(ref err) = deserialized {
-                    log::error!("Deserialization error: {:?}", err);
-                }
-                deserialized.ok()
-            })
-            .ok_or(Errors::YamlParseError)?;
+        let profiles = self.load_or_default()?.get_candidate_profiles(features);
 
-        if let Some(target_profile) = Profile::get_similar_profile(
+        Ok(Profile::get_similar_profile(
             &profiles,
-            features
-        ) {
-            Ok(Some(target_profile))
-        } else {
-            Ok(None)
-        }
+            features,
+            options
+        ))
     }
 
     async fn get_basis_node_by_lineage(
         &self,
         lineage: &Lineage
     ) -> Result<Option<BasisNode>, Errors> {
-        Ok(None)
+        Ok(self.load_or_default()?.basis_nodes.into_iter().find(|node| node.lineage == *lineage))
+    }
+
+    async fn list_profiles(&self) -> Result<Vec<Profile>, Errors> {
+        Ok(self.load_or_default()?.profiles)
+    }
+
+    async fn list_basis_nodes(&self) -> Result<Vec<BasisNode>, Errors> {
+        Ok(self.load_or_default()?.basis_nodes)
+    }
+
+    async fn list_basis_networks(&self) -> Result<Vec<BasisNetwork>, Errors> {
+        Ok(self.load_or_default()?.basis_networks)
+    }
+
+    async fn save_profile(&self, profile: &Profile) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.upsert_profile(profile);
+        self.store(&data)
+    }
+
+    async fn save_basis_node(&self, basis_node: &BasisNode) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.upsert_basis_node(basis_node);
+        self.store(&data)
+    }
+
+    async fn save_basis_network(&self, basis_network: &BasisNetwork) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.upsert_basis_network(basis_network);
+        self.store(&data)
+    }
+
+    async fn save_batch(&self, batch: &ProviderData) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.merge(batch);
+        self.store(&data)
+    }
+
+    async fn build_profile_index(&self) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.profile_index = Some(ProfileIndex::build(&data.profiles, data.profiles_generation));
+        self.store(&data)
+    }
+
+    async fn reweight_profiles(&self) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        Profile::compute_feature_weights(&mut data.profiles);
+        self.store(&data)
     }
 }
 
@@ -105,39 +336,475 @@ impl JsonFileProvider {
     pub fn new(file_path: String) -> Self {
         JsonFileProvider { file_path }
     }
+
+    fn load(&self) -> Result<ProviderData, Errors> {
+        let data = fs::read_to_string(&self.file_path)
+            .map_err(|_| Errors::FileReadError)?;
+
+        serde_json::from_str(&data).map_err(|err| {
+            log::error!("Failed to parse JSON: {:?}", err);
+            Errors::JsonParseError
+        })
+    }
+
+    fn load_or_default(&self) -> Result<ProviderData, Errors> {
+        if Path::new(&self.file_path).exists() {
+            self.load()
+        } else {
+            Ok(ProviderData::default())
+        }
+    }
+
+    fn store(&self, data: &ProviderData) -> Result<(), Errors> {
+        let serialized = serde_json::to_string_pretty(data).map_err(|err| {
+            log::error!("Failed to serialize JSON: {:?}", err);
+            Errors::JsonParseError
+        })?;
+
+        write_text_to_file(&self.file_path, &serialized).map_err(|err| {
+            log::error!("Failed to write provider file: {:?}", err);
+            Errors::FileOutputError
+        })
+    }
 }
 
 #[async_trait]
 impl Provider for JsonFileProvider {
     async fn get_profile(
         &self,
-        features: &HashSet<Hash>
-    ) -> Result<Option<Profile>, Errors> {
-        let data = fs::read_to_string(&self.file_path)
-            .map_err(|_| Errors::FileReadError)?;
+        features: &HashSet<Hash>,
+        options: &Option<Options>,
+    ) -> Result<Option<ProfileMatch>, Errors> {
+        let profiles = self.load_or_default()?.get_candidate_profiles(features);
 
-        let json: Value = serde_json::from_str(&data)
-            .map_err(|_| Errors::JsonParseError)?;
+        Ok(Profile::get_similar_profile(
+            &profiles,
+            features,
+            options
+        ))
+    }
+
+    async fn get_basis_node_by_lineage(
+        &self,
+        lineage: &Lineage
+    ) -> Result<Option<BasisNode>, Errors> {
+        Ok(self.load_or_default()?.basis_nodes.into_iter().find(|node| node.lineage == *lineage))
+    }
 
-        let profiles: Vec<Profile> = json.get("profiles")
-            .and_then(|dp| serde_json::from_value(dp.clone()).ok())
-            .ok_or(Errors::JsonParseError)?;
+    async fn list_profiles(&self) -> Result<Vec<Profile>, Errors> {
+        Ok(self.load_or_default()?.profiles)
+    }
 
-        if let Some(target_profile) = Profile::get_similar_profile(
-            &profiles,
-            features
-        ) {
-            Ok(Some(target_profile))
-        } else {
-            Ok(None)
+    async fn list_basis_nodes(&self) -> Result<Vec<BasisNode>, Errors> {
+        Ok(self.load_or_default()?.basis_nodes)
+    }
+
+    async fn list_basis_networks(&self) -> Result<Vec<BasisNetwork>, Errors> {
+        Ok(self.load_or_default()?.basis_networks)
+    }
+
+    async fn save_profile(&self, profile: &Profile) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.upsert_profile(profile);
+        self.store(&data)
+    }
+
+    async fn save_basis_node(&self, basis_node: &BasisNode) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.upsert_basis_node(basis_node);
+        self.store(&data)
+    }
+
+    async fn save_basis_network(&self, basis_network: &BasisNetwork) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.upsert_basis_network(basis_network);
+        self.store(&data)
+    }
+
+    async fn save_batch(&self, batch: &ProviderData) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.merge(batch);
+        self.store(&data)
+    }
+
+    async fn build_profile_index(&self) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        data.profile_index = Some(ProfileIndex::build(&data.profiles, data.profiles_generation));
+        self.store(&data)
+    }
+
+    async fn reweight_profiles(&self) -> Result<(), Errors> {
+        let mut data = self.load_or_default()?;
+        Profile::compute_feature_weights(&mut data.profiles);
+        self.store(&data)
+    }
+}
+
+pub struct SledProvider {
+    db: sled::Db,
+}
+
+impl SledProvider {
+    pub fn new(db_path: String) -> Result<Self, Errors> {
+        let db = sled::open(&db_path).map_err(|err| {
+            log::error!("Failed to open sled database: {:?}", err);
+            Errors::SledDatabaseError
+        })?;
+
+        Ok(SledProvider { db })
+    }
+
+    fn tree(&self, name: &str) -> Result<sled::Tree, Errors> {
+        self.db.open_tree(name).map_err(|err| {
+            log::error!("Failed to open sled tree {}: {:?}", name, err);
+            Errors::SledDatabaseError
+        })
+    }
+
+    fn list<T: for<'de> Deserialize<'de>>(&self, name: &str) -> Result<Vec<T>, Errors> {
+        self.tree(name)?
+            .iter()
+            .values()
+            .map(|value| {
+                let bytes = value.map_err(|err| {
+                    log::error!("Failed to read from sled tree {}: {:?}", name, err);
+                    Errors::SledDatabaseError
+                })?;
+
+                serde_json::from_slice(&bytes).map_err(|err| {
+                    log::error!("Failed to deserialize sled value: {:?}", err);
+                    Errors::JsonParseError
+                })
+            })
+            .collect()
+    }
+
+    fn get<T: for<'de> Deserialize<'de>>(&self, name: &str, key: &str) -> Result<Option<T>, Errors> {
+        let value = self.tree(name)?.get(key.as_bytes()).map_err(|err| {
+            log::error!("Failed to read from sled tree {}: {:?}", name, err);
+            Errors::SledDatabaseError
+        })?;
+
+        value.map(|bytes| serde_json::from_slice(&bytes).map_err(|err| {
+            log::error!("Failed to deserialize sled value: {:?}", err);
+            Errors::JsonParseError
+        }))
+        .transpose()
+    }
+
+    fn insert<T: Serialize>(&self, name: &str, key: &str, value: &T) -> Result<(), Errors> {
+        let bytes = serde_json::to_vec(value).map_err(|err| {
+            log::error!("Failed to serialize sled value: {:?}", err);
+            Errors::JsonParseError
+        })?;
+
+        let tree = self.tree(name)?;
+
+        tree.insert(key.as_bytes(), bytes).map_err(|err| {
+            log::error!("Failed to write to sled tree {}: {:?}", name, err);
+            Errors::SledDatabaseError
+        })?;
+
+        tree.flush().map_err(|err| {
+            log::error!("Failed to flush sled tree {}: {:?}", name, err);
+            Errors::SledDatabaseError
+        })?;
+
+        Ok(())
+    }
+
+    fn get_profiles_generation(&self) -> Result<u64, Errors> {
+        Ok(self.get("generations", "profiles")?.unwrap_or(0))
+    }
+
+    /// Buckets are keys of the band and band key followed by a profile id,
+    /// so that a bucket is read with a prefix scan
+    fn bucket_prefix(band: usize, key: u64) -> Vec<u8> {
+        let mut prefix = (band as u32).to_be_bytes().to_vec();
+        prefix.extend_from_slice(&key.to_be_bytes());
+        prefix
+    }
+
+    fn bucket_key(band: usize, key: u64, profile_id: &str) -> Vec<u8> {
+        let mut bucket_key = SledProvider::bucket_prefix(band, key);
+        bucket_key.extend_from_slice(profile_id.as_bytes());
+        bucket_key
+    }
+
+    fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>, Errors> {
+        serde_json::to_vec(value).map_err(|err| {
+            log::error!("Failed to serialize sled value: {:?}", err);
+            Errors::JsonParseError
+        })
+    }
+
+    fn get_in<T: DeserializeOwned>(
+        tree: &TransactionalTree,
+        key: &str,
+    ) -> ConflictableTransactionResult<Option<T>, Errors> {
+        tree.get(key.as_bytes())?
+            .map(|bytes| serde_json::from_slice(&bytes).map_err(|err| {
+                log::error!("Failed to deserialize sled value: {:?}", err);
+                ConflictableTransactionError::Abort(Errors::JsonParseError)
+            }))
+            .transpose()
+    }
+
+    /// Writes every entity of the batch in one transaction, bumping the
+    /// profile generation once and keeping a current profile index current.
+    /// An index that was already stale is rebuilt afterwards.
+    fn write_batch(&self, batch: &ProviderData) -> Result<(), Errors> {
+        log::trace!("In sled/write_batch");
+
+        let profiles = batch.profiles.iter()
+            .map(|profile| Ok((profile, SledProvider::serialize(profile)?)))
+            .collect::<Result<Vec<(&Profile, Vec<u8>)>, Errors>>()?;
+        let basis_nodes = batch.basis_nodes.iter()
+            .map(|basis_node| Ok((basis_node.id.to_string(), SledProvider::serialize(basis_node)?)))
+            .collect::<Result<Vec<(String, Vec<u8>)>, Errors>>()?;
+        let basis_networks = batch.basis_networks.iter()
+            .map(|basis_network| Ok((basis_network.id.to_string(), SledProvider::serialize(basis_network)?)))
+            .collect::<Result<Vec<(String, Vec<u8>)>, Errors>>()?;
+
+        let trees = [
+            "profiles",
+            "generations",
+            "indexes",
+            "profile_buckets",
+            "profile_bands",
+            "basis_nodes",
+            "basis_networks",
+        ].iter().map(|name| self.tree(name)).collect::<Result<Vec<sled::Tree>, Errors>>()?;
+
+        let is_stale = trees.as_slice().transaction(|trees| {
+            let (profiles_tree, generations, indexes, buckets, bands, basis_nodes_tree, basis_networks_tree) =
+                (&trees[0], &trees[1], &trees[2], &trees[3], &trees[4], &trees[5], &trees[6]);
+            let abort = ConflictableTransactionError::Abort;
+
+            let generation: u64 = SledProvider::get_in(generations, "profiles")?.unwrap_or(0);
+            let state: Option<ProfileIndexState> = SledProvider::get_in(indexes, "profile_buckets")?;
+            let banding = state
+                .filter(|state| state.generation == generation)
+                .map(|state| state.banding);
+
+            for (profile, bytes) in profiles.iter() {
+                let id = profile.id.to_string();
+
+                profiles_tree.insert(id.as_bytes(), bytes.as_slice())?;
+
+                if let Some(banding) = &banding {
+                    let previous: Option<Vec<u64>> = SledProvider::get_in(bands, &id)?;
+                    let band_keys = banding.band_keys(&profile.features);
+
+                    for (band, key) in previous.unwrap_or_default().iter().enumerate() {
+                        buckets.remove(SledProvider::bucket_key(band, *key, &id))?;
+                    }
+
+                    for (band, key) in band_keys.iter().enumerate() {
+                        buckets.insert(SledProvider::bucket_key(band, *key, &id), Vec::new())?;
+                    }
+
+                    bands.insert(id.as_bytes(), SledProvider::serialize(&band_keys).map_err(abort)?)?;
+                }
+            }
+
+            if !profiles.is_empty() {
+                let generation = generation + 1;
+
+                generations.insert("profiles", SledProvider::serialize(&generation).map_err(abort)?)?;
+
+                if let Some(banding) = banding {
+                    let state = ProfileIndexState { banding, generation };
+
+                    indexes.insert("profile_buckets", SledProvider::serialize(&state).map_err(abort)?)?;
+                }
+            }
+
+            for (id, bytes) in basis_nodes.iter() {
+                basis_nodes_tree.insert(id.as_bytes(), bytes.as_slice())?;
+            }
+
+            for (id, bytes) in basis_networks.iter() {
+                basis_networks_tree.insert(id.as_bytes(), bytes.as_slice())?;
+            }
+
+            Ok(banding.is_none() && !profiles.is_empty())
+        }).map_err(|err| match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => {
+                log::error!("Failed to write sled batch: {:?}", err);
+                Errors::SledDatabaseError
+            },
+        })?;
+
+        self.db.flush().map_err(|err| {
+            log::error!("Failed to flush sled database: {:?}", err);
+            Errors::SledDatabaseError
+        })?;
+
+        if is_stale {
+            self.rebuild_index()?;
+        }
+
+        Ok(())
+    }
+
+    fn index_profile(&self, banding: &Banding, profile: &Profile) -> Result<(), Errors> {
+        let buckets = self.tree("profile_buckets")?;
+        let previous: Option<Vec<u64>> = self.get("profile_bands", &profile.id.to_string())?;
+        let band_keys = banding.band_keys(&profile.features);
+
+        for (band, key) in previous.unwrap_or_default().iter().enumerate() {
+            buckets.remove(SledProvider::bucket_key(band, *key, &profile.id.to_string())).map_err(|err| {
+                log::error!("Failed to remove from sled tree profile_buckets: {:?}", err);
+                Errors::SledDatabaseError
+            })?;
+        }
+
+        for (band, key) in band_keys.iter().enumerate() {
+            buckets.insert(SledProvider::bucket_key(band, *key, &profile.id.to_string()), Vec::new()).map_err(|err| {
+                log::error!("Failed to write to sled tree profile_buckets: {:?}", err);
+                Errors::SledDatabaseError
+            })?;
+        }
+
+        self.insert("profile_bands", &profile.id.to_string(), &band_keys)
+    }
+
+    fn query_index(&self, banding: &Banding, features: &HashSet<Hash>) -> Result<HashSet<ID>, Errors> {
+        let buckets = self.tree("profile_buckets")?;
+        let mut candidates: HashSet<ID> = HashSet::new();
+
+        for (band, key) in banding.band_keys(features).iter().enumerate() {
+            let prefix = SledProvider::bucket_prefix(band, *key);
+
+            for bucket_key in buckets.scan_prefix(&prefix).keys() {
+                let bucket_key = bucket_key.map_err(|err| {
+                    log::error!("Failed to read from sled tree profile_buckets: {:?}", err);
+                    Errors::SledDatabaseError
+                })?;
+
+                candidates.insert(ID::from_str(&String::from_utf8_lossy(&bucket_key[prefix.len()..])));
+            }
+        }
+
+        log::debug!("Profile index yielded {} candidates", candidates.len());
+
+        Ok(candidates)
+    }
+
+    fn rebuild_index(&self) -> Result<(), Errors> {
+        log::trace!("In sled/rebuild_index");
+
+        let banding = Banding::from_config();
+        let generation = self.get_profiles_generation()?;
+
+        for name in ["profile_buckets", "profile_bands"] {
+            self.tree(name)?.clear().map_err(|err| {
+                log::error!("Failed to clear sled tree {}: {:?}", name, err);
+                Errors::SledDatabaseError
+            })?;
+        }
+
+        for profile in self.list::<Profile>("profiles")?.iter() {
+            self.index_profile(&banding, profile)?;
         }
+
+        // Indexes from before buckets were stored per band
+        self.tree("indexes")?.remove("profiles").map_err(|err| {
+            log::error!("Failed to remove from sled tree indexes: {:?}", err);
+            Errors::SledDatabaseError
+        })?;
+
+        self.insert("indexes", "profile_buckets", &ProfileIndexState { banding, generation })
+    }
+}
+
+#[async_trait]
+impl Provider for SledProvider {
+    async fn get_profile(
+        &self,
+        features: &HashSet<Hash>,
+        options: &Option<Options>,
+    ) -> Result<Option<ProfileMatch>, Errors> {
+        let state: Option<ProfileIndexState> = self.get("indexes", "profile_buckets")?;
+
+        let profiles: Vec<Profile> = match state {
+            Some(state) if state.generation == self.get_profiles_generation()? => {
+                self.query_index(&state.banding, features)?
+                    .iter()
+                    .map(|id| self.get("profiles", &id.to_string()))
+                    .collect::<Result<Vec<Option<Profile>>, Errors>>()?
+                    .into_iter()
+                    .flatten()
+                    .collect()
+            },
+            _ => {
+                log::warn!("Profile index is missing or stale, comparing against every profile");
+                self.list("profiles")?
+            }
+        };
+
+        Ok(Profile::get_similar_profile(
+            &profiles,
+            features,
+            options
+        ))
     }
 
     async fn get_basis_node_by_lineage(
         &self,
         lineage: &Lineage
     ) -> Result<Option<BasisNode>, Errors> {
-        Ok(None)
+        let basis_nodes: Vec<BasisNode> = self.list("basis_nodes")?;
+
+        Ok(basis_nodes.into_iter().find(|node| node.lineage == *lineage))
+    }
+
+    async fn list_profiles(&self) -> Result<Vec<Profile>, Errors> {
+        self.list("profiles")
+    }
+
+    async fn list_basis_nodes(&self) -> Result<Vec<BasisNode>, Errors> {
+        self.list("basis_nodes")
+    }
+
+    async fn list_basis_networks(&self) -> Result<Vec<BasisNetwork>, Errors> {
+        self.list("basis_networks")
+    }
+
+    async fn save_profile(&self, profile: &Profile) -> Result<(), Errors> {
+        self.write_batch(&ProviderData {
+            profiles: vec![profile.clone()],
+            ..ProviderData::default()
+        })
+    }
+
+    async fn save_basis_node(&self, basis_node: &BasisNode) -> Result<(), Errors> {
+        self.insert("basis_nodes", &basis_node.id.to_string(), basis_node)
+    }
+
+    async fn save_basis_network(&self, basis_network: &BasisNetwork) -> Result<(), Errors> {
+        self.insert("basis_networks", &basis_network.id.to_string(), basis_network)
+    }
+
+    async fn save_batch(&self, batch: &ProviderData) -> Result<(), Errors> {
+        self.write_batch(batch)
+    }
+
+    async fn build_profile_index(&self) -> Result<(), Errors> {
+        self.rebuild_index()
+    }
+
+    async fn reweight_profiles(&self) -> Result<(), Errors> {
+        let mut profiles: Vec<Profile> = self.list("profiles")?;
+        Profile::compute_feature_weights(&mut profiles);
+
+        for profile in profiles.iter() {
+            self.insert("profiles", &profile.id.to_string(), profile)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -146,7 +813,673 @@ pub struct SqliteProvider {
 }
 
 impl SqliteProvider {
-    pub fn new(db_path: String) -> Self {
-        SqliteProvider { db_path }
+    /// Creates the tables, and adds columns that older databases lack, once
+    /// rather than on every connection
+    pub fn new(db_path: String) -> Result<Self, Errors> {
+        let provider = SqliteProvider { db_path };
+        let connection = provider.connect()?;
+
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS profiles (
+                id TEXT PRIMARY KEY,
+                data TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS basis_nodes (
+                id TEXT PRIMARY KEY,
+                lineage TEXT NOT NULL,
+                path TEXT NOT NULL DEFAULT '',
+                data TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS basis_nodes_lineage ON basis_nodes (lineage);
+            CREATE TABLE IF NOT EXISTS basis_networks (
+                id TEXT PRIMARY KEY,
+                data TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS indexes (
+                name TEXT PRIMARY KEY,
+                data TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS profile_buckets (
+                band INTEGER NOT NULL,
+                key INTEGER NOT NULL,
+                profile_id TEXT NOT NULL,
+                PRIMARY KEY (band, key, profile_id)
+            );
+            CREATE INDEX IF NOT EXISTS profile_buckets_profile ON profile_buckets (profile_id);
+            CREATE TABLE IF NOT EXISTS generations (
+                name TEXT PRIMARY KEY,
+                generation INTEGER NOT NULL
+            );"
+        ).map_err(|err| {
+            log::error!("Failed to create sqlite tables: {:?}", err);
+            Errors::SqliteQueryError
+        })?;
+
+        SqliteProvider::add_path_column(&connection)?;
+
+        Ok(provider)
+    }
+
+    fn connect(&self) -> Result<Connection, Errors> {
+        Connection::open(&self.db_path).map_err(|err| {
+            log::error!("Failed to open sqlite database: {:?}", err);
+            Errors::SqliteDatabaseConnectionError
+        })
+    }
+
+    /// Databases created before lineage paths were stored lack the column,
+    /// which is added and filled from the stored basis nodes
+    fn add_path_column(connection: &Connection) -> Result<(), Errors> {
+        let to_error = |err| {
+            log::error!("Failed to add basis node paths: {:?}", err);
+            Errors::SqliteQueryError
+        };
+
+        let has_path: bool = connection
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('basis_nodes') WHERE name = 'path'",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .map_err(to_error)? > 0;
+
+        if has_path {
+            return Ok(());
+        }
+
+        log::info!("Adding path column to sqlite basis nodes");
+
+        let transaction = connection.unchecked_transaction().map_err(to_error)?;
+
+        transaction
+            .execute("ALTER TABLE basis_nodes ADD COLUMN path TEXT NOT NULL DEFAULT ''", [])
+            .map_err(to_error)?;
+
+        for basis_node in SqliteProvider::list_with::<BasisNode>(&transaction, "basis_nodes")?.iter() {
+            transaction.execute(
+                "UPDATE basis_nodes SET path = ?1 WHERE id = ?2",
+                params![basis_node.lineage.get_path(), basis_node.id.to_string()],
+            ).map_err(to_error)?;
+        }
+
+        transaction.commit().map_err(to_error)
+    }
+
+    fn list<T: for<'de> Deserialize<'de>>(&self, table: &str) -> Result<Vec<T>, Errors> {
+        SqliteProvider::list_with(&self.connect()?, table)
+    }
+
+    fn list_with<T: for<'de> Deserialize<'de>>(connection: &Connection, table: &str) -> Result<Vec<T>, Errors> {
+        let mut statement = connection
+            .prepare(&format!("SELECT data FROM {}", table))
+            .map_err(|err| {
+                log::error!("Failed to prepare sqlite statement: {:?}", err);
+                Errors::SqliteQueryError
+            })?;
+
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(|err| {
+                log::error!("Failed to query sqlite table {}: {:?}", table, err);
+                Errors::SqliteQueryError
+            })?;
+
+        rows.map(|row| {
+            let data = row.map_err(|err| {
+                log::error!("Failed to read sqlite row: {:?}", err);
+                Errors::SqliteQueryError
+            })?;
+
+            serde_json::from_str(&data).map_err(|err| {
+                log::error!("Failed to deserialize sqlite row: {:?}", err);
+                Errors::JsonParseError
+            })
+        })
+        .collect()
+    }
+
+    fn get<T: for<'de> Deserialize<'de>>(
+        connection: &Connection,
+        table: &str,
+        key_column: &str,
+        key: &str,
+    ) -> Result<Option<T>, Errors> {
+        let data: Option<String> = connection
+            .query_row(
+                &format!("SELECT data FROM {} WHERE {} = ?1", table, key_column),
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|err| {
+                log::error!("Failed to query sqlite table {}: {:?}", table, err);
+                Errors::SqliteQueryError
+            })?;
+
+        data.map(|data| serde_json::from_str(&data).map_err(|err| {
+            log::error!("Failed to deserialize sqlite row: {:?}", err);
+            Errors::JsonParseError
+        }))
+        .transpose()
+    }
+
+    fn get_profiles_generation(connection: &Connection) -> Result<u64, Errors> {
+        connection
+            .query_row("SELECT generation FROM generations WHERE name = 'profiles'", [], |row| row.get::<_, i64>(0))
+            .optional()
+            .map(|generation| generation.unwrap_or(0) as u64)
+            .map_err(|err| {
+                log::error!("Failed to read profile generation: {:?}", err);
+                Errors::SqliteQueryError
+            })
+    }
+
+    fn set_profiles_generation(connection: &Connection, generation: u64) -> Result<(), Errors> {
+        connection.execute(
+            "INSERT OR REPLACE INTO generations (name, generation) VALUES ('profiles', ?1)",
+            params![generation as i64],
+        ).map_err(|err| {
+            log::error!("Failed to save profile generation: {:?}", err);
+            Errors::SqliteQueryError
+        })?;
+
+        Ok(())
+    }
+
+    fn get_index_state(connection: &Connection) -> Result<Option<ProfileIndexState>, Errors> {
+        SqliteProvider::get(connection, "indexes", "name", "profile_buckets")
+    }
+
+    fn store_index_state(connection: &Connection, state: &ProfileIndexState) -> Result<(), Errors> {
+        connection.execute(
+            "INSERT OR REPLACE INTO indexes (name, data) VALUES ('profile_buckets', ?1)",
+            params![SqliteProvider::to_json(state)?],
+        ).map_err(|err| {
+            log::error!("Failed to save profile index: {:?}", err);
+            Errors::SqliteQueryError
+        })?;
+
+        Ok(())
+    }
+
+    fn index_profile(connection: &Connection, banding: &Banding, profile: &Profile) -> Result<(), Errors> {
+        let to_error = |err| {
+            log::error!("Failed to index profile {}: {:?}", profile.id.to_string(), err);
+            Errors::SqliteQueryError
+        };
+
+        connection.execute(
+            "DELETE FROM profile_buckets WHERE profile_id = ?1",
+            params![profile.id.to_string()],
+        ).map_err(to_error)?;
+
+        let mut statement = connection
+            .prepare("INSERT OR IGNORE INTO profile_buckets (band, key, profile_id) VALUES (?1, ?2, ?3)")
+            .map_err(to_error)?;
+
+        for (band, key) in banding.band_keys(&profile.features).iter().enumerate() {
+            statement.execute(params![band as i64, *key as i64, profile.id.to_string()]).map_err(to_error)?;
+        }
+
+        Ok(())
+    }
+
+    fn query_index(
+        connection: &Connection,
+        banding: &Banding,
+        features: &HashSet<Hash>,
+    ) -> Result<HashSet<ID>, Errors> {
+        let to_error = |err| {
+            log::error!("Failed to query profile index: {:?}", err);
+            Errors::SqliteQueryError
+        };
+
+        let mut statement = connection
+            .prepare("SELECT profile_id FROM profile_buckets WHERE band = ?1 AND key = ?2")
+            .map_err(to_error)?;
+
+        let mut candidates: HashSet<ID> = HashSet::new();
+
+        for (band, key) in banding.band_keys(features).iter().enumerate() {
+            let rows = statement
+                .query_map(params![band as i64, *key as i64], |row| row.get::<_, String>(0))
+                .map_err(to_error)?;
+
+            for row in rows {
+                candidates.insert(ID::from_str(&row.map_err(to_error)?));
+            }
+        }
+
+        log::debug!("Profile index yielded {} candidates", candidates.len());
+
+        Ok(candidates)
+    }
+
+    fn rebuild_index(connection: &Connection) -> Result<(), Errors> {
+        log::trace!("In sqlite/rebuild_index");
+
+        let banding = Banding::from_config();
+        let generation = SqliteProvider::get_profiles_generation(connection)?;
+
+        // Also drops the index from before buckets were stored per band
+        connection.execute_batch(
+            "DELETE FROM profile_buckets;
+            DELETE FROM indexes WHERE name = 'profiles';"
+        ).map_err(|err| {
+            log::error!("Failed to clear profile index: {:?}", err);
+            Errors::SqliteQueryError
+        })?;
+
+        for profile in SqliteProvider::list_with::<Profile>(connection, "profiles")?.iter() {
+            SqliteProvider::index_profile(connection, &banding, profile)?;
+        }
+
+        SqliteProvider::store_index_state(connection, &ProfileIndexState { banding, generation })
+    }
+
+    fn insert_profile(connection: &Connection, profile: &Profile) -> Result<(), Errors> {
+        let state = SqliteProvider::get_index_state(connection)?;
+        let generation = SqliteProvider::get_profiles_generation(connection)? + 1;
+
+        connection.execute(
+            "INSERT OR REPLACE INTO profiles (id, data) VALUES (?1, ?2)",
+            params![profile.id.to_string(), SqliteProvider::to_json(profile)?],
+        ).map_err(|err| {
+            log::error!("Failed to save profile: {:?}", err);
+            Errors::SqliteQueryError
+        })?;
+
+        SqliteProvider::set_profiles_generation(connection, generation)?;
+
+        match state {
+            Some(state) if state.generation + 1 == generation => {
+                SqliteProvider::index_profile(connection, &state.banding, profile)?;
+                SqliteProvider::store_index_state(connection, &ProfileIndexState { banding: state.banding, generation })
+            },
+            _ => SqliteProvider::rebuild_index(connection),
+        }
+    }
+
+    fn insert_basis_node(connection: &Connection, basis_node: &BasisNode) -> Result<(), Errors> {
+        connection.execute(
+            "INSERT OR REPLACE INTO basis_nodes (id, lineage, path, data) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                basis_node.id.to_string(),
+                basis_node.lineage.to_string(),
+                basis_node.lineage.get_path(),
+                SqliteProvider::to_json(basis_node)?
+            ],
+        ).map_err(|err| {
+            log::error!("Failed to save basis node: {:?}", err);
+            Errors::SqliteQueryError
+        })?;
+
+        Ok(())
+    }
+
+    fn insert_basis_network(connection: &Connection, basis_network: &BasisNetwork) -> Result<(), Errors> {
+        connection.execute(
+            "INSERT OR REPLACE INTO basis_networks (id, data) VALUES (?1, ?2)",
+            params![basis_network.id.to_string(), SqliteProvider::to_json(basis_network)?],
+        ).map_err(|err| {
+            log::error!("Failed to save basis network: {:?}", err);
+            Errors::SqliteQueryError
+        })?;
+
+        Ok(())
+    }
+
+    /// Runs the writes in a transaction, so that a profile is never saved
+    /// without its buckets
+    fn in_transaction<F>(&self, write: F) -> Result<(), Errors>
+    where
+        F: FnOnce(&Connection) -> Result<(), Errors>,
+    {
+        let mut connection = self.connect()?;
+
+        let transaction = connection.transaction().map_err(|err| {
+            log::error!("Failed to begin sqlite transaction: {:?}", err);
+            Errors::SqliteQueryError
+        })?;
+
+        write(&transaction)?;
+
+        transaction.commit().map_err(|err| {
+            log::error!("Failed to commit sqlite transaction: {:?}", err);
+            Errors::SqliteQueryError
+        })
+    }
+
+    fn to_json<T: Serialize>(value: &T) -> Result<String, Errors> {
+        serde_json::to_string(value).map_err(|err| {
+            log::error!("Failed to serialize sqlite row: {:?}", err);
+            Errors::JsonParseError
+        })
+    }
+}
+
+#[async_trait]
+impl Provider for SqliteProvider {
+    async fn get_profile(
+        &self,
+        features: &HashSet<Hash>,
+        options: &Option<Options>,
+    ) -> Result<Option<ProfileMatch>, Errors> {
+        let connection = self.connect()?;
+        let state = SqliteProvider::get_index_state(&connection)?;
+
+        let profiles: Vec<Profile> = match state {
+            Some(state) if state.generation == SqliteProvider::get_profiles_generation(&connection)? => {
+                SqliteProvider::query_index(&connection, &state.banding, features)?
+                    .iter()
+                    .map(|id| SqliteProvider::get(&connection, "profiles", "id", &id.to_string()))
+                    .collect::<Result<Vec<Option<Profile>>, Errors>>()?
+                    .into_iter()
+                    .flatten()
+                    .collect()
+            },
+            _ => {
+                log::warn!("Profile index is missing or stale, comparing against every profile");
+                SqliteProvider::list_with(&connection, "profiles")?
+            }
+        };
+
+        Ok(Profile::get_similar_profile(
+            &profiles,
+            features,
+            options
+        ))
+    }
+
+    async fn get_basis_node_by_lineage(
+        &self,
+        lineage: &Lineage
+    ) -> Result<Option<BasisNode>, Errors> {
+        let connection = self.connect()?;
+
+        SqliteProvider::get(&connection, "basis_nodes", "lineage", &lineage.to_string())
+    }
+
+    async fn list_profiles(&self) -> Result<Vec<Profile>, Errors> {
+        self.list("profiles")
+    }
+
+    async fn list_basis_nodes(&self) -> Result<Vec<BasisNode>, Errors> {
+        self.list("basis_nodes")
+    }
+
+    async fn list_basis_networks(&self) -> Result<Vec<BasisNetwork>, Errors> {
+        self.list("basis_networks")
+    }
+
+    async fn save_profile(&self, profile: &Profile) -> Result<(), Errors> {
+        self.in_transaction(|connection| SqliteProvider::insert_profile(connection, profile))
+    }
+
+    async fn save_basis_node(&self, basis_node: &BasisNode) -> Result<(), Errors> {
+        SqliteProvider::insert_basis_node(&self.connect()?, basis_node)
+    }
+
+    async fn save_basis_network(&self, basis_network: &BasisNetwork) -> Result<(), Errors> {
+        SqliteProvider::insert_basis_network(&self.connect()?, basis_network)
+    }
+
+    async fn save_batch(&self, batch: &ProviderData) -> Result<(), Errors> {
+        self.in_transaction(|connection| {
+            for profile in batch.profiles.iter() {
+                SqliteProvider::insert_profile(connection, profile)?;
+            }
+
+            for basis_node in batch.basis_nodes.iter() {
+                SqliteProvider::insert_basis_node(connection, basis_node)?;
+            }
+
+            for basis_network in batch.basis_networks.iter() {
+                SqliteProvider::insert_basis_network(connection, basis_network)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn build_profile_index(&self) -> Result<(), Errors> {
+        self.in_transaction(SqliteProvider::rebuild_index)
+    }
+
+    async fn reweight_profiles(&self) -> Result<(), Errors> {
+        self.in_transaction(|connection| {
+            let mut profiles: Vec<Profile> = SqliteProvider::list_with(connection, "profiles")?;
+            Profile::compute_feature_weights(&mut profiles);
+
+            for profile in profiles.iter() {
+                connection.execute(
+                    "UPDATE profiles SET data = ?2 WHERE id = ?1",
+                    params![profile.id.to_string(), SqliteProvider::to_json(profile)?],
+                ).map_err(|err| {
+                    log::error!("Failed to update profile weights: {:?}", err);
+                    Errors::SqliteQueryError
+                })?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::document::hash_feature;
+    use crate::hash::HashAlgorithm;
+    use crate::testing::{basis_node, lineage, temp_path};
+
+    fn profile(prefix: &str) -> Profile {
+        let features: HashSet<Hash> = (0..20)
+            .map(|i| hash_feature(&format!("{}/{}", prefix, i), HashAlgorithm::current()))
+            .collect();
+
+        Profile::bootstrap(features, &None)
+    }
+
+    #[test]
+    fn file_index_is_rebuilt_once_stale() {
+        let (a, b, c) = (profile("a"), profile("b"), profile("c"));
+
+        let mut data = ProviderData::default();
+        data.upsert_profile(&a);
+        data.upsert_profile(&b);
+
+        assert_eq!(data.profile_index.as_ref().unwrap().generation, 2);
+        assert_eq!(data.get_candidate_profiles(&a.features).len(), 1);
+
+        // A write that did not maintain the index
+        data.profiles_generation += 1;
+        assert_eq!(data.get_candidate_profiles(&a.features).len(), 2);
+
+        data.upsert_profile(&c);
+        assert_eq!(data.profile_index.as_ref().unwrap().generation, 4);
+        assert_eq!(data.get_candidate_profiles(&c.features)[0].id, c.id);
+    }
+
+    #[tokio::test]
+    async fn file_providers_read_a_missing_file_as_empty() {
+        let features = profile("a").features;
+
+        let yaml = YamlFileProvider::new(temp_path("missing.yaml"));
+        assert!(yaml.get_profile(&features, &None).await.unwrap().is_none());
+        assert!(yaml.get_basis_node_by_lineage(&Lineage::new()).await.unwrap().is_none());
+
+        let json = JsonFileProvider::new(temp_path("missing.json"));
+        assert!(json.get_profile(&features, &None).await.unwrap().is_none());
+        assert!(json.get_basis_node_by_lineage(&Lineage::new()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn sled_index_reads_only_the_buckets_a_query_hits() {
+        let path = temp_path("sled");
+        let provider = SledProvider::new(path.clone()).unwrap();
+        let (a, b, c) = (profile("a"), profile("b"), profile("c"));
+
+        provider.save_profile(&a).await.unwrap();
+        provider.save_profile(&b).await.unwrap();
+
+        let state: ProfileIndexState = provider.get("indexes", "profile_buckets").unwrap().unwrap();
+        assert_eq!(state.generation, 2);
+        assert_eq!(provider.query_index(&state.banding, &a.features).unwrap(), HashSet::from([a.id.clone()]));
+
+        let profile_match = provider.get_profile(&a.features, &None).await.unwrap().unwrap();
+        assert_eq!(profile_match.profile.id, a.id);
+
+        // A stale index is not queried, and the next save rebuilds it
+        provider.insert("generations", "profiles", &5u64).unwrap();
+        let profile_match = provider.get_profile(&b.features, &None).await.unwrap().unwrap();
+        assert_eq!(profile_match.profile.id, b.id);
+
+        provider.save_profile(&c).await.unwrap();
+
+        let state: ProfileIndexState = provider.get("indexes", "profile_buckets").unwrap().unwrap();
+        assert_eq!(state.generation, 6);
+        assert_eq!(provider.query_index(&state.banding, &c.features).unwrap(), HashSet::from([c.id.clone()]));
+
+        // A batch is one write, whatever the number of profiles
+        let (d, e) = (profile("d"), profile("e"));
+        provider.save_batch(&ProviderData {
+            profiles: vec![d.clone(), e.clone()],
+            ..ProviderData::default()
+        }).await.unwrap();
+
+        let state: ProfileIndexState = provider.get("indexes", "profile_buckets").unwrap().unwrap();
+        assert_eq!(state.generation, 7);
+        assert_eq!(provider.query_index(&state.banding, &d.features).unwrap(), HashSet::from([d.id.clone()]));
+        assert_eq!(provider.query_index(&state.banding, &e.features).unwrap(), HashSet::from([e.id.clone()]));
+
+        drop(provider);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sqlite_index_reads_only_the_buckets_a_query_hits() {
+        let path = temp_path("sqlite");
+        let provider = SqliteProvider::new(path.clone()).unwrap();
+        let (a, b, c) = (profile("a"), profile("b"), profile("c"));
+
+        provider.save_profile(&a).await.unwrap();
+        provider.save_profile(&b).await.unwrap();
+
+        let connection = provider.connect().unwrap();
+        let state = SqliteProvider::get_index_state(&connection).unwrap().unwrap();
+        assert_eq!(state.generation, 2);
+        assert_eq!(
+            SqliteProvider::query_index(&connection, &state.banding, &a.features).unwrap(),
+            HashSet::from([a.id.clone()])
+        );
+
+        let profile_match = provider.get_profile(&a.features, &None).await.unwrap().unwrap();
+        assert_eq!(profile_match.profile.id, a.id);
+
+        // A stale index is not queried, and the next save rebuilds it
+        SqliteProvider::set_profiles_generation(&connection, 5).unwrap();
+        let profile_match = provider.get_profile(&b.features, &None).await.unwrap().unwrap();
+        assert_eq!(profile_match.profile.id, b.id);
+
+        provider.save_profile(&c).await.unwrap();
+
+        let state = SqliteProvider::get_index_state(&connection).unwrap().unwrap();
+        assert_eq!(state.generation, 6);
+        assert_eq!(
+            SqliteProvider::query_index(&connection, &state.banding, &c.features).unwrap(),
+            HashSet::from([c.id.clone()])
+        );
+
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    async fn get_weights<P: Provider>(provider: &P, profiles: &[&Profile]) -> Vec<Option<HashMap<Hash, f64>>> {
+        for profile in profiles.iter() {
+            provider.save_profile(profile).await.unwrap();
+        }
+
+        let unweighted = provider.list_profiles().await.unwrap();
+        assert!(unweighted.iter().all(|profile| profile.feature_weights.is_none()));
+
+        provider.reweight_profiles().await.unwrap();
+
+        let weighted = provider.list_profiles().await.unwrap();
+
+        profiles.iter()
+            .map(|profile| {
+                weighted.iter().find(|other| other.id == profile.id).unwrap().feature_weights.clone()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn profiles_are_weighted_alike_on_every_backend() {
+        let (a, b) = (profile("a"), profile("b"));
+        let mut shared = profile("c");
+        shared.features.extend(a.features.iter().take(5).cloned());
+
+        let profiles = [&a, &b, &shared];
+
+        let json_path = temp_path("json");
+        let yaml_path = temp_path("yaml");
+        let sled_path = temp_path("sled");
+        let sqlite_path = temp_path("sqlite");
+
+        let weights = vec![
+            get_weights(&JsonFileProvider::new(json_path.clone()), &profiles).await,
+            get_weights(&YamlFileProvider::new(yaml_path.clone()), &profiles).await,
+            get_weights(&SledProvider::new(sled_path.clone()).unwrap(), &profiles).await,
+            get_weights(&SqliteProvider::new(sqlite_path.clone()).unwrap(), &profiles).await,
+        ];
+
+        assert!(weights[0].iter().all(Option::is_some));
+        assert!(weights.iter().all(|other| *other == weights[0]));
+
+        std::fs::remove_file(&json_path).unwrap();
+        std::fs::remove_file(&yaml_path).unwrap();
+        std::fs::remove_dir_all(&sled_path).unwrap();
+        std::fs::remove_file(&sqlite_path).unwrap();
+    }
+
+    fn get_path(connection: &Connection, basis_node: &BasisNode) -> String {
+        connection
+            .query_row("SELECT path FROM basis_nodes WHERE id = ?1", params![basis_node.id.to_string()], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sqlite_stores_basis_node_paths_and_adds_them_to_older_databases() {
+        let path = temp_path("sqlite");
+        let stored = basis_node(lineage(&["html", "body", "table.itemlist"]));
+
+        // The table as it was before paths were stored
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch(
+            "CREATE TABLE basis_nodes (
+                id TEXT PRIMARY KEY,
+                lineage TEXT NOT NULL,
+                data TEXT NOT NULL
+            );"
+        ).unwrap();
+        connection.execute(
+            "INSERT INTO basis_nodes (id, lineage, data) VALUES (?1, ?2, ?3)",
+            params![stored.id.to_string(), stored.lineage.to_string(), SqliteProvider::to_json(&stored).unwrap()],
+        ).unwrap();
+        drop(connection);
+
+        let provider = SqliteProvider::new(path.clone()).unwrap();
+        let saved = basis_node(lineage(&["html", "body", "tr.athing"]));
+        provider.save_basis_node(&saved).await.unwrap();
+
+        let connection = provider.connect().unwrap();
+        assert_eq!(get_path(&connection, &stored), "html > body > table.itemlist");
+        assert_eq!(get_path(&connection, &saved), "html > body > tr.athing");
+        assert_eq!(provider.list_basis_nodes().await.unwrap().len(), 2);
+
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
     }
 }
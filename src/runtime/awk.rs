@@ -0,0 +1,449 @@
+use serde_json::{Map, Value};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+use super::{Limits, Shape, TransformRuntime};
+
+/// Runs the infix as the body of an AWK `END` block.
+///
+/// Inputs are written to stdin as tab separated records, one per scalar,
+/// map entry or list item: `S name value`, `M name key value` and
+/// `A name item`. Tabs, newlines and backslashes are escaped as `\t`, `\n`
+/// and `\\`. The generated prefix loads them into variables of the same name,
+/// maps and lists becoming arrays (lists indexed from 1). Outputs are printed
+/// back in the same format. AWK has no null, so an empty scalar output is read
+/// as null, which for example drops an element. On Unix the process runs under
+/// `ulimit -v` so that it cannot allocate past the memory limit.
+pub struct AwkRuntime;
+
+impl TransformRuntime for AwkRuntime {
+    fn execute(
+        &self,
+        infix: &str,
+        inputs: &[(&str, Value)],
+        outputs: &[(&str, Shape)],
+        limits: &Limits,
+    ) -> Result<Map<String, Value>, Errors> {
+        log::trace!("In awk/execute");
+
+        let program = format!("{}\nEND {{\n{}\n{}\n}}\n{}", prefix(inputs), infix, suffix(outputs), HELPERS);
+
+        log::debug!("program: {}", program);
+
+        let awk_command = read_lock!(CONFIG).runtime.awk_command.clone();
+
+        let mut child = command(&awk_command, &program, limits)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                log::error!("Could not start awk: {}", err);
+                Errors::TransformationError(format!("Could not start awk: {}", err))
+            })?;
+
+        {
+            let mut stdin = child.stdin.take().ok_or_else(|| {
+                Errors::TransformationError("Could not open awk stdin".into())
+            })?;
+
+            stdin.write_all(encode_inputs(inputs).as_bytes()).map_err(|err| {
+                log::error!("Could not write to awk: {}", err);
+                Errors::TransformationError(format!("Could not write to awk: {}", err))
+            })?;
+        }
+
+        // Reading one byte past the limit is enough to tell that it was exceeded
+        let stdout = read_in_background(child.stdout.take(), limits.max_output_bytes + 1);
+        let stderr = read_in_background(child.stderr.take(), limits.max_output_bytes);
+
+        let deadline = Instant::now() + limits.timeout;
+
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() >= deadline => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(limits.timed_out());
+                },
+                Ok(None) => thread::sleep(Duration::from_millis(1)),
+                Err(err) => {
+                    log::error!("Could not wait for awk: {}", err);
+                    return Err(Errors::TransformationError(format!("Could not wait for awk: {}", err)));
+                }
+            }
+        };
+
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+
+        if !status.success() {
+            let stderr = String::from_utf8_lossy(&stderr);
+            log::error!("awk failed: {}", stderr);
+
+            if stderr.contains("out of memory") {
+                return Err(Errors::TransformationLimitError(format!("awk failed: {}", stderr.trim())));
+            }
+
+            return Err(Errors::TransformationError(format!("awk failed: {}", stderr.trim())));
+        }
+
+        limits.check_output_size(stdout.len())?;
+
+        decode_outputs(&String::from_utf8_lossy(&stdout), outputs)
+    }
+}
+
+#[cfg(unix)]
+fn command(awk_command: &str, program: &str, limits: &Limits) -> Command {
+    let mut command = Command::new("sh");
+
+    command
+        .arg("-c")
+        .arg(r#"ulimit -v "$1" || exit 1; shift; exec "$@""#)
+        .arg("sh")
+        .arg((limits.memory_limit_bytes / 1024).to_string())
+        .arg(awk_command)
+        .arg(program);
+
+    command
+}
+
+#[cfg(not(unix))]
+fn command(awk_command: &str, program: &str, _limits: &Limits) -> Command {
+    let mut command = Command::new(awk_command);
+
+    command.arg(program);
+
+    command
+}
+
+fn read_in_background<R: Read + Send + 'static>(
+    reader: Option<R>,
+    max_bytes: usize,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+
+        if let Some(mut reader) = reader {
+            let _ = reader.by_ref().take(max_bytes as u64).read_to_end(&mut buffer);
+
+            // Keep draining so that awk does not block on a full pipe
+            let _ = std::io::copy(&mut reader, &mut std::io::sink());
+        }
+
+        buffer
+    })
+}
+
+const HELPERS: &str = r#"
+function __unescape(s,    out, i, c) {
+    out = ""
+    for (i = 1; i <= length(s); i++) {
+        c = substr(s, i, 1)
+        if (c == "\\" && i < length(s)) {
+            i++
+            c = substr(s, i, 1)
+            if (c == "t") c = "\t"
+            else if (c == "n") c = "\n"
+            else if (c == "r") c = "\r"
+        }
+        out = out c
+    }
+    return out
+}
+function __escape(s,    out, i, c) {
+    out = ""
+    for (i = 1; i <= length(s); i++) {
+        c = substr(s, i, 1)
+        if (c == "\\") c = "\\\\"
+        else if (c == "\t") c = "\\t"
+        else if (c == "\n") c = "\\n"
+        else if (c == "\r") c = "\\r"
+        out = out c
+    }
+    return out
+}
+"#;
+
+fn prefix(inputs: &[(&str, Value)]) -> String {
+    let mut lines = vec![String::from(r#"BEGIN { FS = "\t"; OFS = "\t" }"#)];
+
+    for (name, value) in inputs.iter() {
+        let line = match value {
+            Value::Object(_) => format!(
+                r#"$1 == "M" && $2 == "{0}" {{ {0}[__unescape($3)] = __unescape($4) }}"#,
+                name
+            ),
+            Value::Array(_) => format!(
+                r#"$1 == "A" && $2 == "{0}" {{ {0}[++__{0}_length] = __unescape($3) }}"#,
+                name
+            ),
+            _ => format!(
+                r#"$1 == "S" && $2 == "{0}" {{ {0} = __unescape($3) }}"#,
+                name
+            ),
+        };
+
+        lines.push(line);
+    }
+
+    lines.join("\n")
+}
+
+fn suffix(outputs: &[(&str, Shape)]) -> String {
+    outputs.iter()
+        .map(|(name, shape)| match shape {
+            Shape::Scalar => format!(r#"print "S", "{0}", __escape({0})"#, name),
+            Shape::Map => format!(
+                r#"for (__key in {0}) print "M", "{0}", __escape(__key), __escape({0}[__key])"#,
+                name
+            ),
+            Shape::List => format!(
+                r#"for (__index = 1; __index in {0}; __index++) print "A", "{0}", __escape({0}[__index])"#,
+                name
+            ),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn encode_inputs(inputs: &[(&str, Value)]) -> String {
+    let mut records: Vec<String> = Vec::new();
+
+    for (name, value) in inputs.iter() {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object.iter() {
+                    records.push(format!("M\t{}\t{}\t{}", name, escape(key), escape(&to_text(value))));
+                }
+            },
+            Value::Array(array) => {
+                for item in array.iter() {
+                    records.push(format!("A\t{}\t{}", name, escape(&to_text(item))));
+                }
+            },
+            _ => records.push(format!("S\t{}\t{}", name, escape(&to_text(value)))),
+        }
+    }
+
+    records.iter().map(|record| format!("{}\n", record)).collect()
+}
+
+fn decode_outputs(
+    stdout: &str,
+    outputs: &[(&str, Shape)],
+) -> Result<Map<String, Value>, Errors> {
+    let mut result: Map<String, Value> = outputs.iter()
+        .map(|(name, shape)| {
+            let empty = match shape {
+                Shape::Scalar => Value::Null,
+                Shape::Map => Value::Object(Map::new()),
+                Shape::List => Value::Array(Vec::new()),
+            };
+
+            (name.to_string(), empty)
+        })
+        .collect();
+
+    for line in stdout.lines() {
+        let fields: Vec<String> = line.split('\t').map(unescape).collect();
+
+        match (fields.first().map(String::as_str), fields.get(1)) {
+            (Some("S"), Some(name)) => {
+                let value = fields.get(2).cloned().unwrap_or_default();
+
+                if !value.is_empty() {
+                    result.insert(name.clone(), Value::String(value));
+                }
+            },
+            (Some("M"), Some(name)) => {
+                if let Some(Value::Object(object)) = result.get_mut(name) {
+                    object.insert(
+                        fields.get(2).cloned().unwrap_or_default(),
+                        Value::String(fields.get(3).cloned().unwrap_or_default()),
+                    );
+                }
+            },
+            (Some("A"), Some(name)) => {
+                if let Some(Value::Array(array)) = result.get_mut(name) {
+                    array.push(Value::String(fields.get(2).cloned().unwrap_or_default()));
+                }
+            },
+            _ => {
+                log::warn!("Ignoring unexpected awk output: {}", line);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Null => String::new(),
+        _ => value.to_string(),
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(value: &str) -> String {
+    let mut result = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn has_awk() -> bool {
+        let awk_command = read_lock!(CONFIG).runtime.awk_command.clone();
+
+        Command::new(awk_command)
+            .arg("BEGIN { exit 0 }")
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false)
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(5),
+            memory_limit_bytes: 64 * 1024 * 1024,
+            max_output_bytes: 1024 * 1024,
+            fuel: 0,
+        }
+    }
+
+    #[test]
+    fn escape_round_trips_tabs_newlines_and_backslashes() {
+        let value = "a\tb\nc\\d\re\\n";
+
+        assert_eq!(escape(value), "a\\tb\\nc\\\\d\\re\\\\n");
+        assert_eq!(unescape(&escape(value)), value);
+        assert!(!escape(value).contains(['\t', '\n', '\r']));
+    }
+
+    #[test]
+    fn encode_inputs_writes_a_record_per_scalar_entry_and_item() {
+        let inputs = [
+            ("text", json!("a\tb")),
+            ("fields", json!({ "class": "x\ny" })),
+            ("items", json!(["1", 2])),
+            ("missing", Value::Null),
+        ];
+
+        assert_eq!(
+            encode_inputs(&inputs),
+            "S\ttext\ta\\tb\nM\tfields\tclass\tx\\ny\nA\titems\t1\nA\titems\t2\nS\tmissing\t\n"
+        );
+    }
+
+    #[test]
+    fn decode_outputs_reads_every_shape_and_empty_scalars_as_null() {
+        let outputs = [
+            ("text", Shape::Scalar),
+            ("empty", Shape::Scalar),
+            ("unset", Shape::Scalar),
+            ("fields", Shape::Map),
+            ("items", Shape::List),
+            ("none", Shape::List),
+        ];
+        let stdout = "S\ttext\ta\\tb\\\\\nS\tempty\t\nM\tfields\tclass\tx\\ny\nA\titems\t1\nA\titems\t2\n";
+
+        let result = decode_outputs(stdout, &outputs).unwrap();
+
+        assert_eq!(result.get("text"), Some(&json!("a\tb\\")));
+        assert_eq!(result.get("empty"), Some(&Value::Null));
+        assert_eq!(result.get("unset"), Some(&Value::Null));
+        assert_eq!(result.get("fields"), Some(&json!({ "class": "x\ny" })));
+        assert_eq!(result.get("items"), Some(&json!(["1", "2"])));
+        assert_eq!(result.get("none"), Some(&json!([])));
+    }
+
+    #[test]
+    fn execute_round_trips_every_shape() {
+        if !has_awk() {
+            return;
+        }
+
+        let text = "tab\tnewline\nbackslash\\";
+        let infix = "copy = text; for (key in fields) copied[key] = fields[key]; \
+            for (i = 1; i <= __items_length; i++) reversed[i] = items[__items_length - i + 1]; empty = \"\"";
+
+        let outputs = AwkRuntime.execute(
+            infix,
+            &[
+                ("text", json!(text)),
+                ("fields", json!({ "class": text, "id": "main" })),
+                ("items", json!(["first", text])),
+            ],
+            &[
+                ("copy", Shape::Scalar),
+                ("copied", Shape::Map),
+                ("reversed", Shape::List),
+                ("empty", Shape::Scalar),
+            ],
+            &limits(),
+        ).unwrap();
+
+        assert_eq!(outputs.get("copy"), Some(&json!(text)));
+        assert_eq!(outputs.get("copied"), Some(&json!({ "class": text, "id": "main" })));
+        assert_eq!(outputs.get("reversed"), Some(&json!([text, "first"])));
+        assert_eq!(outputs.get("empty"), Some(&Value::Null));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn execute_applies_memory_limit() {
+        if !has_awk() {
+            return;
+        }
+
+        let limits = limits();
+        let infix = "text = \"x\"; for (i = 0; i < 30; i++) text = text text; length_ = length(text)";
+
+        let result = AwkRuntime.execute(infix, &[], &[("length_", Shape::Scalar)], &limits);
+
+        assert!(matches!(result, Err(Errors::TransformationLimitError(_))));
+
+        let outputs = AwkRuntime.execute(
+            "doubled = text text",
+            &[("text", Value::String("ab".into()))],
+            &[("doubled", Shape::Scalar)],
+            &limits,
+        ).unwrap();
+
+        assert_eq!(outputs.get("doubled"), Some(&Value::String("abab".into())));
+    }
+}
@@ -0,0 +1,157 @@
+use serde_json::{Map, Value};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+use crate::transformation::Runtime;
+
+mod awk;
+mod native;
+mod nodejs;
+mod python;
+mod quickjs;
+mod wasm;
+mod worker;
+
+pub use native::{NativeTransformation, ValueOperation};
+pub use awk::AwkRuntime;
+pub use nodejs::NodeJsRuntime;
+pub use python::PythonRuntime;
+pub use quickjs::{QuickJsRuntime, serve_worker_if_requested as serve_quickjs_worker_if_requested};
+pub use wasm::WasmRuntime;
+
+/// The kind of value a transformation reads back from its runtime. Runtimes
+/// without typed values, such as AWK, need it to know how to emit a variable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    Scalar,
+    Map,
+    List,
+}
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub timeout: Duration,
+    pub memory_limit_bytes: usize,
+    pub max_output_bytes: usize,
+    pub fuel: u64,
+}
+
+impl Limits {
+    pub fn from_config() -> Self {
+        let lock = read_lock!(CONFIG);
+
+        Limits {
+            timeout: Duration::from_millis(lock.runtime.timeout_ms),
+            memory_limit_bytes: lock.runtime.memory_limit_mb * 1024 * 1024,
+            max_output_bytes: lock.runtime.max_output_bytes,
+            fuel: lock.runtime.wasm_fuel,
+        }
+    }
+
+    pub fn check_output_size(&self, size: usize) -> Result<(), Errors> {
+        if size > self.max_output_bytes {
+            log::error!("Transformation output of {} bytes exceeds the limit of {}", size, self.max_output_bytes);
+
+            return Err(Errors::TransformationLimitError(format!(
+                "Output exceeds {} bytes",
+                self.max_output_bytes
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn timed_out(&self) -> Errors {
+        log::error!("Transformation did not finish within {:?}", self.timeout);
+
+        Errors::TransformationLimitError(format!("Timed out after {:?}", self.timeout))
+    }
+}
+
+/// Something that can run a transformation's infix code. Every input is
+/// bound to a variable of the same name before the infix runs, and every
+/// output variable is read back afterwards. Implementations are responsible
+/// for their own prefix and suffix and for honouring the limits.
+pub trait TransformRuntime: Send + Sync {
+    fn execute(
+        &self,
+        infix: &str,
+        inputs: &[(&str, Value)],
+        outputs: &[(&str, Shape)],
+        limits: &Limits,
+    ) -> Result<Map<String, Value>, Errors>;
+}
+
+/// Runs transformations with a Rust function instead of code, so that tests
+/// do not depend on an interpreter being installed
+#[cfg(test)]
+pub struct FnRuntime(pub fn(&Map<String, Value>) -> Map<String, Value>);
+
+#[cfg(test)]
+impl TransformRuntime for FnRuntime {
+    fn execute(
+        &self,
+        _infix: &str,
+        inputs: &[(&str, Value)],
+        _outputs: &[(&str, Shape)],
+        _limits: &Limits,
+    ) -> Result<Map<String, Value>, Errors> {
+        let inputs: Map<String, Value> = inputs.iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+
+        Ok((self.0)(&inputs))
+    }
+}
+
+lazy_static! {
+    static ref RUNTIMES: RwLock<HashMap<String, Arc<dyn TransformRuntime>>> = {
+        let mut runtimes: HashMap<String, Arc<dyn TransformRuntime>> = HashMap::new();
+        runtimes.insert(Runtime::AWK.get_name(), Arc::new(AwkRuntime));
+        runtimes.insert(Runtime::NodeJS.get_name(), Arc::new(NodeJsRuntime));
+        runtimes.insert(Runtime::Python.get_name(), Arc::new(PythonRuntime));
+        runtimes.insert(Runtime::QuickJS.get_name(), Arc::new(QuickJsRuntime));
+        runtimes.insert(Runtime::Wasm.get_name(), Arc::new(WasmRuntime));
+
+        RwLock::new(runtimes)
+    };
+}
+
+/// Makes a runtime available to transformations under `name`, which they
+/// select with `Runtime::Custom(name)`. Registering a built-in name such as
+/// `QuickJS` replaces the built-in implementation.
+pub fn register_runtime(name: &str, runtime: Arc<dyn TransformRuntime>) {
+    log::info!("Registering transformation runtime {}", name);
+
+    write_lock!(RUNTIMES).insert(name.to_string(), runtime);
+}
+
+pub fn get_runtime(runtime: &Runtime) -> Option<Arc<dyn TransformRuntime>> {
+    read_lock!(RUNTIMES).get(&runtime.get_name()).cloned()
+}
+
+/// Runs a transformation's infix code on the runtime registered for it
+pub fn execute(
+    runtime: &Runtime,
+    infix: &str,
+    inputs: &[(&str, Value)],
+    outputs: &[(&str, Shape)],
+) -> Result<Map<String, Value>, Errors> {
+    log::trace!("In execute");
+
+    if let Runtime::Native = runtime {
+        return Err(Errors::TransformationError(
+            "Native transformations are applied from their spec, not from code".into()
+        ));
+    }
+
+    let implementation = get_runtime(runtime).ok_or_else(|| Errors::TransformationError(
+        format!("No runtime is registered as {}", runtime.get_name())
+    ))?;
+
+    implementation.execute(infix, inputs, outputs, &Limits::from_config())
+}
@@ -0,0 +1,340 @@
+use serde::{Serialize, Deserialize};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+
+/// A transformation expressed as data and applied in Rust, for the common
+/// cases that do not need a scripting runtime at all.
+///
+/// Element filters are checked against the original element name and drop
+/// the element together with everything beneath it. Renames are applied
+/// after filtering.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct NativeTransformation {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub element_blacklist: Vec<String>,
+    /// When not empty, elements not listed here are dropped
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub element_whitelist: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attribute_blacklist: Vec<String>,
+    /// When not empty, attributes not listed here are dropped
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attribute_whitelist: Vec<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub element_renames: HashMap<String, String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub attribute_renames: HashMap<String, String>,
+    /// When not empty, only these fields take part in hashing
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hash_fields: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hash_exclude: Vec<String>,
+    /// Hash `name=value` pairs rather than field names alone
+    pub hash_values: bool,
+}
+
+impl NativeTransformation {
+    pub fn transform_element(
+        &self,
+        element: String,
+        attributes: HashMap<String, String>,
+    ) -> (Option<String>, HashMap<String, String>) {
+        let is_dropped = self.element_blacklist.contains(&element) ||
+            (!self.element_whitelist.is_empty() && !self.element_whitelist.contains(&element));
+
+        let attributes: HashMap<String, String> = attributes
+            .into_iter()
+            .filter(|(key, _)| {
+                !self.attribute_blacklist.contains(key) &&
+                    (self.attribute_whitelist.is_empty() || self.attribute_whitelist.contains(key))
+            })
+            .map(|(key, value)| {
+                (self.attribute_renames.get(&key).cloned().unwrap_or(key), value)
+            })
+            .collect();
+
+        if is_dropped {
+            return (None, attributes);
+        }
+
+        let element = self.element_renames.get(&element).cloned().unwrap_or(element);
+
+        (Some(element), attributes)
+    }
+
+    /// The sorted items a node's hash is computed from
+    pub fn hash_items(&self, fields: &HashMap<String, String>) -> Vec<String> {
+        let mut items: Vec<String> = fields.iter()
+            .filter(|(key, _)| {
+                !self.hash_exclude.contains(key) &&
+                    (self.hash_fields.is_empty() || self.hash_fields.contains(key))
+            })
+            .map(|(key, value)| {
+                if self.hash_values {
+                    format!("{}={}", key, value)
+                } else {
+                    key.clone()
+                }
+            })
+            .collect();
+
+        items.sort();
+
+        items
+    }
+
+    /// Recognises the JavaScript idioms used by existing profiles: lookup
+    /// tables of element and attribute names used to null out the element
+    /// or filter the attributes, and hashing by sorted field names. Returns
+    /// `None` unless every statement of the script is understood.
+    pub fn from_js(code: &str) -> Option<Self> {
+        log::trace!("In from_js");
+
+        let mut remaining = code.to_string();
+        let mut tables: HashMap<String, Vec<String>> = HashMap::new();
+        let mut native = NativeTransformation::default();
+
+        let table = Regex::new(r"(?:const|let|var)\s+(\w+)\s*=\s*\{([^}]*)\}\s*;?").unwrap();
+        for captures in table.captures_iter(code) {
+            let keys: Vec<String> = captures[2].split(',')
+                .filter_map(|entry| entry.split(':').next())
+                .map(|key| key.trim().trim_matches(|c| c == '"' || c == '\'').to_string())
+                .filter(|key| !key.is_empty())
+                .collect();
+
+            tables.insert(captures[1].to_string(), keys);
+        }
+        remaining = table.replace_all(&remaining, "").to_string();
+
+        let element_filter = Regex::new(
+            r"if\s*\(\s*(!?)\s*(\w+)\[element\]\s*\)\s*element\s*=\s*null\s*;?"
+        ).unwrap();
+        for captures in element_filter.captures_iter(code) {
+            let names = tables.get(&captures[2])?.clone();
+
+            if &captures[1] == "!" {
+                native.element_whitelist.extend(names);
+            } else {
+                native.element_blacklist.extend(names);
+            }
+        }
+        remaining = element_filter.replace_all(&remaining, "").to_string();
+
+        let attribute_filter = Regex::new(
+            r"attributes\s*=\s*Object\.keys\(attributes\)\s*\.filter\(\s*\(?(\w+)\)?\s*=>\s*(!?)\s*(\w+)\[(\w+)\]\s*\)\s*\.reduce\(\s*\(\s*(\w+)\s*,\s*(\w+)\s*\)\s*=>\s*\{\s*(\w+)\[(\w+)\]\s*=\s*attributes\[(\w+)\]\s*;\s*return\s+(\w+)\s*;?\s*\}\s*,\s*\{\s*\}\s*\)\s*;?"
+        ).unwrap();
+        for captures in attribute_filter.captures_iter(code) {
+            let is_consistent = captures[1] == captures[4] &&
+                captures[5] == captures[7] && captures[7] == captures[10] &&
+                captures[6] == captures[8] && captures[8] == captures[9];
+
+            if !is_consistent {
+                return None;
+            }
+
+            let names = tables.get(&captures[3])?.clone();
+
+            if &captures[2] == "!" {
+                native.attribute_blacklist.extend(names);
+            } else {
+                native.attribute_whitelist.extend(names);
+            }
+        }
+        remaining = attribute_filter.replace_all(&remaining, "").to_string();
+
+        let hash_by_names = Regex::new(
+            r"(?:let|var|const)?\s*hasherItems\s*=\s*Object\.keys\(fields\)\.sort\(\)\s*;?"
+        ).unwrap();
+        remaining = hash_by_names.replace_all(&remaining, "").to_string();
+
+        if !remaining.trim().is_empty() {
+            log::debug!("Could not convert script to a native transformation, left over: {}", remaining.trim());
+            return None;
+        }
+
+        let used: HashSet<&String> = native.element_blacklist.iter()
+            .chain(native.element_whitelist.iter())
+            .chain(native.attribute_blacklist.iter())
+            .chain(native.attribute_whitelist.iter())
+            .collect();
+
+        if used.is_empty() && !tables.is_empty() {
+            log::debug!("Script declares lookup tables that are never applied");
+            return None;
+        }
+
+        Some(native)
+    }
+}
+
+/// A declarative rewrite of a single extracted value
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ValueOperation {
+    Trim,
+    /// Replaces runs of whitespace with a single space
+    CollapseWhitespace,
+    Lowercase,
+    Uppercase,
+    /// Reduces an amount such as `$1,234.50` or `1.234,50 €` to `1234.50`
+    NormalizeCurrency,
+    Replace {
+        pattern: String,
+        replacement: String,
+    },
+}
+
+impl ValueOperation {
+    pub fn validate(&self) -> Result<(), String> {
+        if let ValueOperation::Replace { pattern, .. } = self {
+            Regex::new(pattern).map_err(|err| format!("Invalid pattern {}: {}", pattern, err))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn apply(&self, value: &str) -> Result<String, String> {
+        match self {
+            ValueOperation::Trim => Ok(value.trim().to_string()),
+            ValueOperation::CollapseWhitespace => {
+                Ok(value.split_whitespace().collect::<Vec<&str>>().join(" "))
+            },
+            ValueOperation::Lowercase => Ok(value.to_lowercase()),
+            ValueOperation::Uppercase => Ok(value.to_uppercase()),
+            ValueOperation::NormalizeCurrency => normalize_currency(value),
+            ValueOperation::Replace { pattern, replacement } => {
+                let regex = Regex::new(pattern)
+                    .map_err(|err| format!("Invalid pattern {}: {}", pattern, err))?;
+
+                Ok(regex.replace_all(value, replacement.as_str()).to_string())
+            },
+        }
+    }
+}
+
+/// The last of `.` and `,` is taken as the decimal separator when both
+/// appear. A lone separator is decimal only when followed by one or two
+/// digits, so `1,234` reads as a thousand and `12,5` as a fraction.
+fn normalize_currency(value: &str) -> Result<String, String> {
+    let is_negative = value.contains('-') || (value.contains('(') && value.contains(')'));
+    let amount: String = value.chars()
+        .filter(|c| c.is_ascii_digit() || *c == '.' || *c == ',')
+        .collect();
+
+    if !amount.chars().any(|c| c.is_ascii_digit()) {
+        return Err(format!("No amount in {:?}", value));
+    }
+
+    let decimal_separator = match (amount.rfind('.'), amount.rfind(',')) {
+        (Some(dot), Some(comma)) => Some(dot.max(comma)),
+        (Some(index), None) | (None, Some(index)) => {
+            let fraction = amount.len() - index - 1;
+            let is_single = amount.matches(['.', ',']).count() == 1;
+
+            if is_single && (1..=2).contains(&fraction) {
+                Some(index)
+            } else {
+                None
+            }
+        },
+        (None, None) => None,
+    };
+
+    let (whole, fraction) = match decimal_separator {
+        Some(index) => (&amount[..index], &amount[index + 1..]),
+        None => (amount.as_str(), ""),
+    };
+
+    let whole: String = whole.chars().filter(|c| c.is_ascii_digit()).collect();
+    let whole = if whole.is_empty() { String::from("0") } else { whole };
+    let sign = if is_negative { "-" } else { "" };
+
+    if fraction.is_empty() {
+        Ok(format!("{}{}", sign, whole))
+    } else {
+        Ok(format!("{}{}.{}", sign, whole, fraction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An infix of the first profile in the bundled provider file
+    fn provider_infix(transformation: &str) -> String {
+        let provider: serde_yaml::Value = serde_yaml::from_str(include_str!("../../provider.yaml")).unwrap();
+
+        provider["profiles"][0][transformation]["infix"].as_str().unwrap().to_string()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn from_js_converts_the_provider_element_transformation() {
+        let native = NativeTransformation::from_js(&provider_infix("xml_element_transformation")).unwrap();
+
+        assert_eq!(native, NativeTransformation {
+            element_blacklist: strings(&["script", "meta", "link", "iframe", "svg", "style", "noscript"]),
+            attribute_blacklist: strings(&[
+                "style", "bgcolor", "border", "cellpadding", "cellspacing", "width", "height", "rows",
+                "cols", "wrap", "aria-hidden", "size", "op", "lang", "colspan", "rel",
+            ]),
+            ..NativeTransformation::default()
+        });
+
+        let attributes = HashMap::from([
+            (String::from("class"), String::from("title")),
+            (String::from("style"), String::from("color: red")),
+            (String::from("aria-hidden"), String::from("true")),
+        ]);
+
+        assert_eq!(
+            native.transform_element(String::from("span"), attributes.clone()),
+            (Some(String::from("span")), HashMap::from([(String::from("class"), String::from("title"))]))
+        );
+        assert_eq!(native.transform_element(String::from("script"), attributes).0, None);
+    }
+
+    #[test]
+    fn from_js_converts_the_provider_hash_transformation() {
+        let native = NativeTransformation::from_js(&provider_infix("hash_transformation")).unwrap();
+
+        assert_eq!(native, NativeTransformation::default());
+
+        let fields = HashMap::from([
+            (String::from("tag"), String::from("a")),
+            (String::from("href"), String::from("/item")),
+            (String::from("class"), String::from("title")),
+        ]);
+
+        assert_eq!(native.hash_items(&fields), strings(&["class", "href", "tag"]));
+    }
+
+    #[test]
+    fn normalize_currency_reads_either_decimal_separator() {
+        assert_eq!(normalize_currency("$1,234.50"), Ok(String::from("1234.50")));
+        assert_eq!(normalize_currency("1.234,50 €"), Ok(String::from("1234.50")));
+        assert_eq!(normalize_currency("(12.00)"), Ok(String::from("-12.00")));
+        assert_eq!(normalize_currency("1,234"), Ok(String::from("1234")));
+        assert_eq!(normalize_currency("12,5"), Ok(String::from("12.5")));
+        assert!(normalize_currency("free").is_err());
+    }
+
+    #[test]
+    fn from_js_rejects_unsupported_scripts() {
+        assert_eq!(NativeTransformation::from_js("element = element.toUpperCase();"), None);
+        assert_eq!(
+            NativeTransformation::from_js("const TABLE = {a:1}; if (TABLE[element]) element = null; console.log(element);"),
+            None
+        );
+        assert_eq!(NativeTransformation::from_js("const UNUSED = {a:1};"), None);
+        assert_eq!(
+            NativeTransformation::from_js(
+                "const T = {a:1}; attributes = Object.keys(attributes).filter(item => !T[other]).reduce((acc, key) => { acc[key] = attributes[key]; return acc; }, {});"
+            ),
+            None
+        );
+    }
+}
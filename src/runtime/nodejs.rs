@@ -0,0 +1,155 @@
+use serde_json::{Map, Value};
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+use super::{worker, Limits, Shape, TransformRuntime};
+
+/// Compiles each distinct infix once into a function taking the inputs as
+/// parameters and returning the outputs. `console.log` goes to stderr so that
+/// it cannot corrupt the protocol, and `require` resolves modules from the
+/// working directory.
+const WORKER_SCRIPT: &str = r#"
+const readline = require('readline');
+globalThis.require = require;
+console.log = console.error;
+const cache = new Map();
+const respond = (response) => process.stdout.write(JSON.stringify(response) + '\n');
+readline.createInterface({ input: process.stdin }).on('line', (line) => {
+  try {
+    const request = JSON.parse(line);
+    const names = Object.keys(request.inputs);
+    const key = JSON.stringify([names, request.outputs, request.code]);
+    let fn = cache.get(key);
+    if (!fn) {
+      fn = new Function(...names, `${request.code}\nreturn { ${request.outputs.join(', ')} };`);
+      cache.set(key, fn);
+    }
+    respond({ outputs: fn(...names.map((name) => request.inputs[name])) });
+  } catch (err) {
+    respond({ error: String((err && err.stack) || err) });
+  }
+});
+"#;
+
+pub struct NodeJsRuntime;
+
+impl TransformRuntime for NodeJsRuntime {
+    fn execute(
+        &self,
+        infix: &str,
+        inputs: &[(&str, Value)],
+        outputs: &[(&str, Shape)],
+        limits: &Limits,
+    ) -> Result<Map<String, Value>, Errors> {
+        log::trace!("In nodejs/execute");
+
+        let command = read_lock!(CONFIG).runtime.node_command.clone();
+        let heap_limit = format!("--max-old-space-size={}", limits.memory_limit_bytes / (1024 * 1024));
+
+        worker::execute("nodejs", &command, &[&heap_limit, "-e", WORKER_SCRIPT], infix, inputs, outputs, limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::process::Command;
+    use std::time::Duration;
+
+    fn has_node() -> bool {
+        let command = read_lock!(CONFIG).runtime.node_command.clone();
+
+        Command::new(command)
+            .arg("--version")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+
+    /// Workers are pooled by command line, so each test picks its own memory
+    /// limit to get workers of its own
+    fn limits(memory_limit_mb: usize, timeout: Duration) -> Limits {
+        Limits {
+            timeout,
+            memory_limit_bytes: memory_limit_mb * 1024 * 1024,
+            max_output_bytes: 1024 * 1024,
+            fuel: 0,
+        }
+    }
+
+    #[test]
+    fn execute_passes_inputs_and_outputs_as_json() {
+        if !has_node() {
+            return;
+        }
+
+        let text = "it's \"quoted\", a \\ and a\nnewline";
+        let outputs = NodeJsRuntime.execute(
+            "const upper = text.toUpperCase(); const keys = Object.keys(fields); const first = items[0]; console.log('ignored');",
+            &[
+                ("text", json!(text)),
+                ("fields", json!({ "class": "a" })),
+                ("items", json!([1, 2])),
+            ],
+            &[("upper", Shape::Scalar), ("keys", Shape::List), ("first", Shape::Scalar)],
+            &limits(257, Duration::from_secs(10)),
+        ).unwrap();
+
+        assert_eq!(outputs.get("upper"), Some(&json!(text.to_uppercase())));
+        assert_eq!(outputs.get("keys"), Some(&json!(["class"])));
+        assert_eq!(outputs.get("first"), Some(&json!(1)));
+
+        let result = NodeJsRuntime.execute(
+            "throw new Error('broken');",
+            &[],
+            &[("value", Shape::Scalar)],
+            &limits(257, Duration::from_secs(10)),
+        );
+
+        assert!(matches!(result, Err(Errors::TransformationError(message)) if message.contains("broken")));
+    }
+
+    #[test]
+    fn execute_reuses_the_worker() {
+        if !has_node() {
+            return;
+        }
+
+        let count = || NodeJsRuntime.execute(
+            "globalThis.calls = (globalThis.calls || 0) + 1; const calls = globalThis.calls;",
+            &[],
+            &[("calls", Shape::Scalar)],
+            &limits(258, Duration::from_secs(10)),
+        ).unwrap().get("calls").cloned();
+
+        let first = count().and_then(|calls| calls.as_u64()).unwrap();
+
+        assert_eq!(count(), Some(json!(first + 1)));
+    }
+
+    #[test]
+    fn execute_replaces_a_worker_that_timed_out() {
+        if !has_node() {
+            return;
+        }
+
+        let result = NodeJsRuntime.execute(
+            "while (true) {}",
+            &[],
+            &[("value", Shape::Scalar)],
+            &limits(259, Duration::from_millis(500)),
+        );
+
+        assert!(matches!(result, Err(Errors::TransformationLimitError(_))));
+
+        let outputs = NodeJsRuntime.execute(
+            "const value = 1;",
+            &[],
+            &[("value", Shape::Scalar)],
+            &limits(259, Duration::from_secs(10)),
+        ).unwrap();
+
+        assert_eq!(outputs.get("value"), Some(&json!(1)));
+    }
+}
@@ -0,0 +1,161 @@
+use serde_json::{Map, Value};
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+use super::{worker, Limits, Shape, TransformRuntime};
+
+/// Compiles each distinct infix once and executes it in a fresh namespace
+/// holding the inputs, reading the outputs back from that namespace. `print`
+/// goes to stderr so that it cannot corrupt the protocol. The address space
+/// limit in bytes is passed as the first argument.
+const WORKER_SCRIPT: &str = r#"
+import json, resource, sys, traceback
+memory_limit = int(sys.argv[1])
+resource.setrlimit(resource.RLIMIT_AS, (memory_limit, memory_limit))
+out = sys.stdout
+sys.stdout = sys.stderr
+cache = {}
+while True:
+    line = sys.stdin.readline()
+    if not line:
+        break
+    try:
+        request = json.loads(line)
+        code = cache.get(request["code"])
+        if code is None:
+            code = compile(request["code"], "<transformation>", "exec")
+            cache[request["code"]] = code
+        scope = dict(request["inputs"])
+        exec(code, scope)
+        response = json.dumps({"outputs": {name: scope.get(name) for name in request["outputs"]}}, default=str)
+    except MemoryError:
+        response = json.dumps({"error": "MemoryError", "limit": True})
+    except Exception:
+        response = json.dumps({"error": traceback.format_exc()})
+    out.write(response + "\n")
+    out.flush()
+"#;
+
+pub struct PythonRuntime;
+
+impl TransformRuntime for PythonRuntime {
+    fn execute(
+        &self,
+        infix: &str,
+        inputs: &[(&str, Value)],
+        outputs: &[(&str, Shape)],
+        limits: &Limits,
+    ) -> Result<Map<String, Value>, Errors> {
+        log::trace!("In python/execute");
+
+        let command = read_lock!(CONFIG).runtime.python_command.clone();
+        let memory_limit = limits.memory_limit_bytes.to_string();
+
+        worker::execute("python", &command, &["-u", "-c", WORKER_SCRIPT, &memory_limit], infix, inputs, outputs, limits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::process::Command;
+    use std::time::Duration;
+
+    fn has_python() -> bool {
+        let command = read_lock!(CONFIG).runtime.python_command.clone();
+
+        Command::new(command)
+            .arg("--version")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+
+    /// Workers are pooled by command line, so each test picks its own memory
+    /// limit to get workers of its own
+    fn limits(memory_limit_mb: usize, timeout: Duration) -> Limits {
+        Limits {
+            timeout,
+            memory_limit_bytes: memory_limit_mb * 1024 * 1024,
+            max_output_bytes: 1024 * 1024,
+            fuel: 0,
+        }
+    }
+
+    #[test]
+    fn execute_passes_inputs_and_outputs_as_json() {
+        if !has_python() {
+            return;
+        }
+
+        let text = "it's \"quoted\", a \\ and a\nnewline";
+        let outputs = PythonRuntime.execute(
+            "upper = text.upper()\nkeys = list(fields)\nfirst = items[0]\nprint('ignored')",
+            &[
+                ("text", json!(text)),
+                ("fields", json!({ "class": "a" })),
+                ("items", json!([1, 2])),
+            ],
+            &[("upper", Shape::Scalar), ("keys", Shape::List), ("first", Shape::Scalar), ("unset", Shape::Scalar)],
+            &limits(1025, Duration::from_secs(10)),
+        ).unwrap();
+
+        assert_eq!(outputs.get("upper"), Some(&json!(text.to_uppercase())));
+        assert_eq!(outputs.get("keys"), Some(&json!(["class"])));
+        assert_eq!(outputs.get("first"), Some(&json!(1)));
+        assert_eq!(outputs.get("unset"), Some(&Value::Null));
+
+        let result = PythonRuntime.execute(
+            "raise ValueError('broken')",
+            &[],
+            &[("value", Shape::Scalar)],
+            &limits(1025, Duration::from_secs(10)),
+        );
+
+        assert!(matches!(result, Err(Errors::TransformationError(message)) if message.contains("broken")));
+    }
+
+    #[test]
+    fn execute_reuses_the_worker() {
+        if !has_python() {
+            return;
+        }
+
+        let count = || PythonRuntime.execute(
+            "import sys\nsys.calls = getattr(sys, 'calls', 0) + 1\ncalls = sys.calls",
+            &[],
+            &[("calls", Shape::Scalar)],
+            &limits(1026, Duration::from_secs(10)),
+        ).unwrap().get("calls").cloned();
+
+        let first = count().and_then(|calls| calls.as_u64()).unwrap();
+
+        assert_eq!(count(), Some(json!(first + 1)));
+    }
+
+    #[test]
+    fn execute_replaces_a_worker_that_timed_out() {
+        if !has_python() {
+            return;
+        }
+
+        let result = PythonRuntime.execute(
+            "while True:\n    pass",
+            &[],
+            &[("value", Shape::Scalar)],
+            &limits(1027, Duration::from_millis(500)),
+        );
+
+        assert!(matches!(result, Err(Errors::TransformationLimitError(_))));
+
+        let outputs = PythonRuntime.execute(
+            "value = 1",
+            &[],
+            &[("value", Shape::Scalar)],
+            &limits(1027, Duration::from_secs(10)),
+        ).unwrap();
+
+        assert_eq!(outputs.get("value"), Some(&json!(1)));
+    }
+}
@@ -0,0 +1,408 @@
+use serde_json::{json, Map, Value};
+use quick_js::{Context as QuickContext, ExecutionError, JsValue};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+use super::{worker, Limits, Shape, TransformRuntime};
+
+/// First argument of a process started as a QuickJS worker, followed by the
+/// memory limit in bytes
+pub const WORKER_ARGUMENT: &str = "--quickjs-worker";
+
+/// Transformations compiled into functions of a context
+struct ContextPool {
+    context: QuickContext,
+    /// Function name for each compiled transformation, keyed by its
+    /// parameters, outputs and code
+    functions: HashMap<String, String>,
+}
+
+/// Past this many distinct transformations the context is replaced, so that
+/// a long run over many profiles does not grow it without bound
+const MAX_COMPILED_FUNCTIONS: usize = 256;
+
+impl ContextPool {
+    fn new(memory_limit_bytes: usize) -> Result<Self, Errors> {
+        log::debug!("Creating QuickJS context");
+
+        let context = QuickContext::builder()
+            .memory_limit(memory_limit_bytes)
+            .build()
+            .map_err(|err| {
+                log::error!("Could not create QuickJS context: {}", err);
+                Errors::TransformationError(format!("Could not create QuickJS context: {}", err))
+            })?;
+
+        Ok(ContextPool {
+            context,
+            functions: HashMap::new(),
+        })
+    }
+
+    /// Compiles the infix into a function taking the inputs as parameters
+    /// and returning the outputs as JSON, unless it already was
+    fn compile(
+        &mut self,
+        infix: &str,
+        parameters: &[&str],
+        results: &[&str],
+    ) -> Result<String, Errors> {
+        let key = format!("{}\n{}\n{}", parameters.join(","), results.join(","), infix);
+
+        if let Some(name) = self.functions.get(&key) {
+            return Ok(name.clone());
+        }
+
+        let name = format!("__transformation_{}", self.functions.len());
+        let definition = format!(
+            "function {}({}) {{\n{}\nreturn JSON.stringify({{ {} }});\n}}",
+            name,
+            parameters.join(", "),
+            infix,
+            results.join(", ")
+        );
+
+        self.context.eval(&definition).map_err(|err| {
+            to_error("Could not compile transformation", err)
+        })?;
+
+        self.functions.insert(key, name.clone());
+
+        Ok(name)
+    }
+}
+
+lazy_static! {
+    /// Idle in-process QuickJS threads. A thread is taken out for the
+    /// duration of a call, so that concurrent calls do not wait for one.
+    static ref THREADS: Mutex<Vec<ContextThread>> = Mutex::new(Vec::new());
+}
+
+struct Request {
+    infix: String,
+    inputs: Map<String, Value>,
+    results: Vec<String>,
+    memory_limit_bytes: usize,
+}
+
+/// A thread owning its own context pool, so that compiled transformations
+/// are reused across calls
+struct ContextThread {
+    requests: Sender<Request>,
+    responses: Receiver<Result<String, Errors>>,
+}
+
+impl ContextThread {
+    fn spawn() -> Result<Self, Errors> {
+        log::debug!("Starting QuickJS thread");
+
+        let (request_sender, requests) = mpsc::channel::<Request>();
+        let (response_sender, responses) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("quickjs".into())
+            .spawn(move || {
+                let mut pool: Option<ContextPool> = None;
+                let mut memory_limit_bytes = 0;
+
+                for request in requests {
+                    if request.memory_limit_bytes != memory_limit_bytes {
+                        pool = None;
+                        memory_limit_bytes = request.memory_limit_bytes;
+                    }
+
+                    let results: Vec<&str> = request.results.iter().map(String::as_str).collect();
+                    let response = run(&mut pool, memory_limit_bytes, &request.infix, &request.inputs, &results);
+
+                    if response_sender.send(response).is_err() {
+                        break;
+                    }
+                }
+            })
+            .map_err(|err| {
+                log::error!("Could not start QuickJS thread: {}", err);
+                Errors::TransformationError(format!("Could not start QuickJS thread: {}", err))
+            })?;
+
+        Ok(ContextThread {
+            requests: request_sender,
+            responses,
+        })
+    }
+
+    fn call(&self, request: Request, limits: &Limits) -> Result<String, Errors> {
+        let stopped = || Errors::TransformationError("QuickJS thread stopped unexpectedly".into());
+
+        self.requests.send(request).map_err(|_| stopped())?;
+
+        match self.responses.recv_timeout(limits.timeout) {
+            Ok(response) => response,
+            Err(RecvTimeoutError::Timeout) => Err(limits.timed_out()),
+            Err(RecvTimeoutError::Disconnected) => Err(stopped()),
+        }
+    }
+}
+
+/// Scripts run in a worker process if `runtime.quickjs_command` is set, so
+/// that one exceeding its timeout can be killed. The worker is started with
+/// `WORKER_ARGUMENT` and has to call `serve_worker_if_requested` before doing
+/// anything else. Otherwise they run on threads of this process; a thread
+/// whose script times out is abandoned and keeps running until the script
+/// ends, as QuickJS offers no way to interrupt it.
+/// Inputs are passed as function arguments through the QuickJS API rather
+/// than spliced into the source, so values with quotes or newlines cannot
+/// break out of the script.
+pub struct QuickJsRuntime;
+
+impl TransformRuntime for QuickJsRuntime {
+    fn execute(
+        &self,
+        infix: &str,
+        inputs: &[(&str, Value)],
+        outputs: &[(&str, Shape)],
+        limits: &Limits,
+    ) -> Result<Map<String, Value>, Errors> {
+        log::trace!("In quickjs/execute");
+
+        let (command, max_threads) = {
+            let config = read_lock!(CONFIG);
+            (config.runtime.quickjs_command.clone(), config.runtime.max_workers)
+        };
+
+        if let Some(command) = command {
+            let memory_limit = limits.memory_limit_bytes.to_string();
+
+            return worker::execute("quickjs", &command, &[WORKER_ARGUMENT, &memory_limit], infix, inputs, outputs, limits);
+        }
+
+        let request = Request {
+            infix: infix.to_string(),
+            inputs: inputs.iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            results: outputs.iter().map(|(name, _)| name.to_string()).collect(),
+            memory_limit_bytes: limits.memory_limit_bytes,
+        };
+
+        let idle = THREADS.lock().map_err(|_| {
+            Errors::TransformationError("QuickJS thread pool is poisoned".into())
+        })?.pop();
+
+        let context_thread = match idle {
+            Some(context_thread) => context_thread,
+            None => ContextThread::spawn()?,
+        };
+
+        // A thread that failed is dropped, which stops it once its script ends
+        let result = context_thread.call(request, limits)?;
+
+        {
+            let mut threads = THREADS.lock().map_err(|_| {
+                Errors::TransformationError("QuickJS thread pool is poisoned".into())
+            })?;
+
+            if threads.len() < max_threads {
+                threads.push(context_thread);
+            }
+        }
+
+        limits.check_output_size(result.len())?;
+
+        let results = parse_result(&result)?;
+
+        Ok(outputs.iter()
+            .map(|(name, _)| {
+                (name.to_string(), results.get(*name).cloned().unwrap_or(Value::Null))
+            })
+            .collect())
+    }
+}
+
+/// Serves worker requests on stdin until it closes if the process was
+/// started with `WORKER_ARGUMENT`, and returns whether it was
+pub fn serve_worker_if_requested() -> bool {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.get(1).map(String::as_str) != Some(WORKER_ARGUMENT) {
+        return false;
+    }
+
+    let memory_limit_bytes: usize = args.get(2)
+        .and_then(|limit| limit.parse().ok())
+        .expect("QuickJS worker requires a memory limit in bytes");
+
+    let mut pool: Option<ContextPool> = None;
+    let mut stdout = io::stdout();
+
+    for line in io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        let response = match serve(&mut pool, memory_limit_bytes, &line) {
+            Ok(outputs) => json!({ "outputs": outputs }),
+            Err(Errors::TransformationLimitError(message)) => json!({ "error": message, "limit": true }),
+            Err(Errors::TransformationError(message)) |
+            Err(Errors::TransformationOutputError(message)) => json!({ "error": message }),
+            Err(err) => json!({ "error": format!("{:?}", err) }),
+        };
+
+        if writeln!(stdout, "{}", response).and_then(|_| stdout.flush()).is_err() {
+            break;
+        }
+    }
+
+    true
+}
+
+/// Runs one request line on the worker, returning its outputs
+fn serve(pool: &mut Option<ContextPool>, memory_limit_bytes: usize, line: &str) -> Result<Map<String, Value>, Errors> {
+    let request: Value = serde_json::from_str(line).map_err(|err| {
+        Errors::TransformationError(format!("Could not parse request: {}", err))
+    })?;
+
+    let infix = request.get("code").and_then(|code| code.as_str()).unwrap_or_default();
+    let inputs = request.get("inputs").and_then(|inputs| inputs.as_object()).cloned().unwrap_or_default();
+    let results: Vec<&str> = request.get("outputs")
+        .and_then(|outputs| outputs.as_array())
+        .map(|outputs| outputs.iter().filter_map(|output| output.as_str()).collect())
+        .unwrap_or_default();
+
+    let result = run(pool, memory_limit_bytes, infix, &inputs, &results)?;
+
+    parse_result(&result)
+}
+
+/// Runs the infix in the pool's context, returning its outputs as JSON
+fn run(
+    pool: &mut Option<ContextPool>,
+    memory_limit_bytes: usize,
+    infix: &str,
+    inputs: &Map<String, Value>,
+    results: &[&str],
+) -> Result<String, Errors> {
+    let is_full = pool.as_ref()
+        .map(|pool| pool.functions.len() >= MAX_COMPILED_FUNCTIONS)
+        .unwrap_or(true);
+
+    if is_full {
+        *pool = Some(ContextPool::new(memory_limit_bytes)?);
+    }
+
+    let context_pool = pool.as_mut().expect("Context pool was just created");
+    let parameters: Vec<&str> = inputs.keys().map(|name| name.as_str()).collect();
+    let name = context_pool.compile(infix, &parameters, results)?;
+
+    let arguments: Vec<JsValue> = inputs.values().map(to_js_value).collect();
+
+    let result = context_pool.context.call_function(&name, arguments).map_err(|err| {
+        // A context that ran out of memory is not reused
+        if let ExecutionError::OutOfMemory = err {
+            *pool = None;
+        }
+
+        to_error("QuickJS evaluation failed", err)
+    })?;
+
+    match result {
+        JsValue::String(result) => Ok(result),
+        other => Err(Errors::TransformationOutputError(
+            format!("QuickJS returned {:?} instead of a JSON string", other)
+        )),
+    }
+}
+
+fn parse_result(result: &str) -> Result<Map<String, Value>, Errors> {
+    serde_json::from_str(result).map_err(|err| {
+        Errors::TransformationOutputError(format!("Could not parse QuickJS result: {}", err))
+    })
+}
+
+fn to_error(message: &str, err: ExecutionError) -> Errors {
+    match err {
+        ExecutionError::OutOfMemory => Errors::TransformationLimitError(
+            format!("{}: memory limit exceeded", message)
+        ),
+        err => Errors::TransformationError(format!("{}: {}", message, err)),
+    }
+}
+
+fn to_js_value(value: &Value) -> JsValue {
+    match value {
+        Value::Null => JsValue::Null,
+        Value::Bool(value) => JsValue::Bool(*value),
+        Value::Number(number) => {
+            match number.as_i64().and_then(|value| i32::try_from(value).ok()) {
+                Some(value) => JsValue::Int(value),
+                None => JsValue::Float(number.as_f64().unwrap_or(f64::NAN)),
+            }
+        },
+        Value::String(value) => JsValue::String(value.clone()),
+        Value::Array(array) => JsValue::Array(array.iter().map(to_js_value).collect()),
+        Value::Object(object) => JsValue::Object(
+            object.iter()
+                .map(|(key, value)| (key.clone(), to_js_value(value)))
+                .collect()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(5),
+            memory_limit_bytes: 64 * 1024 * 1024,
+            max_output_bytes: 1024,
+            fuel: 0,
+        }
+    }
+
+    #[test]
+    fn execute_runs_in_process_without_a_worker_command() {
+        let value = "it's a \\ and a\nnewline";
+        let outputs = QuickJsRuntime.execute(
+            "let length = text.length; let copy = text;",
+            &[("text", json!(value))],
+            &[("copy", Shape::Scalar), ("length", Shape::Scalar)],
+            &limits(),
+        ).unwrap();
+
+        assert_eq!(outputs.get("copy"), Some(&json!(value)));
+        assert_eq!(outputs.get("length"), Some(&json!(value.chars().count())));
+    }
+
+    #[test]
+    fn execute_rejects_oversized_output() {
+        let result = QuickJsRuntime.execute(
+            "let text = 'x'.repeat(2048);",
+            &[],
+            &[("text", Shape::Scalar)],
+            &limits(),
+        );
+
+        assert!(matches!(result, Err(Errors::TransformationLimitError(_))));
+    }
+
+    #[test]
+    fn execute_reports_script_errors() {
+        let result = QuickJsRuntime.execute(
+            "let value = undefined_function();",
+            &[],
+            &[("value", Shape::Scalar)],
+            &limits(),
+        );
+
+        assert!(matches!(result, Err(Errors::TransformationError(_))));
+    }
+}
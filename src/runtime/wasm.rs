@@ -0,0 +1,255 @@
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use wasmi::core::TrapCode;
+use wasmi::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::prelude::*;
+use super::{Limits, Shape, TransformRuntime};
+
+lazy_static! {
+    static ref ENGINE: Engine = {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        Engine::new(&config)
+    };
+    /// Compiled modules keyed by a digest of their bytes
+    static ref MODULES: Mutex<HashMap<String, Arc<Module>>> = Mutex::new(HashMap::new());
+}
+
+/// Runs a WebAssembly module against the JSON ABI shared by all shapes.
+///
+/// The module must import nothing, which leaves it without any I/O, and must
+/// export `memory`, `alloc(len: i32) -> i32` and
+/// `transform(ptr: i32, len: i32) -> i64`. The host calls `alloc` for the
+/// input, writes a UTF-8 JSON object holding each input by name and calls
+/// `transform`, which returns the pointer to its UTF-8 JSON output in the
+/// high 32 bits and the length in the low 32 bits. The output is an object
+/// holding each output by name. For example an XML element transformation
+/// receives `{"element", "attributes"}` and returns the same, and a hash
+/// transformation receives `{"fields"}` and returns `{"hasherItems"}`.
+///
+/// Each call runs in a fresh instance, limited by fuel rather than wall clock
+/// and with its linear memory capped by the memory limit.
+pub struct WasmRuntime;
+
+impl TransformRuntime for WasmRuntime {
+    fn execute(
+        &self,
+        infix: &str,
+        inputs: &[(&str, Value)],
+        outputs: &[(&str, Shape)],
+        limits: &Limits,
+    ) -> Result<Map<String, Value>, Errors> {
+        log::trace!("In wasm/execute");
+
+        let module = get_module(infix)?;
+
+        let mut store: Store<StoreLimits> = Store::new(
+            &ENGINE,
+            StoreLimitsBuilder::new().memory_size(limits.memory_limit_bytes).build(),
+        );
+        store.limiter(|limits| limits);
+        store.set_fuel(limits.fuel).map_err(|err| {
+            Errors::TransformationError(format!("Could not set fuel: {}", err))
+        })?;
+
+        let instance = Linker::<StoreLimits>::new(&ENGINE)
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(|err| to_error("Could not instantiate WASM module", err, limits))?;
+
+        let memory = instance.get_memory(&store, "memory").ok_or_else(|| {
+            Errors::TransformationError("WASM module does not export memory".into())
+        })?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(|err| to_error("WASM module does not export alloc", err, limits))?;
+        let transform = instance.get_typed_func::<(i32, i32), i64>(&store, "transform")
+            .map_err(|err| to_error("WASM module does not export transform", err, limits))?;
+
+        let input: Map<String, Value> = inputs.iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        let input = Value::Object(input).to_string();
+
+        let input_len = i32::try_from(input.len()).map_err(|_| {
+            Errors::TransformationLimitError("Input is too large for a WASM module".into())
+        })?;
+        let input_ptr = alloc.call(&mut store, input_len)
+            .map_err(|err| to_error("WASM alloc failed", err, limits))?;
+
+        memory.write(&mut store, input_ptr as u32 as usize, input.as_bytes()).map_err(|err| {
+            Errors::TransformationError(format!("Could not write input to WASM memory: {}", err))
+        })?;
+
+        let packed = transform.call(&mut store, (input_ptr, input_len))
+            .map_err(|err| to_error("WASM transformation failed", err, limits))?;
+
+        let output_ptr = (packed as u64 >> 32) as usize;
+        let output_len = (packed as u64 & 0xffff_ffff) as usize;
+
+        limits.check_output_size(output_len)?;
+
+        let mut output = vec![0u8; output_len];
+        memory.read(&store, output_ptr, &mut output).map_err(|err| {
+            Errors::TransformationOutputError(format!("WASM output is out of bounds: {}", err))
+        })?;
+
+        let parsed: Map<String, Value> = serde_json::from_slice(&output).map_err(|err| {
+            log::error!("Could not parse WASM output: {}", err);
+            Errors::TransformationOutputError(format!("Could not parse WASM output: {}", err))
+        })?;
+
+        Ok(outputs.iter()
+            .map(|(name, _)| {
+                (name.to_string(), parsed.get(*name).cloned().unwrap_or(Value::Null))
+            })
+            .collect())
+    }
+}
+
+/// The module bytes are either base64, ignoring whitespace, or read from
+/// the path following `file:`
+fn get_module(infix: &str) -> Result<Arc<Module>, Errors> {
+    let infix = infix.trim();
+
+    let bytes = if let Some(path) = infix.strip_prefix("file:") {
+        std::fs::read(path.trim()).map_err(|err| {
+            log::error!("Could not read WASM module {}: {}", path, err);
+            Errors::TransformationError(format!("Could not read WASM module {}: {}", path, err))
+        })?
+    } else {
+        let encoded: String = infix.chars().filter(|c| !c.is_whitespace()).collect();
+
+        BASE64_STANDARD.decode(encoded).map_err(|err| {
+            log::error!("Could not decode WASM module: {}", err);
+            Errors::TransformationError(format!("Could not decode WASM module: {}", err))
+        })?
+    };
+
+    let key = format!("{:x}", Sha256::digest(&bytes));
+
+    let mut modules = MODULES.lock().map_err(|_| {
+        Errors::TransformationError("WASM module cache is poisoned".into())
+    })?;
+
+    if let Some(module) = modules.get(&key) {
+        return Ok(Arc::clone(module));
+    }
+
+    log::debug!("Compiling WASM module {}", key);
+
+    let module = Arc::new(Module::new(&ENGINE, &bytes[..]).map_err(|err| {
+        log::error!("Could not compile WASM module: {}", err);
+        Errors::TransformationError(format!("Could not compile WASM module: {}", err))
+    })?);
+
+    modules.insert(key, Arc::clone(&module));
+
+    Ok(module)
+}
+
+fn to_error(message: &str, err: impl Into<wasmi::Error>, limits: &Limits) -> Errors {
+    let err: wasmi::Error = err.into();
+
+    log::error!("{}: {}", message, err);
+
+    match err.as_trap_code() {
+        Some(TrapCode::OutOfFuel) => Errors::TransformationLimitError(
+            format!("{}: exhausted {} units of fuel", message, limits.fuel)
+        ),
+        Some(TrapCode::GrowthOperationLimited) => Errors::TransformationLimitError(
+            format!("{}: memory limit exceeded", message)
+        ),
+        _ => Errors::TransformationError(format!("{}: {}", message, err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    /// `alloc` always returns offset 1024
+    const ALLOC: &[u8] = &[0x41, 0x80, 0x08];
+    /// Returns the input as the output: `(ptr << 32) | len`
+    const ECHO: &[u8] = &[0x20, 0x00, 0xad, 0x42, 0x20, 0x86, 0x20, 0x01, 0xad, 0x84];
+    /// `loop br 0 end unreachable`
+    const SPIN: &[u8] = &[0x03, 0x40, 0x0c, 0x00, 0x0b, 0x00];
+
+    fn section(id: u8, content: Vec<u8>) -> Vec<u8> {
+        [vec![id, content.len() as u8], content].concat()
+    }
+
+    fn export(name: &str, kind: u8, index: u8) -> Vec<u8> {
+        [vec![name.len() as u8], name.as_bytes().to_vec(), vec![kind, index]].concat()
+    }
+
+    fn body(instructions: &[u8]) -> Vec<u8> {
+        [vec![instructions.len() as u8 + 2, 0x00], instructions.to_vec(), vec![0x0b]].concat()
+    }
+
+    /// A base64 module exporting one page of memory, `alloc` and, unless
+    /// `transform` is `None`, a `transform` with the given instructions
+    fn module(transform: Option<&[u8]>) -> String {
+        let mut exports = vec![export("memory", 0x02, 0), export("alloc", 0x00, 0)];
+        exports.extend(transform.map(|_| export("transform", 0x00, 1)));
+
+        let bytes = [
+            vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00],
+            section(0x01, vec![0x02, 0x60, 0x01, 0x7f, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e]),
+            section(0x03, vec![0x02, 0x00, 0x01]),
+            section(0x05, vec![0x01, 0x00, 0x01]),
+            section(0x07, [vec![exports.len() as u8], exports.concat()].concat()),
+            section(0x0a, [vec![0x02], body(ALLOC), body(transform.unwrap_or(ECHO))].concat()),
+        ].concat();
+
+        BASE64_STANDARD.encode(bytes)
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            timeout: Duration::from_secs(5),
+            memory_limit_bytes: 1024 * 1024,
+            max_output_bytes: 1024 * 1024,
+            fuel: 100_000,
+        }
+    }
+
+    #[test]
+    fn execute_passes_inputs_and_outputs_as_json() {
+        let attributes = json!({ "title": "it's a \\ and a\nnewline" });
+
+        let outputs = WasmRuntime.execute(
+            &module(Some(ECHO)),
+            &[("element", json!("a")), ("attributes", attributes.clone())],
+            &[("element", Shape::Scalar), ("attributes", Shape::Map), ("missing", Shape::Scalar)],
+            &limits(),
+        ).unwrap();
+
+        assert_eq!(outputs.get("element"), Some(&json!("a")));
+        assert_eq!(outputs.get("attributes"), Some(&attributes));
+        assert_eq!(outputs.get("missing"), Some(&Value::Null));
+    }
+
+    #[test]
+    fn execute_maps_exhausted_fuel_to_a_limit_error() {
+        let result = WasmRuntime.execute(&module(Some(SPIN)), &[], &[("value", Shape::Scalar)], &limits());
+
+        assert!(matches!(result, Err(Errors::TransformationLimitError(_))));
+    }
+
+    #[test]
+    fn execute_requires_a_transform_export() {
+        let result = WasmRuntime.execute(&module(None), &[], &[("value", Shape::Scalar)], &limits());
+
+        assert!(matches!(
+            result,
+            Err(Errors::TransformationError(message)) if message.contains("does not export transform")
+        ));
+    }
+}
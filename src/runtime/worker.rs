@@ -0,0 +1,219 @@
+use serde_json::{json, Map, Value};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::prelude::*;
+use crate::config::{CONFIG};
+use super::{Limits, Shape};
+
+lazy_static! {
+    /// Idle workers, keyed by their command line. A worker is taken out for
+    /// the duration of a call, so that concurrent calls run on separate
+    /// workers instead of waiting for one.
+    static ref WORKERS: Mutex<HashMap<String, Vec<Worker>>> = Mutex::new(HashMap::new());
+}
+
+/// A long-lived interpreter process speaking JSON lines. Each request is
+/// `{"code", "inputs", "outputs"}` and each response is either
+/// `{"outputs": {...}}` or `{"error": "..."}`, with `"limit": true` for an
+/// error caused by the worker's memory limit.
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    responses: Receiver<String>,
+}
+
+impl Worker {
+    fn spawn(command: &str, args: &[&str]) -> Result<Self, Errors> {
+        log::trace!("In spawn");
+        log::info!("Starting {} worker", command);
+
+        let mut child = Command::new(command)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|err| {
+                log::error!("Could not start {}: {}", command, err);
+                Errors::TransformationError(format!("Could not start {}: {}", command, err))
+            })?;
+
+        let stdin = child.stdin.take()
+            .ok_or_else(|| Errors::TransformationError(format!("Could not open {} stdin", command)))?;
+        let stdout = child.stdout.take()
+            .ok_or_else(|| Errors::TransformationError(format!("Could not open {} stdout", command)))?;
+
+        let (sender, responses) = mpsc::channel();
+
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(line) => {
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    },
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Ok(Worker {
+            child,
+            stdin,
+            responses,
+        })
+    }
+
+    fn call(&mut self, request: &Value, limits: &Limits) -> Result<String, Errors> {
+        writeln!(self.stdin, "{}", request).and_then(|_| self.stdin.flush()).map_err(|err| {
+            log::error!("Could not write to worker: {}", err);
+            Errors::TransformationError(format!("Could not write to worker: {}", err))
+        })?;
+
+        self.responses.recv_timeout(limits.timeout).map_err(|err| match err {
+            RecvTimeoutError::Timeout => limits.timed_out(),
+            RecvTimeoutError::Disconnected => self.exit_error(),
+        })
+    }
+
+    /// A worker killed by a signal most likely hit its memory limit, as
+    /// interpreters abort when they cannot allocate
+    fn exit_error(&mut self) -> Errors {
+        let deadline = Instant::now() + Duration::from_millis(100);
+
+        let status = loop {
+            match self.child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(5)),
+                _ => break None,
+            }
+        };
+
+        log::error!("Worker exited unexpectedly: {:?}", status);
+
+        match status {
+            Some(status) if is_killed(&status) => Errors::TransformationLimitError(
+                format!("Worker was killed, most likely for exceeding its memory limit: {}", status)
+            ),
+            _ => Errors::TransformationError("Worker exited unexpectedly".into()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_killed(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+
+    status.signal().is_some()
+}
+
+#[cfg(not(unix))]
+fn is_killed(_status: &ExitStatus) -> bool {
+    false
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Runs the infix on an idle worker of the named runtime, starting one if
+/// there is none. A worker that times out or breaks is discarded so that the
+/// next call starts a fresh one. Oversized responses are rejected but leave
+/// the worker running.
+pub fn execute(
+    name: &str,
+    command: &str,
+    args: &[&str],
+    infix: &str,
+    inputs: &[(&str, Value)],
+    outputs: &[(&str, Shape)],
+    limits: &Limits,
+) -> Result<Map<String, Value>, Errors> {
+    log::trace!("In worker/execute");
+
+    let request = json!({
+        "code": infix,
+        "inputs": inputs.iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect::<Map<String, Value>>(),
+        "outputs": outputs.iter()
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<String>>(),
+    });
+
+    let key = std::iter::once(command).chain(args.iter().copied()).collect::<Vec<&str>>().join(" ");
+
+    let mut worker = match take_idle_worker(&key)? {
+        Some(worker) => worker,
+        None => Worker::spawn(command, args)?,
+    };
+
+    // Dropping the worker on error stops it
+    let line = worker.call(&request, limits)?;
+
+    return_idle_worker(&key, worker)?;
+
+    limits.check_output_size(line.len())?;
+
+    let response: Value = serde_json::from_str(&line).map_err(|err| {
+        log::error!("Could not parse worker response: {}", err);
+        Errors::TransformationOutputError(format!("Could not parse worker response: {}", err))
+    })?;
+
+    if let Some(error) = response.get("error") {
+        log::error!("{} transformation failed: {}", name, error);
+
+        let message = error.as_str().map(String::from).unwrap_or_else(|| error.to_string());
+
+        return match response.get("limit").and_then(|limit| limit.as_bool()) {
+            Some(true) => Err(Errors::TransformationLimitError(message)),
+            _ => Err(Errors::TransformationError(message)),
+        };
+    }
+
+    let results = response.get("outputs")
+        .and_then(|outputs| outputs.as_object())
+        .cloned()
+        .unwrap_or_default();
+
+    Ok(outputs.iter()
+        .map(|(name, _)| {
+            (name.to_string(), results.get(*name).cloned().unwrap_or(Value::Null))
+        })
+        .collect())
+}
+
+fn take_idle_worker(key: &str) -> Result<Option<Worker>, Errors> {
+    let mut workers = WORKERS.lock().map_err(|_| {
+        Errors::TransformationError("Worker pool is poisoned".into())
+    })?;
+
+    Ok(workers.get_mut(key).and_then(|idle| idle.pop()))
+}
+
+fn return_idle_worker(key: &str, worker: Worker) -> Result<(), Errors> {
+    let max_workers = read_lock!(CONFIG).runtime.max_workers;
+
+    let mut workers = WORKERS.lock().map_err(|_| {
+        Errors::TransformationError("Worker pool is poisoned".into())
+    })?;
+
+    let idle = workers.entry(key.to_string()).or_default();
+
+    if idle.len() < max_workers {
+        idle.push(worker);
+    }
+
+    Ok(())
+}
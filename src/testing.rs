@@ -0,0 +1,84 @@
+//! Fixtures shared by the unit tests of several modules
+
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+use crate::prelude::*;
+use crate::basis_node::BasisNode;
+use crate::config::LineageMode;
+use crate::hash::HashScheme;
+use crate::lineage::{legacy_max_cycle_length, LineageSettings};
+use crate::runtime::{register_runtime, FnRuntime};
+use crate::transformation::Runtime;
+
+/// A unique path in the temporary directory, for providers to store into
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("parversion-{}-{}", name, ID::new().to_string()))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// A lineage with one segment per given tag, under the current settings
+pub fn lineage(segments: &[&str]) -> Lineage {
+    let settings = LineageSettings::current();
+
+    segments.iter().fold(Lineage::new(), |lineage, segment| {
+        lineage.with_hash(Hash::from_str(segment), segment.to_string(), &settings)
+    })
+}
+
+/// A basis node for the last element of the lineage, without
+/// transformations
+pub fn basis_node(lineage: Lineage) -> BasisNode {
+    BasisNode {
+        id: ID::new(),
+        hash: lineage.last_hash().unwrap(),
+        lineage,
+        description: String::new(),
+        transformations: Vec::new(),
+        profile_id: None,
+        hash_scheme: HashScheme::current(),
+        lineage_mode: LineageMode::legacy(),
+        lineage_max_cycle_length: legacy_max_cycle_length(),
+    }
+}
+
+/// Registers the function as a runtime under the given name
+pub fn runtime(name: &str, run: fn(&Map<String, Value>) -> Map<String, Value>) -> Runtime {
+    register_runtime(name, Arc::new(FnRuntime(run)));
+
+    Runtime::Custom(name.to_string())
+}
+
+/// Produces a `text` field from nodes with text and nothing from others
+pub fn text_field(inputs: &Map<String, Value>) -> Map<String, Value> {
+    let text = inputs.get("fields").and_then(|fields| fields.get("text")).cloned();
+
+    Map::from_iter([
+        (String::from("key"), text.as_ref().map(|_| Value::from("text")).unwrap_or(Value::Null)),
+        (String::from("value"), text.unwrap_or(Value::Null)),
+    ])
+}
+
+/// Moves the text of every descendant into the root and drops them
+pub fn merge_text(inputs: &Map<String, Value>) -> Map<String, Value> {
+    fn collect(node: &Value, text: &mut Vec<String>) {
+        if let Some(value) = node["fields"]["text"].as_str() {
+            text.push(value.trim().to_string());
+        }
+
+        for child in node["children"].as_array().unwrap() {
+            collect(child, text);
+        }
+    }
+
+    let mut text = Vec::new();
+    collect(&inputs["node"], &mut text);
+
+    let mut node = inputs["node"].clone();
+    node["fields"]["text"] = Value::from(text.join(" "));
+    node["children"] = Value::Array(Vec::new());
+
+    Map::from_iter([(String::from("node"), node)])
+}
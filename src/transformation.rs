@@ -1,32 +1,116 @@
 use serde::{Serialize, Deserialize};
-use serde_json::Value;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use regex::Regex;
 use std::collections::{HashMap, HashSet};
-use quick_js::{Context as QuickContext, JsValue};
 
 use crate::prelude::*;
+use crate::config::{CONFIG, TransformationPolicy};
+use crate::data_node::{DataNode, DataNodeFields};
+use crate::hash::{HashAlgorithm, HashScheme};
 use crate::id::{ID};
+use crate::json_node::{Json, JsonMetadata};
+use crate::runtime::{self, NativeTransformation, Shape, ValueOperation};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Runtime {
     AWK,
     NodeJS,
     Python,
     QuickJS,
+    /// A WebAssembly module, given as base64 in the infix or as
+    /// `file:<path>`
+    Wasm,
+    /// Declarative, applied in Rust from the transformation's `native` spec
+    Native,
+    /// A runtime registered by the embedder under this name
+    Custom(String),
+}
+
+impl Runtime {
+    /// The name the runtime is registered under
+    pub fn get_name(&self) -> String {
+        match self {
+            Runtime::Custom(name) => name.clone(),
+            other => format!("{:?}", other),
+        }
+    }
+}
+
+/// An input and the output a transformation is expected to produce from it.
+/// The shape of both depends on the kind of transformation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransformationExample {
+    pub input: Value,
+    pub output: Value,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExampleFailure {
+    pub transformation_id: ID,
+    /// Position of the example within the transformation
+    pub index: usize,
+    pub input: Value,
+    pub expected: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actual: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 trait Transform {
     fn get_id(&self) -> ID;
     fn get_runtime(&self) -> Runtime;
     fn get_code(&self) -> String;
+
+    fn validate_code(&self) -> Result<(), Errors> {
+        if let Runtime::Native = self.get_runtime() {
+            return Err(Errors::TransformationValidationError(format!(
+                "Transformation {} cannot use the native runtime",
+                self.get_id().to_string()
+            )));
+        }
+
+        if runtime::get_runtime(&self.get_runtime()).is_none() {
+            return Err(Errors::TransformationValidationError(format!(
+                "Transformation {} uses unregistered runtime {}",
+                self.get_id().to_string(),
+                self.get_runtime().get_name()
+            )));
+        }
+
+        if self.get_code().trim().is_empty() {
+            return Err(Errors::TransformationValidationError(format!(
+                "Transformation {} has no code",
+                self.get_id().to_string()
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn execute(
+        &self,
+        inputs: &[(&str, Value)],
+        outputs: &[(&str, Shape)],
+    ) -> Result<Map<String, Value>, Errors> {
+        self.validate_code()?;
+
+        runtime::execute(&self.get_runtime(), &self.get_code(), inputs, outputs)
+    }
 }
 
+/// Reshapes a whole JSON result. The input is checked against the `source`
+/// schema and the output against the `target` schema.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct JsonSchemaTransform {
-    id: ID,
-    runtime: Runtime,
-    code: String,
-    source: String,
-    target: String,
+    pub id: ID,
+    pub runtime: Runtime,
+    pub code: String,
+    pub source: String,
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<TransformationExample>,
 }
 
 impl Transform for JsonSchemaTransform {
@@ -43,11 +127,88 @@ impl Transform for JsonSchemaTransform {
     }
 }
 
+impl JsonSchemaTransform {
+    pub fn validate(&self) -> Result<(), Errors> {
+        self.validate_code()?;
+        self.get_schema(&self.source)?;
+        self.get_schema(&self.target)?;
+
+        Ok(())
+    }
+
+    /// Nothing if the transformation failed and the configured policy skips
+    /// the node
+    pub fn transform(&self, json: &Value) -> Result<Option<Value>, Errors> {
+        log::trace!("In transform");
+
+        with_policy(&self.id, self.reshape(json), || json.clone())
+    }
+
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        test_examples(&self.id, &self.examples, |input| self.reshape(input))
+    }
+
+    /// Runs the code with the input bound to `json`, reading the result back
+    /// from `json` in the shape of the target schema's type
+    fn reshape(&self, json: &Value) -> Result<Value, Errors> {
+        let target = self.get_schema(&self.target)?;
+
+        conforms(json, &self.get_schema(&self.source)?, "$").map_err(|err| {
+            Errors::TransformationValidationError(format!(
+                "Input to transformation {} does not match its source schema: {}",
+                self.id.to_string(),
+                err
+            ))
+        })?;
+
+        let shape = match target.get("type").and_then(|expected| expected.as_str()) {
+            Some("object") | None => Shape::Map,
+            Some("array") => Shape::List,
+            Some(_) => Shape::Scalar,
+        };
+
+        let result = self.execute(&[("json", json.clone())], &[("json", shape)])?;
+        let output = result.get("json").cloned().unwrap_or(Value::Null);
+
+        conforms(&output, &target, "$").map_err(|err| {
+            Errors::TransformationOutputError(format!(
+                "Output of transformation {} does not match its target schema: {}",
+                self.id.to_string(),
+                err
+            ))
+        })?;
+
+        Ok(output)
+    }
+
+    fn get_schema(&self, schema: &str) -> Result<Value, Errors> {
+        let schema: Value = serde_json::from_str(schema).map_err(|err| {
+            Errors::TransformationValidationError(format!(
+                "Transformation {} has an invalid schema: {}",
+                self.id.to_string(),
+                err
+            ))
+        })?;
+
+        if !schema.is_object() {
+            return Err(Errors::TransformationValidationError(format!(
+                "Transformation {} has a schema that is not an object",
+                self.id.to_string()
+            )));
+        }
+
+        Ok(schema)
+    }
+}
+
+/// Rewrites a data node's fields before they are turned into JSON
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DataNodeFieldsTransform {
-    id: ID,
-    runtime: Runtime,
-    code: String,
+    pub id: ID,
+    pub runtime: Runtime,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<TransformationExample>,
 }
 
 impl Transform for DataNodeFieldsTransform {
@@ -64,12 +225,39 @@ impl Transform for DataNodeFieldsTransform {
     }
 }
 
+impl DataNodeFieldsTransform {
+    pub fn validate(&self) -> Result<(), Errors> {
+        self.validate_code()
+    }
+
+    /// Nothing if the transformation failed and the configured policy skips
+    /// the node
+    pub fn transform(&self, fields: &DataNodeFields) -> Result<Option<DataNodeFields>, Errors> {
+        log::trace!("In transform");
+
+        with_policy(&self.id, transform_fields(self, fields), || fields.clone())
+    }
+
+    /// Each example's input and output is a map of fields
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        test_examples(&self.id, &self.examples, |input| {
+            let fields: DataNodeFields = from_example(&self.id, input)?;
+
+            Ok(to_object(&transform_fields(self, &fields)?))
+        })
+    }
+}
+
+/// Rewrites the fields of data nodes whose hash matches `regex`, leaving
+/// other nodes untouched
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DataNodeHashTransform {
-    id: ID,
-    runtime: Runtime,
-    regex: String,
-    code: String,
+    pub id: ID,
+    pub runtime: Runtime,
+    pub regex: String,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<TransformationExample>,
 }
 
 impl Transform for DataNodeHashTransform {
@@ -86,11 +274,77 @@ impl Transform for DataNodeHashTransform {
     }
 }
 
+impl DataNodeHashTransform {
+    pub fn validate(&self) -> Result<(), Errors> {
+        self.validate_code()?;
+        self.get_regex()?;
+
+        Ok(())
+    }
+
+    pub fn applies_to(&self, hash: &str) -> Result<bool, Errors> {
+        Ok(self.get_regex()?.is_match(hash))
+    }
+
+    /// Nothing if the transformation failed and the configured policy skips
+    /// the node
+    pub fn transform(&self, data_node: &DataNode) -> Result<Option<DataNodeFields>, Errors> {
+        log::trace!("In transform");
+
+        with_policy(
+            &self.id,
+            self.transform_fields(&data_node.hash.to_string().unwrap_or_default(), &data_node.fields),
+            || data_node.fields.clone()
+        )
+    }
+
+    /// Each example's input is `{ hash, fields }` and its output a map of
+    /// fields
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        test_examples(&self.id, &self.examples, |input| {
+            let hash: String = from_example(&self.id, input.get("hash").unwrap_or(&Value::Null))?;
+            let fields: DataNodeFields = from_example(&self.id, input.get("fields").unwrap_or(&Value::Null))?;
+
+            Ok(to_object(&self.transform_fields(&hash, &fields)?))
+        })
+    }
+
+    fn transform_fields(&self, hash: &str, fields: &DataNodeFields) -> Result<DataNodeFields, Errors> {
+        if !self.applies_to(hash)? {
+            return Ok(fields.clone());
+        }
+
+        transform_fields(self, fields)
+    }
+
+    fn get_regex(&self) -> Result<Regex, Errors> {
+        Regex::new(&self.regex).map_err(|err| {
+            Errors::TransformationValidationError(format!(
+                "Transformation {} has an invalid regex: {}",
+                self.id.to_string(),
+                err
+            ))
+        })
+    }
+}
+
+/// A data node's fields together with those of its descendants
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DataNodeTree {
+    pub fields: DataNodeFields,
+    #[serde(default)]
+    pub children: Vec<DataNodeTree>,
+}
+
+/// Rewrites a subtree of data nodes at once, for cases such as merging the
+/// text of descendants into their parent
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DataNodeRecursiveTransform {
-    id: ID,
-    runtime: Runtime,
-    code: String,
+    pub id: ID,
+    pub runtime: Runtime,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<TransformationExample>,
 }
 
 impl Transform for DataNodeRecursiveTransform {
@@ -107,11 +361,59 @@ impl Transform for DataNodeRecursiveTransform {
     }
 }
 
+impl DataNodeRecursiveTransform {
+    pub fn validate(&self) -> Result<(), Errors> {
+        self.validate_code()
+    }
+
+    /// Nothing if the transformation failed and the configured policy skips
+    /// the subtree
+    pub fn transform(&self, tree: &DataNodeTree) -> Result<Option<DataNodeTree>, Errors> {
+        log::trace!("In transform");
+
+        with_policy(&self.id, self.transform_tree(tree), || tree.clone())
+    }
+
+    /// Each example's input and output is a `{ fields, children }` tree
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        test_examples(&self.id, &self.examples, |input| {
+            let tree: DataNodeTree = from_example(&self.id, input)?;
+            let tree = self.transform_tree(&tree)?;
+
+            serde_json::to_value(tree).map_err(|err| {
+                Errors::TransformationError(format!("Could not serialize data node tree: {}", err))
+            })
+        })
+    }
+
+    /// Runs the code with the subtree bound to `node` as
+    /// `{ fields, children }`, reading the result back from `node`
+    fn transform_tree(&self, tree: &DataNodeTree) -> Result<DataNodeTree, Errors> {
+        let node = serde_json::to_value(tree).map_err(|err| {
+            Errors::TransformationError(format!("Could not serialize data node tree: {}", err))
+        })?;
+
+        let result = self.execute(&[("node", node)], &[("node", Shape::Map)])?;
+
+        serde_json::from_value(result.get("node").cloned().unwrap_or(Value::Null)).map_err(|err| {
+            Errors::TransformationOutputError(format!(
+                "Transformation {} did not produce a data node tree: {}",
+                self.id.to_string(),
+                err
+            ))
+        })
+    }
+}
+
+/// Turns a data node into a JSON field. The code reads `fields` and
+/// `description` and sets `key` and `value`; a null key produces no field.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DataToJsonFieldTransform {
-    id: ID,
-    runtime: Runtime,
-    code: String,
+    pub id: ID,
+    pub runtime: Runtime,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<TransformationExample>,
 }
 
 impl Transform for DataToJsonFieldTransform {
@@ -128,6 +430,214 @@ impl Transform for DataToJsonFieldTransform {
     }
 }
 
+impl DataToJsonFieldTransform {
+    pub fn validate(&self) -> Result<(), Errors> {
+        self.validate_code()
+    }
+
+    /// Nothing if the transformation failed and the configured policy skips
+    /// the node. Keeping the original produces no field, as there is none.
+    pub fn transform(&self, data_node: &DataNode) -> Result<Option<Option<Json>>, Errors> {
+        log::trace!("In transform");
+
+        with_policy(&self.id, self.to_json(&data_node.fields, &data_node.description), || None)
+    }
+
+    /// Each example's input is `{ fields, description }` and its output
+    /// `{ key, value }`, or null when no field is produced
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        test_examples(&self.id, &self.examples, |input| {
+            let fields: DataNodeFields = from_example(&self.id, input.get("fields").unwrap_or(&Value::Null))?;
+            let description: String = input.get("description")
+                .map(|description| from_example(&self.id, description))
+                .transpose()?
+                .unwrap_or_default();
+
+            Ok(self.to_json(&fields, &description)?
+                .map(|json| json!({ "key": json.key, "value": json.value }))
+                .unwrap_or(Value::Null))
+        })
+    }
+
+    fn to_json(&self, fields: &DataNodeFields, description: &str) -> Result<Option<Json>, Errors> {
+        let result = self.execute(
+            &[
+                ("fields", to_object(fields)),
+                ("description", Value::String(description.to_string())),
+            ],
+            &[
+                ("key", Shape::Scalar),
+                ("value", Shape::Scalar),
+            ],
+        )?;
+
+        let key = match result.get("key") {
+            Some(Value::String(key)) => key.clone(),
+            Some(Value::Null) | None => return Ok(None),
+            Some(other) => return Err(Errors::TransformationOutputError(format!(
+                "Transformation {} produced a non-string key: {}",
+                self.id.to_string(),
+                other
+            ))),
+        };
+
+        let value = match result.get("value") {
+            Some(Value::String(value)) => value.clone(),
+            Some(Value::Null) | None => String::new(),
+            Some(value @ Value::Bool(_)) | Some(value @ Value::Number(_)) => value.to_string(),
+            Some(other) => return Err(Errors::TransformationOutputError(format!(
+                "Transformation {} produced a non-scalar value: {}",
+                self.id.to_string(),
+                other
+            ))),
+        };
+
+        Ok(Some(Json {
+            key,
+            value,
+            meta: JsonMetadata {
+                is_primary_content: false,
+            },
+        }))
+    }
+}
+
+/// Rewrites extracted JSON values after analysis. The code reads `key`,
+/// `value` and `lineage` and sets `value`; a null value removes the field.
+/// Native transformations apply `operations` in order instead. Lineages are
+/// matched by hash, readable path or CSS selector. Empty scopes match every
+/// lineage and key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JsonValueTransform {
+    pub id: ID,
+    pub runtime: Runtime,
+    #[serde(default)]
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub operations: Vec<ValueOperation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lineages: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<TransformationExample>,
+}
+
+impl Transform for JsonValueTransform {
+    fn get_id(&self) -> ID {
+        self.id.clone()
+    }
+
+    fn get_runtime(&self) -> Runtime {
+        self.runtime.clone()
+    }
+
+    fn get_code(&self) -> String {
+        self.code.clone()
+    }
+}
+
+impl JsonValueTransform {
+    pub fn validate(&self) -> Result<(), Errors> {
+        if let Runtime::Native = self.runtime {
+            if self.operations.is_empty() {
+                return Err(Errors::TransformationValidationError(format!(
+                    "Native transformation {} has no operations",
+                    self.id.to_string()
+                )));
+            }
+
+            for operation in self.operations.iter() {
+                operation.validate().map_err(|err| {
+                    Errors::TransformationValidationError(format!(
+                        "Transformation {} has an invalid operation: {}",
+                        self.id.to_string(),
+                        err
+                    ))
+                })?;
+            }
+
+            return Ok(());
+        }
+
+        self.validate_code()
+    }
+
+    pub fn applies_to(&self, lineage: &Lineage, key: &str) -> bool {
+        if !self.keys.is_empty() && !self.keys.iter().any(|scoped_key| scoped_key == key) {
+            return false;
+        }
+
+        if self.lineages.is_empty() {
+            return true;
+        }
+
+        let names = [lineage.to_string(), lineage.get_path(), lineage.to_css_selector()];
+
+        self.lineages.iter().any(|scoped_lineage| {
+            names.iter().any(|name| !name.is_empty() && name == scoped_lineage)
+        })
+    }
+
+    /// A failed transformation removes the field if the configured policy
+    /// skips it
+    pub fn transform(&self, lineage: &str, key: &str, value: &str) -> Result<Option<String>, Errors> {
+        log::trace!("In transform");
+
+        Ok(with_policy(&self.id, self.transform_value(lineage, key, value), || Some(value.to_string()))?.flatten())
+    }
+
+    /// Each example's input is `{ key, value, lineage }` and its output the
+    /// new value, or null when the field is removed
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        test_examples(&self.id, &self.examples, |input| {
+            let get = |name: &str| -> Result<String, Errors> {
+                input.get(name)
+                    .map(|value| from_example(&self.id, value))
+                    .transpose()
+                    .map(Option::unwrap_or_default)
+            };
+
+            Ok(self.transform_value(&get("lineage")?, &get("key")?, &get("value")?)?
+                .map(Value::String)
+                .unwrap_or(Value::Null))
+        })
+    }
+
+    fn transform_value(&self, lineage: &str, key: &str, value: &str) -> Result<Option<String>, Errors> {
+        if let Runtime::Native = self.runtime {
+            return self.operations.iter()
+                .try_fold(value.to_string(), |value, operation| operation.apply(&value))
+                .map(Some)
+                .map_err(|err| Errors::TransformationError(format!(
+                    "Transformation {} failed: {}",
+                    self.id.to_string(),
+                    err
+                )));
+        }
+
+        let result = self.execute(
+            &[
+                ("key", Value::String(key.to_string())),
+                ("value", Value::String(value.to_string())),
+                ("lineage", Value::String(lineage.to_string())),
+            ],
+            &[("value", Shape::Scalar)],
+        )?;
+
+        match result.get("value") {
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(value @ Value::Bool(_)) | Some(value @ Value::Number(_)) => Ok(Some(value.to_string())),
+            Some(Value::Null) | None => Ok(None),
+            Some(other) => Err(Errors::TransformationOutputError(format!(
+                "Transformation {} produced a non-scalar value: {}",
+                self.id.to_string(),
+                other
+            ))),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Transformation {
     DataNodeFieldsTransform(DataNodeFieldsTransform),
@@ -135,6 +645,175 @@ pub enum Transformation {
     DataNodeHashTransform(DataNodeHashTransform),
     DataToJsonFieldTransform(DataToJsonFieldTransform),
     JsonSchemaTransform(JsonSchemaTransform),
+    JsonValueTransform(JsonValueTransform),
+}
+
+impl Transformation {
+    pub fn get_id(&self) -> ID {
+        match self {
+            Transformation::DataNodeFieldsTransform(transform) => transform.get_id(),
+            Transformation::DataNodeRecursiveTransform(transform) => transform.get_id(),
+            Transformation::DataNodeHashTransform(transform) => transform.get_id(),
+            Transformation::DataToJsonFieldTransform(transform) => transform.get_id(),
+            Transformation::JsonSchemaTransform(transform) => transform.get_id(),
+            Transformation::JsonValueTransform(transform) => transform.get_id(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Errors> {
+        match self {
+            Transformation::DataNodeFieldsTransform(transform) => transform.validate(),
+            Transformation::DataNodeRecursiveTransform(transform) => transform.validate(),
+            Transformation::DataNodeHashTransform(transform) => transform.validate(),
+            Transformation::DataToJsonFieldTransform(transform) => transform.validate(),
+            Transformation::JsonSchemaTransform(transform) => transform.validate(),
+            Transformation::JsonValueTransform(transform) => transform.validate(),
+        }
+    }
+
+    /// Runs the transformation's examples, returning those that fail
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        match self {
+            Transformation::DataNodeFieldsTransform(transform) => transform.test(),
+            Transformation::DataNodeRecursiveTransform(transform) => transform.test(),
+            Transformation::DataNodeHashTransform(transform) => transform.test(),
+            Transformation::DataToJsonFieldTransform(transform) => transform.test(),
+            Transformation::JsonSchemaTransform(transform) => transform.test(),
+            Transformation::JsonValueTransform(transform) => transform.test(),
+        }
+    }
+}
+
+fn test_examples<F>(id: &ID, examples: &[TransformationExample], run: F) -> Vec<ExampleFailure>
+where
+    F: Fn(&Value) -> Result<Value, Errors>,
+{
+    examples.iter()
+        .enumerate()
+        .filter_map(|(index, example)| {
+            let (actual, error) = match run(&example.input) {
+                Ok(actual) if actual == example.output => return None,
+                Ok(actual) => (Some(actual), None),
+                Err(err) => (None, Some(format!("{:?}", err))),
+            };
+
+            Some(ExampleFailure {
+                transformation_id: id.clone(),
+                index,
+                input: example.input.clone(),
+                expected: example.output.clone(),
+                actual,
+                error,
+            })
+        })
+        .collect()
+}
+
+fn from_example<T: DeserializeOwned>(id: &ID, value: &Value) -> Result<T, Errors> {
+    serde_json::from_value(value.clone()).map_err(|err| {
+        Errors::TransformationValidationError(format!(
+            "Example for transformation {} is malformed: {}",
+            id.to_string(),
+            err
+        ))
+    })
+}
+
+/// The result of a transformation under the configured policy: its error if
+/// it fails, nothing if it skips the node, or the original if it keeps it
+fn with_policy<T>(id: &ID, result: Result<T, Errors>, original: impl FnOnce() -> T) -> Result<Option<T>, Errors> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) => match read_lock!(CONFIG).runtime.on_error {
+            TransformationPolicy::Fail => Err(err),
+            TransformationPolicy::Skip => {
+                log::warn!("Skipping node after failed transformation {}: {:?}", id.to_string(), err);
+                Ok(None)
+            },
+            TransformationPolicy::KeepOriginal => {
+                log::warn!("Keeping node after failed transformation {}: {:?}", id.to_string(), err);
+                Ok(Some(original()))
+            },
+        },
+    }
+}
+
+fn transform_fields<T: Transform>(transform: &T, fields: &DataNodeFields) -> Result<DataNodeFields, Errors> {
+    let result = transform.execute(&[("fields", to_object(fields))], &[("fields", Shape::Map)])?;
+
+    match result.get("fields") {
+        Some(Value::Object(fields)) => fields.iter()
+            .map(|(key, value)| match value {
+                Value::String(value) => Ok((key.clone(), value.clone())),
+                Value::Bool(_) | Value::Number(_) => Ok((key.clone(), value.to_string())),
+                _ => Err(Errors::TransformationOutputError(format!(
+                    "Transformation {} produced a non-scalar value for field {}",
+                    transform.get_id().to_string(),
+                    key
+                ))),
+            })
+            .collect(),
+        other => Err(Errors::TransformationOutputError(format!(
+            "Transformation {} did not produce a fields object: {:?}",
+            transform.get_id().to_string(),
+            other
+        ))),
+    }
+}
+
+fn to_object(fields: &DataNodeFields) -> Value {
+    Value::Object(
+        fields.iter()
+            .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+            .collect()
+    )
+}
+
+/// Checks a value against the subset of JSON Schema that transformations
+/// rely on: `type`, `properties`, `required` and `items`
+fn conforms(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    if let Some(expected) = schema.get("type").and_then(|expected| expected.as_str()) {
+        let is_match = match expected {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+
+        if !is_match {
+            return Err(format!("{} is not of type {}", path, expected));
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        if let Some(required) = schema.get("required").and_then(|required| required.as_array()) {
+            for key in required.iter().filter_map(|key| key.as_str()) {
+                if !object.contains_key(key) {
+                    return Err(format!("{} lacks required property {}", path, key));
+                }
+            }
+        }
+
+        if let Some(properties) = schema.get("properties").and_then(|properties| properties.as_object()) {
+            for (key, property_schema) in properties {
+                if let Some(property) = object.get(key) {
+                    conforms(property, property_schema, &format!("{}.{}", path, key))?;
+                }
+            }
+        }
+    }
+
+    if let (Some(array), Some(items)) = (value.as_array(), schema.get("items")) {
+        for (index, item) in array.iter().enumerate() {
+            conforms(item, items, &format!("{}[{}]", path, index))?;
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -142,73 +821,134 @@ pub struct HashTransformation {
     pub id: ID,
     pub description: String,
     pub runtime: Runtime,
+    #[serde(default)]
     pub infix: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub native: Option<NativeTransformation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<TransformationExample>,
 }
 
-impl HashTransformation {
-    fn prefix(&self, fields: HashMap<String, String>) -> String {
-        match self.runtime {
-            Runtime::QuickJS => {
-                let fields_js: Vec<String> = fields
-                    .into_iter()
-                    .map(|(key, value)| {
-                        if key == "text" {
-                            format!("'{}': '<omitted>'", key)
-                        } else {
-                            format!("'{}': '{}'", key, value)
-                        }
-                    })
-                    .collect();
-                format!("let fields = {{ {} }};", fields_js.join(", "))
-            },
-            _ => panic!("Unexpected runtime: {:?}", self.runtime),
+impl Default for HashTransformation {
+    /// Hashes a node by the names of its fields, ignoring their values
+    fn default() -> Self {
+        HashTransformation {
+            id: ID::from_str("25fe8722-2528-4257-ad6f-9aa5f08ec284"),
+            description: String::from("Default hash transformation. Nodes are distinguished by the names of their fields, not their values"),
+            runtime: Runtime::Native,
+            infix: String::new(),
+            native: Some(NativeTransformation::default()),
+            examples: Vec::new(),
         }
     }
+}
 
-    fn suffix(&self) -> String {
-        match self.runtime {
-            Runtime::QuickJS => {
-                format!("JSON.stringify({{ hasherItems }})")
-            },
-            _ => panic!("Unexpected runtime: {:?}", self.runtime),
-        }
+impl HashTransformation {
+    /// The equivalent native transformation, if the code only uses patterns
+    /// that can be expressed declaratively
+    pub fn to_native(&self) -> Option<Self> {
+        to_native(&self.runtime, &self.infix, &self.native).map(|native| HashTransformation {
+            runtime: Runtime::Native,
+            infix: String::new(),
+            native: Some(native),
+            ..self.clone()
+        })
     }
 
+    /// Nothing if the transformation failed and the configured policy skips
+    /// the node
     pub fn transform(
         &self,
-        fields: HashMap<String, String>
-    ) -> Hash {
+        fields: HashMap<String, String>,
+        scheme: &HashScheme,
+        algorithm: HashAlgorithm,
+    ) -> Result<Option<Hash>, Errors> {
         log::trace!("In transform");
 
-        let prefix = self.prefix(fields.clone());
-        let suffix = self.suffix();
-        let script = format!("{}\n{}\n{}", prefix, self.infix, suffix);
-
-        log::debug!("script: {}", script);
-
-        match self.runtime {
-            Runtime::QuickJS => {
-                let quick_context = QuickContext::new().unwrap();
-                let result = quick_context.eval_as::<String>(&script).unwrap();
-                let parsed: Value = serde_json::from_str(&result).unwrap();
-                let hasher_items = parsed.get("hasherItems").unwrap();
-
-                if let Some(array) = hasher_items.as_array() {
-                    let hasher_items_vec = array
-                        .iter()
-                        .filter_map(|v| v.as_str().map(String::from))
-                        .collect::<Vec<String>>();
-
-                    let mut hash = Hash::from_items(hasher_items_vec);
-                    hash.finalize();
-                    return hash;
-                } else {
-                    panic!("Expected 'hasherItems' to be an array");
-                }
+        let hasher_items = match self.get_hasher_items(&fields) {
+            Ok(hasher_items) => hasher_items,
+            Err(err) => match read_lock!(CONFIG).runtime.on_error {
+                TransformationPolicy::Fail => return Err(err),
+                TransformationPolicy::Skip => {
+                    log::warn!("Skipping node after failed hash transformation: {:?}", err);
+
+                    return Ok(None);
+                },
+                TransformationPolicy::KeepOriginal => {
+                    log::warn!("Hashing by field names after failed transformation: {:?}", err);
+
+                    NativeTransformation::default().hash_items(&mask_text(&fields))
+                },
             },
-            _ => panic!("Unexpected runtime: {:?}", self.runtime),
+        };
+
+        let mut hash = Hash::from_items(hasher_items);
+        hash.finalize_with(scheme, algorithm);
+
+        Ok(Some(hash))
+    }
+
+    /// The items a node's hash is computed from, in order
+    pub fn get_hasher_items(&self, fields: &HashMap<String, String>) -> Result<Vec<String>, Errors> {
+        let fields = mask_text(fields);
+
+        if let Runtime::Native = self.runtime {
+            let native = self.native.as_ref().ok_or_else(|| Errors::TransformationError(
+                format!("Native hash transformation {} has no spec", self.id.to_string())
+            ))?;
+
+            return Ok(native.hash_items(&fields));
         }
+
+        let fields: serde_json::Map<String, Value> = fields
+            .into_iter()
+            .map(|(key, value)| (key, Value::String(value)))
+            .collect();
+
+        let result = runtime::execute(
+            &self.runtime,
+            &self.infix,
+            &[("fields", Value::Object(fields))],
+            &[("hasherItems", Shape::List)],
+        )?;
+
+        let hasher_items = result.get("hasherItems")
+            .and_then(|items| items.as_array())
+            .ok_or_else(|| Errors::TransformationOutputError(
+                format!("Hash transformation {} did not produce a hasherItems array", self.id.to_string())
+            ))?
+            .iter()
+            .map(|item| item.as_str().map(String::from))
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| Errors::TransformationOutputError(
+                format!("Hash transformation {} produced a non-string hasher item", self.id.to_string())
+            ))?;
+
+        Ok(hasher_items)
     }
+
+    /// Each example's input is a map of fields and its output the list of
+    /// hasher items
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        test_examples(&self.id, &self.examples, |input| {
+            let fields: HashMap<String, String> = from_example(&self.id, input)?;
+
+            Ok(Value::from(self.get_hasher_items(&fields)?))
+        })
+    }
+}
+
+/// Text content is never part of a node's identity
+fn mask_text(fields: &HashMap<String, String>) -> HashMap<String, String> {
+    fields.iter()
+        .map(|(key, value)| {
+            if key == "text" {
+                (key.clone(), String::from("<omitted>"))
+            } else {
+                (key.clone(), value.clone())
+            }
+        })
+        .collect()
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -216,70 +956,484 @@ pub struct XMLElementTransformation {
     pub id: ID,
     pub description: String,
     pub runtime: Runtime,
+    #[serde(default)]
     pub infix: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub native: Option<NativeTransformation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<TransformationExample>,
 }
 
-impl XMLElementTransformation {
-    fn prefix(&self, element: String, attributes: HashMap<String, String>) -> String {
-        let element_code = format!("let element = '{}';", element);
-
-        let attributes_code = {
-            let attributes_list: Vec<String> = attributes
-                .into_iter()
-                .map(|(key, value)| format!("'{}': '{}'", key, value))
-                .collect();
-            format!("let attributes = {{ {} }};", attributes_list.join(", "))
-        };
+impl Default for XMLElementTransformation {
+    /// Drops presentational elements and attributes that carry no data
+    fn default() -> Self {
+        let to_strings = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
 
-        format!("{}\n{}", element_code, attributes_code)
+        XMLElementTransformation {
+            id: ID::from_str("3841771b-a378-47b8-8c2d-d55f4e2a46d3"),
+            description: String::from("Default XML element transformation. Removes scripts, styles and other elements and attributes that are irrelevant to the data"),
+            runtime: Runtime::Native,
+            infix: String::new(),
+            native: Some(NativeTransformation {
+                element_blacklist: to_strings(&["script", "meta", "link", "iframe", "svg", "style", "noscript"]),
+                attribute_blacklist: to_strings(&[
+                    "style", "bgcolor", "border", "cellpadding", "cellspacing", "width", "height", "rows",
+                    "cols", "wrap", "aria-hidden", "size", "op", "lang", "colspan", "rel",
+                ]),
+                ..NativeTransformation::default()
+            }),
+            examples: Vec::new(),
+        }
     }
+}
 
-    fn suffix(&self) -> String {
-        match self.runtime {
-            Runtime::QuickJS => {
-                format!("JSON.stringify({{ element, attributes }})")
-            },
-            _ => panic!("unexpected runtime: {:?}", self.runtime),
-        }
+impl XMLElementTransformation {
+    /// The equivalent native transformation, if the code only uses patterns
+    /// that can be expressed declaratively
+    pub fn to_native(&self) -> Option<Self> {
+        to_native(&self.runtime, &self.infix, &self.native).map(|native| XMLElementTransformation {
+            runtime: Runtime::Native,
+            infix: String::new(),
+            native: Some(native),
+            ..self.clone()
+        })
     }
-    
+
     pub fn transform(
         &self,
         element: String,
         attributes: HashMap<String, String>
-    ) -> (
+    ) -> Result<(
         Option<String>,
         HashMap<String, String>
-    ) {
+    ), Errors> {
         log::trace!("In transform");
 
-        let prefix = self.prefix(element, attributes);
-        let suffix = self.suffix();
+        match self.execute(&element, &attributes) {
+            Ok(result) => Ok(result),
+            Err(err) => match read_lock!(CONFIG).runtime.on_error {
+                TransformationPolicy::Fail => Err(err),
+                TransformationPolicy::Skip => {
+                    log::warn!("Dropping element {} after failed transformation: {:?}", element, err);
+                    Ok((None, attributes))
+                },
+                TransformationPolicy::KeepOriginal => {
+                    log::warn!("Keeping element {} after failed transformation: {:?}", element, err);
+                    Ok((Some(element), attributes))
+                },
+            },
+        }
+    }
 
-        let code = format!("{}\n{}\n{}", prefix, self.infix, suffix);
+    fn execute(
+        &self,
+        element: &str,
+        attributes: &HashMap<String, String>
+    ) -> Result<(
+        Option<String>,
+        HashMap<String, String>
+    ), Errors> {
+        if let Runtime::Native = self.runtime {
+            let native = self.native.as_ref().ok_or_else(|| Errors::TransformationError(
+                format!("Native XML element transformation {} has no spec", self.id.to_string())
+            ))?;
+
+            return Ok(native.transform_element(element.to_string(), attributes.clone()));
+        }
 
-        match self.runtime {
-            Runtime::QuickJS => {
-                let quick_context = QuickContext::new().unwrap();
+        let attributes: serde_json::Map<String, Value> = attributes
+            .iter()
+            .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+            .collect();
 
-                let result =  quick_context.eval_as::<String>(&code).unwrap();
+        let result = runtime::execute(
+            &self.runtime,
+            &self.infix,
+            &[
+                ("element", Value::String(element.to_string())),
+                ("attributes", Value::Object(attributes)),
+            ],
+            &[
+                ("element", Shape::Scalar),
+                ("attributes", Shape::Map),
+            ],
+        )?;
 
-                let parsed: Value = serde_json::from_str(&result).unwrap();
+        let transformed_element = match result.get("element") {
+            Some(Value::String(element)) => Some(element.clone()),
+            Some(Value::Null) | None => None,
+            Some(other) => return Err(Errors::TransformationOutputError(format!(
+                "XML element transformation {} produced a non-string element: {}",
+                self.id.to_string(),
+                other
+            ))),
+        };
 
-                let transformed_element = parsed.get("element").and_then(|e|
-                    e.as_str().map(String::from));
+        let transformed_attributes = match result.get("attributes") {
+            Some(Value::Object(attributes)) => attributes.iter()
+                .map(|(key, value)| match value {
+                    Value::String(value) => Ok((key.clone(), value.clone())),
+                    Value::Null => Ok((key.clone(), String::new())),
+                    Value::Bool(_) | Value::Number(_) => Ok((key.clone(), value.to_string())),
+                    _ => Err(Errors::TransformationOutputError(format!(
+                        "XML element transformation {} produced a non-scalar value for attribute {}",
+                        self.id.to_string(),
+                        key
+                    ))),
+                })
+                .collect::<Result<HashMap<String, String>, Errors>>()?,
+            Some(Value::Null) | None => HashMap::new(),
+            Some(other) => return Err(Errors::TransformationOutputError(format!(
+                "XML element transformation {} produced non-object attributes: {}",
+                self.id.to_string(),
+                other
+            ))),
+        };
 
-                let transformed_attributes = parsed.get("attributes")
-                    .and_then(|attr| attr.as_object())
-                    .map(|attr_obj| {
-                        attr_obj.iter().map(|(k, v)| {
-                            (k.clone(), v.as_str().unwrap_or("").to_string())
-                        }).collect::<HashMap<String, String>>()
-                    }).unwrap_or_default();
+        Ok((transformed_element, transformed_attributes))
+    }
 
-                (transformed_element, transformed_attributes)
-            },
-            _ => panic!("Unexpected runtime: {:?}", self.runtime),
+    /// Each example's input is `{ element, attributes }` and its output the
+    /// same shape, with a null element for one that is dropped
+    pub fn test(&self) -> Vec<ExampleFailure> {
+        test_examples(&self.id, &self.examples, |input| {
+            let element: String = from_example(&self.id, input.get("element").unwrap_or(&Value::Null))?;
+            let attributes: HashMap<String, String> = input.get("attributes")
+                .map(|attributes| from_example(&self.id, attributes))
+                .transpose()?
+                .unwrap_or_default();
+
+            let (element, attributes) = self.execute(&element, &attributes)?;
+
+            Ok(json!({
+                "element": element,
+                "attributes": attributes,
+            }))
+        })
+    }
+}
+
+fn to_native(
+    runtime: &Runtime,
+    infix: &str,
+    native: &Option<NativeTransformation>,
+) -> Option<NativeTransformation> {
+    match runtime {
+        Runtime::Native => native.clone(),
+        Runtime::QuickJS | Runtime::NodeJS => NativeTransformation::from_js(infix),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::runtime::{register_runtime, Limits, TransformRuntime};
+    use crate::testing::{merge_text, runtime, text_field};
+    use crate::lineage::{describe_segment, LineageSettings};
+
+    fn output(name: &str, value: Value) -> Map<String, Value> {
+        Map::from_iter([(name.to_string(), value)])
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> DataNodeFields {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn data_node(fields: DataNodeFields) -> DataNode {
+        DataNode {
+            id: ID::new(),
+            hash: Hash::from_str("div"),
+            lineage: Lineage::new(),
+            fields,
+            description: String::from("div"),
+            fingerprint: None,
+        }
+    }
+
+    fn uppercase_fields(inputs: &Map<String, Value>) -> Map<String, Value> {
+        let fields = inputs["fields"].as_object().unwrap().iter()
+            .map(|(key, value)| (key.clone(), Value::from(value.as_str().unwrap().to_uppercase())))
+            .collect();
+
+        output("fields", Value::Object(fields))
+    }
+
+    fn identity(inputs: &Map<String, Value>) -> Map<String, Value> {
+        inputs.clone()
+    }
+
+    #[test]
+    fn fields_transform_rewrites_fields() {
+        let transform = DataNodeFieldsTransform {
+            id: ID::new(),
+            runtime: runtime("test-uppercase-fields", uppercase_fields),
+            code: String::from("uppercase"),
+            examples: Vec::new(),
+        };
+
+        assert!(transform.validate().is_ok());
+        assert_eq!(transform.transform(&fields(&[("text", "abc")])).unwrap(), Some(fields(&[("text", "ABC")])));
+    }
+
+    #[test]
+    fn fields_transform_rejects_nested_values() {
+        fn nested(_inputs: &Map<String, Value>) -> Map<String, Value> {
+            output("fields", json!({ "text": ["a"] }))
         }
+
+        let transform = DataNodeFieldsTransform {
+            id: ID::new(),
+            runtime: runtime("test-nested-fields", nested),
+            code: String::from("nested"),
+            examples: Vec::new(),
+        };
+
+        assert!(matches!(
+            transform.transform(&fields(&[("text", "abc")])),
+            Err(Errors::TransformationOutputError(_))
+        ));
+    }
+
+    #[test]
+    fn hash_transform_applies_to_matching_hashes_only() {
+        let node = data_node(fields(&[("text", "abc")]));
+        let hash = node.hash.to_string().unwrap();
+
+        let transform = |regex: &str| DataNodeHashTransform {
+            id: ID::new(),
+            runtime: runtime("test-uppercase-hashed", uppercase_fields),
+            regex: regex.to_string(),
+            code: String::from("uppercase"),
+            examples: Vec::new(),
+        };
+
+        assert_eq!(transform(&format!("^{}$", hash)).transform(&node).unwrap(), Some(fields(&[("text", "ABC")])));
+        assert_eq!(transform("^unmatched$").transform(&node).unwrap(), Some(fields(&[("text", "abc")])));
+        assert!(matches!(transform("(").validate(), Err(Errors::TransformationValidationError(_))));
+    }
+
+    #[test]
+    fn recursive_transform_rewrites_subtree() {
+        let transform = DataNodeRecursiveTransform {
+            id: ID::new(),
+            runtime: runtime("test-merge-text", merge_text),
+            code: String::from("merge"),
+            examples: Vec::new(),
+        };
+
+        let tree = DataNodeTree {
+            fields: fields(&[("tag", "p")]),
+            children: vec![
+                DataNodeTree { fields: fields(&[("text", "a")]), children: Vec::new() },
+                DataNodeTree { fields: fields(&[("text", "b")]), children: Vec::new() },
+            ],
+        };
+
+        assert_eq!(transform.transform(&tree).unwrap(), Some(DataNodeTree {
+            fields: fields(&[("tag", "p"), ("text", "a b")]),
+            children: Vec::new(),
+        }));
+    }
+
+    #[test]
+    fn json_field_transform_produces_a_field_unless_the_key_is_null() {
+        let transform = DataToJsonFieldTransform {
+            id: ID::new(),
+            runtime: runtime("test-text-field", text_field),
+            code: String::from("text"),
+            examples: Vec::new(),
+        };
+
+        let json = transform.transform(&data_node(fields(&[("text", "Hello")]))).unwrap().flatten().unwrap();
+
+        assert_eq!((json.key.as_str(), json.value.as_str()), ("text", "Hello"));
+        assert!(matches!(transform.transform(&data_node(fields(&[("tag", "div")]))), Ok(Some(None))));
+    }
+
+    #[test]
+    fn schema_transform_checks_source_and_target() {
+        let transform = |source: &str, target: &str| JsonSchemaTransform {
+            id: ID::new(),
+            runtime: runtime("test-identity", identity),
+            code: String::from("identity"),
+            source: source.to_string(),
+            target: target.to_string(),
+            examples: Vec::new(),
+        };
+
+        let object = r#"{ "type": "object", "required": ["a"] }"#;
+
+        assert_eq!(transform(object, object).transform(&json!({ "a": "1" })).unwrap(), Some(json!({ "a": "1" })));
+        assert!(matches!(
+            transform(object, object).transform(&json!({ "b": "1" })),
+            Err(Errors::TransformationValidationError(_))
+        ));
+        assert!(matches!(
+            transform(r#"{ "type": "object" }"#, object).transform(&json!({ "b": "1" })),
+            Err(Errors::TransformationOutputError(_))
+        ));
+        assert!(matches!(transform("[]", object).validate(), Err(Errors::TransformationValidationError(_))));
+    }
+
+    /// Returns the shape it was asked for, as a value of that shape
+    struct ShapeRuntime;
+
+    impl TransformRuntime for ShapeRuntime {
+        fn execute(
+            &self,
+            _infix: &str,
+            _inputs: &[(&str, Value)],
+            outputs: &[(&str, Shape)],
+            _limits: &Limits,
+        ) -> Result<Map<String, Value>, Errors> {
+            Ok(outputs.iter()
+                .map(|(name, shape)| {
+                    let value = match shape {
+                        Shape::Map => json!({ "shape": "map" }),
+                        Shape::List => json!(["list"]),
+                        Shape::Scalar => json!("scalar"),
+                    };
+
+                    (name.to_string(), value)
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn schema_transform_reads_output_in_the_shape_of_the_target_type() {
+        register_runtime("test-shape", Arc::new(ShapeRuntime));
+
+        let transform = |target: &str| JsonSchemaTransform {
+            id: ID::new(),
+            runtime: Runtime::Custom(String::from("test-shape")),
+            code: String::from("shape"),
+            source: String::from("{}"),
+            target: target.to_string(),
+            examples: Vec::new(),
+        };
+
+        assert_eq!(transform(r#"{ "type": "object" }"#).transform(&json!({})).unwrap(), Some(json!({ "shape": "map" })));
+        assert_eq!(transform("{}").transform(&json!({})).unwrap(), Some(json!({ "shape": "map" })));
+        assert_eq!(transform(r#"{ "type": "array" }"#).transform(&json!({})).unwrap(), Some(json!(["list"])));
+        assert_eq!(transform(r#"{ "type": "string" }"#).transform(&json!({})).unwrap(), Some(json!("scalar")));
+    }
+
+    #[test]
+    fn value_transform_applies_native_operations_in_order() {
+        let transform = JsonValueTransform {
+            id: ID::new(),
+            runtime: Runtime::Native,
+            code: String::new(),
+            operations: vec![ValueOperation::Trim, ValueOperation::NormalizeCurrency],
+            lineages: Vec::new(),
+            keys: vec![String::from("price")],
+            examples: Vec::new(),
+        };
+
+        assert!(transform.validate().is_ok());
+        assert!(transform.applies_to(&Lineage::new(), "price"));
+        assert!(!transform.applies_to(&Lineage::new(), "title"));
+        assert_eq!(transform.transform("any", "price", " $1,234.50 ").unwrap(), Some(String::from("1234.50")));
+    }
+
+    #[test]
+    fn value_transform_lineages_match_hash_path_or_selector() {
+        let settings = LineageSettings::current();
+        let fields = HashMap::from([
+            (String::from("tag"), String::from("span")),
+            (String::from("class"), String::from("price")),
+        ]);
+        let lineage = Lineage::new()
+            .with_hash(Hash::from_str("span"), describe_segment(&fields), &settings)
+            .with_hash(Hash::from_str("text"), describe_segment(&HashMap::new()), &settings);
+
+        let scoped = |lineage: String| JsonValueTransform {
+            id: ID::new(),
+            runtime: Runtime::Native,
+            code: String::new(),
+            operations: vec![ValueOperation::Trim],
+            lineages: vec![lineage],
+            keys: Vec::new(),
+            examples: Vec::new(),
+        };
+
+        assert!(scoped(lineage.to_string()).applies_to(&lineage, "price"));
+        assert!(scoped(String::from("span.price > #text")).applies_to(&lineage, "price"));
+        assert!(scoped(String::from("span.price")).applies_to(&lineage, "price"));
+        assert!(!scoped(String::from("div.price")).applies_to(&lineage, "price"));
+    }
+
+    #[test]
+    fn quickjs_element_transform_passes_attributes_through_unchanged() {
+        let transformation = XMLElementTransformation {
+            id: ID::new(),
+            description: String::from("Copies the title"),
+            runtime: Runtime::QuickJS,
+            infix: String::from("attributes = Object.assign({}, attributes, { copy: attributes.title });"),
+            native: None,
+            examples: Vec::new(),
+        };
+
+        let title = String::from("it's a \\ and a\nnewline");
+        let attributes = HashMap::from([(String::from("title"), title.clone())]);
+
+        let (element, attributes) = transformation.transform(String::from("a"), attributes).unwrap();
+
+        assert_eq!(element.as_deref(), Some("a"));
+        assert_eq!(attributes.get("title"), Some(&title));
+        assert_eq!(attributes.get("copy"), Some(&title));
+    }
+
+    #[test]
+    fn script_transforms_need_a_registered_runtime_and_code() {
+        let transform = |runtime: Runtime, code: &str| DataNodeFieldsTransform {
+            id: ID::new(),
+            runtime,
+            code: code.to_string(),
+            examples: Vec::new(),
+        };
+
+        assert!(transform(Runtime::Native, "code").validate().is_err());
+        assert!(transform(Runtime::Custom(String::from("test-unregistered")), "code").validate().is_err());
+        assert!(transform(runtime("test-empty-code", identity), " ").validate().is_err());
+    }
+
+    #[test]
+    fn conforms_checks_types() {
+        assert!(conforms(&json!("a"), &json!({ "type": "string" }), "$").is_ok());
+        assert!(conforms(&json!(1), &json!({ "type": "integer" }), "$").is_ok());
+        assert!(conforms(&json!(1.5), &json!({ "type": "number" }), "$").is_ok());
+        assert!(conforms(&json!(null), &json!({ "type": "null" }), "$").is_ok());
+        assert!(conforms(&json!(1.5), &json!({ "type": "integer" }), "$").is_err());
+        assert!(conforms(&json!("a"), &json!({ "type": "object" }), "$").is_err());
+        assert!(conforms(&json!("a"), &json!({ "type": "unknown" }), "$").is_ok());
+        assert!(conforms(&json!("a"), &json!({}), "$").is_ok());
+    }
+
+    #[test]
+    fn conforms_checks_properties_and_items() {
+        let schema = json!({
+            "type": "object",
+            "required": ["items"],
+            "properties": {
+                "items": {
+                    "type": "array",
+                    "items": { "type": "object", "properties": { "price": { "type": "number" } } }
+                }
+            }
+        });
+
+        assert!(conforms(&json!({ "items": [{ "price": 1 }, {}] }), &schema, "$").is_ok());
+        assert_eq!(
+            conforms(&json!({}), &schema, "$"),
+            Err(String::from("$ lacks required property items"))
+        );
+        assert_eq!(
+            conforms(&json!({ "items": [{ "price": 1 }, { "price": "1" }] }), &schema, "$"),
+            Err(String::from("$.items[1].price is not of type number"))
+        );
     }
 }
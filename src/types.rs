@@ -22,8 +22,19 @@ pub enum Errors {
     BasisGraphBuildError(String),
     PathConversionError,
     SqliteDatabaseConnectionError,
+    SqliteQueryError,
+    SledDatabaseError,
     YamlParseError,
     FetchUrlError(String),
+    ProfileValidationError(String),
+    ProfileNotFound,
+    BasisNodeValidationError(String),
+    BasisNetworkValidationError(String),
+    TransformationError(String),
+    TransformationValidationError(String),
+    TransformationOutputError(String),
+    TransformationLimitError(String),
+    ProviderSpecError(String),
 }
 
 #[derive(Clone, Debug)]
@@ -31,7 +42,8 @@ pub struct Options {
     pub analysis_mode: Option<AnalysisMode>,
     pub origin: Option<String>,
     pub date: Option<String>,
-    pub value_transformations: Option<Vec<Transformation>>
+    pub value_transformations: Option<Vec<Transformation>>,
+    pub profile_similarity_threshold: Option<f64>,
 }
 
 impl Default for Options {
@@ -41,6 +53,7 @@ impl Default for Options {
             origin: None,
             date: None,
             value_transformations: None,
+            profile_similarity_threshold: None,
         }
     }
 }